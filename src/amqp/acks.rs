@@ -0,0 +1,174 @@
+use std::collections::BTreeSet;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use lapin::Channel;
+use lapin::options::BasicAckOptions;
+use tokio::time::MissedTickBehavior;
+use tracing::{debug, error};
+
+use crate::config::Env;
+
+// Deliveries processed successfully are acked together with a single `basic.ack` (multiple = true),
+// when `max_acks` deliveries are waiting or every `window`.
+// It follows the bulk writes of the sensors, so all deliveries of a batch are acked together
+#[derive(Debug, Clone, PartialEq)]
+pub struct AckBatchConfig {
+    pub max_acks: usize,
+    pub window: Duration,
+}
+
+impl AckBatchConfig {
+    // acks are batched only if bulk writes are enabled (`bulk_write_max_ops` greater than 1)
+    pub fn from_env(env: &Env) -> Option<Self> {
+        if env.bulk_write_max_ops <= 1 {
+            return None;
+        }
+        Some(Self {
+            max_acks: env.bulk_write_max_ops,
+            window: Duration::from_millis(env.bulk_write_window_ms),
+        })
+    }
+}
+
+// Delivery tags of a channel, waiting to be acked.
+// A multiple-ack acks every unsettled delivery up to its tag, so it can only be sent
+// up to the first delivery still in progress
+#[derive(Debug, Default)]
+pub struct AckWindow {
+    // received, but not settled yet
+    in_progress: BTreeSet<u64>,
+    // processed successfully, but not acked yet
+    completed: BTreeSet<u64>,
+}
+
+impl AckWindow {
+    pub fn track(&mut self, delivery_tag: u64) {
+        self.in_progress.insert(delivery_tag);
+    }
+
+    pub fn complete(&mut self, delivery_tag: u64) {
+        self.in_progress.remove(&delivery_tag);
+        self.completed.insert(delivery_tag);
+    }
+
+    // the delivery has been settled on its own (nack or reject)
+    pub fn release(&mut self, delivery_tag: u64) {
+        self.in_progress.remove(&delivery_tag);
+    }
+
+    pub fn completed(&self) -> usize {
+        self.completed.len()
+    }
+
+    // highest tag that can be acked with a multiple-ack, removing the deliveries acked by it
+    pub fn take_ackable(&mut self) -> Option<u64> {
+        let ackable: u64 = match self.in_progress.first() {
+            Some(first_in_progress) => *self.completed.range(..first_in_progress).next_back()?,
+            None => *self.completed.last()?,
+        };
+        self.completed = self.completed.split_off(&(ackable + 1));
+        Some(ackable)
+    }
+}
+
+// acks the completed deliveries of a channel in batches.
+// The window is flushed periodically by a background task, that stops when the batcher is dropped
+pub struct AckBatcher {
+    channel: Channel,
+    max_acks: usize,
+    window: Mutex<AckWindow>,
+    // multiple-acks must be sent in order, otherwise an older one would ack an unknown tag and close the channel
+    sending: tokio::sync::Mutex<()>,
+}
+
+impl AckBatcher {
+    pub fn spawn(channel: Channel, config: AckBatchConfig) -> Arc<Self> {
+        let batcher: Arc<AckBatcher> = Arc::new(Self {
+            channel,
+            max_acks: config.max_acks.max(1),
+            window: Mutex::new(AckWindow::default()),
+            sending: tokio::sync::Mutex::new(()),
+        });
+        let weak_batcher = Arc::downgrade(&batcher);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(config.window.max(Duration::from_millis(1)));
+            interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            loop {
+                interval.tick().await;
+                let Some(batcher) = weak_batcher.upgrade() else {
+                    break;
+                };
+                batcher.flush().await;
+            }
+            debug!(target: "app", "AckBatcher - stopped");
+        });
+        batcher
+    }
+
+    pub fn track(&self, delivery_tag: u64) {
+        self.window.lock().unwrap().track(delivery_tag);
+    }
+
+    pub async fn complete(&self, delivery_tag: u64) {
+        let full: bool = {
+            let mut window = self.window.lock().unwrap();
+            window.complete(delivery_tag);
+            window.completed() >= self.max_acks
+        };
+        if full {
+            self.flush().await;
+        }
+    }
+
+    // must be called after the delivery has been nacked or rejected, so a multiple-ack never includes it
+    pub fn release(&self, delivery_tag: u64) {
+        self.window.lock().unwrap().release(delivery_tag);
+    }
+
+    // ack all the completed deliveries that can be acked
+    pub async fn flush(&self) {
+        let _sending = self.sending.lock().await;
+        let Some(delivery_tag) = self.window.lock().unwrap().take_ackable() else {
+            return;
+        };
+        debug!(target: "app", "flush - multiple-ack up to delivery_tag = {}", delivery_tag);
+        if let Err(err) = self
+            .channel
+            .basic_ack(delivery_tag, BasicAckOptions { multiple: true })
+            .await
+        {
+            // unacked deliveries are requeued by the AMQP server when the channel is closed
+            error!(target: "app", "flush - cannot ack deliveries up to delivery_tag = {}, err = {:?}", delivery_tag, err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::amqp::acks::AckWindow;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    #[test_log::test]
+    fn ok_ack_window() {
+        let mut window = AckWindow::default();
+        for delivery_tag in 1..=5 {
+            window.track(delivery_tag);
+        }
+        assert_eq!(window.take_ackable(), None);
+        // delivery 1 is still in progress
+        window.complete(2);
+        window.complete(3);
+        assert_eq!(window.take_ackable(), None);
+        window.complete(1);
+        assert_eq!(window.take_ackable(), Some(3));
+        assert_eq!(window.completed(), 0);
+        // delivery 4 has been nacked on its own
+        window.complete(5);
+        assert_eq!(window.take_ackable(), None);
+        window.release(4);
+        assert_eq!(window.take_ackable(), Some(5));
+        assert_eq!(window.take_ackable(), None);
+    }
+}
@@ -0,0 +1,310 @@
+use std::error::Error as StdError;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use lapin::message::Delivery;
+use lapin::options::{ExchangeDeclareOptions, QueueBindOptions, QueueDeclareOptions};
+use lapin::types::{AMQPValue, FieldTable, ShortString};
+use lapin::{BasicProperties, Channel, ExchangeKind};
+use tracing::{debug, error, info};
+
+use crate::amqp::events::publish_confirmed;
+use crate::config::Env;
+use crate::errors::amqp_error::AmqpError;
+use crate::errors::message_error::MessageError;
+use crate::models::sensor::ReadingFailure;
+
+// headers added to every dead-lettered message
+pub const HEADER_ERROR_TYPE: &str = "x-error-type";
+pub const HEADER_ERROR_REASON: &str = "x-error-reason";
+pub const HEADER_ORIGINAL_EXCHANGE: &str = "x-original-exchange";
+pub const HEADER_ORIGINAL_ROUTING_KEY: &str = "x-original-routing-key";
+pub const HEADER_DEAD_LETTERED_AT: &str = "x-dead-lettered-at";
+// added only to the copies of messages stored partially, listing the readings that have been discarded
+pub const HEADER_FAILED_READINGS: &str = "x-failed-readings";
+
+// persistent delivery mode, so dead-lettered messages survive a broker restart
+const PERSISTENT_DELIVERY_MODE: u8 = 2;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeadLetterConfig {
+    pub exchange: String,
+    pub queue: String,
+}
+
+impl DeadLetterConfig {
+    // dead-lettering is enabled only if the exchange is defined.
+    // If the queue is not defined, it will be called `<amqp_queue_name>.dead-letter`
+    pub fn from_env(env: &Env) -> Option<Self> {
+        let exchange = env
+            .amqp_dead_letter_exchange
+            .clone()
+            .filter(|exchange| !exchange.is_empty())?;
+        let queue = env
+            .amqp_dead_letter_queue
+            .clone()
+            .filter(|queue| !queue.is_empty())
+            .unwrap_or_else(|| format!("{}.dead-letter", env.amqp_queue_name));
+        Some(Self { exchange, queue })
+    }
+}
+
+// declare the dead-letter exchange (fanout, because every rejected message must land in the dead-letter queue)
+// and the durable dead-letter queue bound to it
+pub async fn declare_dead_letter(channel: &Channel, config: &DeadLetterConfig) -> Result<(), lapin::Error> {
+    info!(target: "app", "declare_dead_letter - declaring exchange={} and queue={}", &config.exchange, &config.queue);
+    channel
+        .exchange_declare(
+            config.exchange.clone().into(),
+            ExchangeKind::Fanout,
+            ExchangeDeclareOptions {
+                durable: true,
+                ..ExchangeDeclareOptions::default()
+            },
+            FieldTable::default(),
+        )
+        .await?;
+    channel
+        .queue_declare(
+            config.queue.clone().into(),
+            QueueDeclareOptions {
+                durable: true,
+                ..QueueDeclareOptions::default()
+            },
+            FieldTable::default(),
+        )
+        .await?;
+    channel
+        .queue_bind(
+            config.queue.clone().into(),
+            config.exchange.clone().into(),
+            "".into(),
+            QueueBindOptions::default(),
+            FieldTable::default(),
+        )
+        .await?;
+    info!(target: "app", "declare_dead_letter - dead-letter exchange and queue declared");
+    Ok(())
+}
+
+// publish a copy of the delivery to the dead-letter exchange,
+// adding headers that describe why the message has been rejected.
+// The original delivery must be acked by the caller only if this function returns Ok,
+// that is, only if the server has confirmed the dead-letter.
+pub async fn publish_dead_letter(
+    channel: &Channel,
+    config: &DeadLetterConfig,
+    delivery: &Delivery,
+    err: &MessageError,
+) -> Result<(), AmqpError> {
+    debug!(target: "app", "publish_dead_letter - dead-lettering delivery_tag = {} to exchange = {}", delivery.delivery_tag, &config.exchange);
+    let headers: FieldTable = dead_letter_headers(delivery, err, unix_timestamp());
+    publish_with_headers(channel, config, delivery, headers).await
+}
+
+// publish a copy of a message stored partially to the dead-letter exchange, so the discarded readings can be inspected.
+// The original delivery is settled on its own, because the other readings have already been stored
+pub async fn publish_failed_readings(
+    channel: &Channel,
+    config: &DeadLetterConfig,
+    delivery: &Delivery,
+    failures: &[&ReadingFailure],
+) -> Result<(), AmqpError> {
+    let Some(first) = failures.first() else {
+        return Ok(());
+    };
+    debug!(target: "app", "publish_failed_readings - dead-lettering {} readings of delivery_tag = {} to exchange = {}", failures.len(), delivery.delivery_tag, &config.exchange);
+    let mut headers: FieldTable = dead_letter_headers(delivery, &first.error, unix_timestamp());
+    headers.insert(
+        HEADER_FAILED_READINGS.into(),
+        AMQPValue::LongString(failed_readings(failures).into()),
+    );
+    publish_with_headers(channel, config, delivery, headers).await
+}
+
+async fn publish_with_headers(
+    channel: &Channel,
+    config: &DeadLetterConfig,
+    delivery: &Delivery,
+    headers: FieldTable,
+) -> Result<(), AmqpError> {
+    let properties: BasicProperties = delivery
+        .properties
+        .clone()
+        .with_headers(headers)
+        .with_delivery_mode(PERSISTENT_DELIVERY_MODE);
+    // the original delivery is acked after this call, so the dead-letter must be confirmed by the server
+    publish_confirmed(
+        channel,
+        config.exchange.as_str(),
+        delivery.routing_key.as_str(),
+        delivery.data.as_slice(),
+        properties,
+    )
+    .await
+    .inspect_err(|publish_err| {
+        error!(target: "app", "publish_with_headers - cannot publish to dead-letter exchange. Err = {:?}", publish_err);
+    })
+}
+
+// keep the original headers (if any) and add the dead-letter ones
+pub(crate) fn dead_letter_headers(delivery: &Delivery, err: &MessageError, dead_lettered_at: u64) -> FieldTable {
+    let mut headers: FieldTable = delivery.properties.headers().clone().unwrap_or_default();
+    headers.insert(HEADER_ERROR_TYPE.into(), AMQPValue::LongString(err.kind().into()));
+    headers.insert(
+        HEADER_ERROR_REASON.into(),
+        AMQPValue::LongString(error_reason(err).into()),
+    );
+    insert_origin(&mut headers, delivery);
+    headers.insert(HEADER_DEAD_LETTERED_AT.into(), AMQPValue::Timestamp(dead_lettered_at));
+    headers
+}
+
+// add the exchange and routing key of the delivery, unless the message already carries its origin.
+// Retried messages come back through the default exchange, so their origin is set at the first retry
+pub(crate) fn insert_origin(headers: &mut FieldTable, delivery: &Delivery) {
+    if header_as_string(headers, HEADER_ORIGINAL_EXCHANGE).is_none() {
+        headers.insert(
+            HEADER_ORIGINAL_EXCHANGE.into(),
+            AMQPValue::LongString(delivery.exchange.as_str().into()),
+        );
+    }
+    if header_as_string(headers, HEADER_ORIGINAL_ROUTING_KEY).is_none() {
+        headers.insert(
+            HEADER_ORIGINAL_ROUTING_KEY.into(),
+            AMQPValue::LongString(delivery.routing_key.as_str().into()),
+        );
+    }
+}
+
+// discarded readings separated by commas, e.g. `<feature_uuid> (<feature_name>): <error_type>`
+fn failed_readings(failures: &[&ReadingFailure]) -> String {
+    failures
+        .iter()
+        .map(|failure| {
+            format!(
+                "{} ({}): {}",
+                failure.feature_uuid,
+                failure.feature_name,
+                failure.error.kind()
+            )
+        })
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+// error message followed by its source (if any), e.g. the MongoDB error, or by the validation failure
+fn error_reason(err: &MessageError) -> String {
+    match (err, err.source()) {
+        (
+            MessageError::InvalidValueError(reason)
+            | MessageError::UnsupportedUnitError(reason)
+            | MessageError::DuplicateReadingError(reason)
+            | MessageError::InvalidTopicError(reason),
+            _,
+        ) => {
+            format!("{}: {}", err, reason)
+        }
+        (_, Some(source)) => format!("{}: {}", err, source),
+        (_, None) => err.to_string(),
+    }
+}
+
+pub(crate) fn unix_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default()
+}
+
+pub fn header_as_string(headers: &FieldTable, key: &str) -> Option<String> {
+    let key: ShortString = key.into();
+    match headers.inner().get(&key)? {
+        AMQPValue::LongString(value) => Some(value.to_string()),
+        AMQPValue::ShortString(value) => Some(value.to_string()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::amqp::dead_letter::{
+        HEADER_DEAD_LETTERED_AT, HEADER_ERROR_REASON, HEADER_ERROR_TYPE, HEADER_ORIGINAL_EXCHANGE,
+        HEADER_ORIGINAL_ROUTING_KEY, dead_letter_headers, failed_readings, header_as_string, insert_origin,
+    };
+    use crate::errors::message_error::MessageError;
+    use crate::models::sensor::ReadingFailure;
+    use lapin::BasicProperties;
+    use lapin::message::Delivery;
+    use lapin::types::{AMQPValue, FieldTable, ShortString};
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    #[test_log::test]
+    fn ok_dead_letter_headers() {
+        let delivery = Delivery::mock(1, "amq.topic".into(), "sensors.temperature".into(), false, vec![]);
+        let headers = dead_letter_headers(&delivery, &MessageError::MessageParsingError, 1700000000);
+
+        assert_eq!(
+            header_as_string(&headers, HEADER_ERROR_TYPE),
+            Some("MessageParsingError".to_string())
+        );
+        assert_eq!(
+            header_as_string(&headers, HEADER_ERROR_REASON),
+            Some(MessageError::MessageParsingError.to_string())
+        );
+        assert_eq!(
+            header_as_string(&headers, HEADER_ORIGINAL_EXCHANGE),
+            Some("amq.topic".to_string())
+        );
+        assert_eq!(
+            header_as_string(&headers, HEADER_ORIGINAL_ROUTING_KEY),
+            Some("sensors.temperature".to_string())
+        );
+        assert_eq!(
+            headers.inner().get(&ShortString::from(HEADER_DEAD_LETTERED_AT)),
+            Some(&AMQPValue::Timestamp(1700000000))
+        );
+    }
+
+    #[test]
+    #[test_log::test]
+    fn ok_dead_letter_headers_of_retried_message() {
+        // retried messages are delivered again through the default exchange
+        let mut delivery = Delivery::mock(1, "".into(), "ks89".into(), false, vec![]);
+        let mut headers = FieldTable::default();
+        let origin = Delivery::mock(1, "amq.topic".into(), "sensors.temperature".into(), false, vec![]);
+        insert_origin(&mut headers, &origin);
+        delivery.properties = BasicProperties::default().with_headers(headers);
+        let headers = dead_letter_headers(&delivery, &MessageError::MessageParsingError, 1700000000);
+
+        assert_eq!(
+            header_as_string(&headers, HEADER_ORIGINAL_EXCHANGE),
+            Some("amq.topic".to_string())
+        );
+        assert_eq!(
+            header_as_string(&headers, HEADER_ORIGINAL_ROUTING_KEY),
+            Some("sensors.temperature".to_string())
+        );
+    }
+
+    #[test]
+    #[test_log::test]
+    fn ok_failed_readings() {
+        let duplicate = ReadingFailure {
+            feature_uuid: "temperature-uuid".to_string(),
+            feature_name: "temperature".to_string(),
+            error: MessageError::DuplicateReadingError(
+                "feature_uuid temperature-uuid appears more than once".to_string(),
+            ),
+        };
+        let invalid = ReadingFailure {
+            feature_uuid: "humidity-uuid".to_string(),
+            feature_name: "humidity".to_string(),
+            error: MessageError::InvalidValueError("value out of range".to_string()),
+        };
+        assert_eq!(
+            failed_readings(&[&duplicate, &invalid]),
+            "temperature-uuid (temperature): DuplicateReadingError, humidity-uuid (humidity): InvalidValueError"
+        );
+    }
+}
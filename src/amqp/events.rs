@@ -0,0 +1,147 @@
+use lapin::options::{BasicPublishOptions, ExchangeDeclareOptions};
+use lapin::types::FieldTable;
+use lapin::{BasicProperties, Channel, Confirmation, ExchangeKind, PublisherConfirm};
+use tracing::{debug, error, info};
+
+use crate::config::Env;
+use crate::errors::amqp_error::AmqpError;
+use crate::models::sensor_event::{SENSOR_UPDATED_EVENT, SensorUpdatedEvent};
+
+// persistent delivery mode, so events survive a broker restart
+const PERSISTENT_DELIVERY_MODE: u8 = 2;
+
+// Domain events are published to a durable topic exchange, so other services can bind their own queues.
+// Routing keys are `<event type>.<feature name>`, for example `sensor.updated.temperature`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EventsConfig {
+    pub exchange: String,
+}
+
+impl EventsConfig {
+    // events are published only if the exchange is defined
+    pub fn from_env(env: &Env) -> Option<Self> {
+        let exchange = env
+            .amqp_events_exchange
+            .clone()
+            .filter(|exchange| !exchange.is_empty())?;
+        Some(Self { exchange })
+    }
+}
+
+pub async fn declare_events(channel: &Channel, config: &EventsConfig) -> Result<(), lapin::Error> {
+    info!(target: "app", "declare_events - declaring exchange={}", &config.exchange);
+    channel
+        .exchange_declare(
+            config.exchange.clone().into(),
+            ExchangeKind::Topic,
+            ExchangeDeclareOptions {
+                durable: true,
+                ..ExchangeDeclareOptions::default()
+            },
+            FieldTable::default(),
+        )
+        .await
+}
+
+// Publishes domain events on the channel of the consumer.
+// It's cheap to clone, so every worker can own one.
+#[derive(Clone)]
+pub struct EventPublisher {
+    channel: Channel,
+    config: EventsConfig,
+}
+
+impl EventPublisher {
+    pub fn new(channel: Channel, config: EventsConfig) -> Self {
+        Self { channel, config }
+    }
+
+    pub async fn publish_sensor_updated(&self, event: &SensorUpdatedEvent) -> Result<(), AmqpError> {
+        let routing_key: String = format!("{}.{}", SENSOR_UPDATED_EVENT, event.feature_name);
+        debug!(target: "app", "publish_sensor_updated - publishing event with routing_key = {}", &routing_key);
+        let payload: Vec<u8> = serde_json::to_vec(event)
+            .map_err(|err| AmqpError::PublishError(format!("cannot serialize event: {}", err)))?;
+        let properties: BasicProperties = BasicProperties::default()
+            .with_content_type("application/json".into())
+            .with_type(SENSOR_UPDATED_EVENT.into())
+            .with_delivery_mode(PERSISTENT_DELIVERY_MODE);
+        publish_confirmed(&self.channel, &self.config.exchange, &routing_key, &payload, properties).await
+    }
+}
+
+// publish a message and wait until the AMQP server confirms it
+pub async fn publish_confirmed(
+    channel: &Channel,
+    exchange: &str,
+    routing_key: &str,
+    payload: &[u8],
+    properties: BasicProperties,
+) -> Result<(), AmqpError> {
+    let confirm: PublisherConfirm = channel
+        .basic_publish(
+            exchange.into(),
+            routing_key.into(),
+            BasicPublishOptions::default(),
+            payload,
+            properties,
+        )
+        .await
+        .map_err(|err| {
+            error!(target: "app", "publish_confirmed - cannot publish to exchange = {}. Err = {:?}", exchange, err);
+            AmqpError::PublishError(err.to_string())
+        })?;
+    wait_for_confirm(confirm).await
+}
+
+// wait for the server confirmation of a published message (publisher confirms must be enabled on the channel)
+pub async fn wait_for_confirm(confirm: PublisherConfirm) -> Result<(), AmqpError> {
+    confirmation_result(confirm.await)
+}
+
+// only a confirmation from the server guarantees that the message has been stored.
+// Messages republished on behalf of a delivery (e.g. dead-letters) would be lost otherwise
+pub fn confirmation_result(confirmation: Result<Confirmation, lapin::Error>) -> Result<(), AmqpError> {
+    match confirmation {
+        Ok(Confirmation::Ack(_)) => Ok(()),
+        Ok(Confirmation::Nack(_)) => {
+            error!(target: "app", "confirmation_result - message nacked by the server");
+            Err(AmqpError::PublishError(String::from("message nacked by the server")))
+        }
+        Ok(Confirmation::NotRequested) => {
+            error!(target: "app", "confirmation_result - publisher confirms not enabled on the channel");
+            Err(AmqpError::PublishError(String::from(
+                "publisher confirms not enabled on the channel",
+            )))
+        }
+        Err(err) => {
+            error!(target: "app", "confirmation_result - cannot receive confirmation. Err = {:?}", err);
+            Err(AmqpError::PublishError(err.to_string()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::amqp::events::confirmation_result;
+    use lapin::Confirmation;
+
+    #[test]
+    #[test_log::test]
+    fn ok_confirmation_result() {
+        assert!(confirmation_result(Ok(Confirmation::Ack(None))).is_ok());
+    }
+
+    #[test]
+    #[test_log::test]
+    fn bad_confirmation_result() {
+        assert!(confirmation_result(Ok(Confirmation::Nack(None))).is_err());
+        assert!(confirmation_result(Ok(Confirmation::NotRequested)).is_err());
+        assert!(
+            confirmation_result(Err(lapin::Error::from(lapin::ErrorKind::InvalidChannelState(
+                lapin::ChannelState::Closed,
+                "basic.publish"
+            ))))
+            .is_err()
+        );
+    }
+}
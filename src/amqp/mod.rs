@@ -1,26 +1,72 @@
 use std::string::String;
+use std::time::Duration;
 
 use lapin::message::Delivery;
-use lapin::options::{BasicAckOptions, BasicConsumeOptions, BasicPublishOptions};
-use lapin::types::ShortString;
-use lapin::{
-    BasicProperties, Channel, Connection, ConnectionProperties, Consumer, Error, Queue, options::QueueDeclareOptions,
-    types::FieldTable,
+use lapin::options::{
+    BasicAckOptions, BasicCancelOptions, BasicConsumeOptions, BasicNackOptions, BasicPublishOptions, BasicQosOptions,
+    BasicRejectOptions, ConfirmSelectOptions,
 };
+use lapin::types::{AMQPValue, ShortString};
+use lapin::{BasicProperties, Channel, Connection, ConnectionProperties, Consumer, Error, Queue, types::FieldTable};
+use mongodb::bson::DateTime;
+use tokio::sync::watch;
+use tokio::time::timeout;
 use tracing::{debug, error, info};
 
+use crate::amqp::acks::AckBatchConfig;
+use crate::amqp::dead_letter::{DeadLetterConfig, declare_dead_letter};
+use crate::amqp::events::{EventPublisher, EventsConfig, declare_events, wait_for_confirm};
+use crate::amqp::retry::{RetryConfig, declare_retry};
+use crate::amqp::settler::DeliverySettler;
+use crate::amqp::tls::{TlsConfig, connect_tls};
+use crate::amqp::topology::{QueueConfig, Topology, declare_bindings, declare_exchanges};
+use crate::config::Env;
 use crate::errors::amqp_error::AmqpError;
+use crate::errors::message_error::MessageError;
+
+pub mod acks;
+pub mod dead_letter;
+pub mod events;
+pub mod retry;
+pub mod settler;
+pub mod supervisor;
+pub mod tls;
+pub mod topology;
 
 pub struct AmqpClient {
     amqp_uri: String,
     amqp_queue_name: ShortString,
     consumer_tag: ShortString,
+    dead_letter: Option<DeadLetterConfig>,
+    retry: Option<RetryConfig>,
+    events: Option<EventsConfig>,
+    acks: Option<AckBatchConfig>,
+    prefetch_count: u16,
+    topology: Topology,
+    tls: Option<TlsConfig>,
     properties: ConnectionProperties,
     connection: Option<Connection>,
     channel: Option<Channel>,
     queue: Option<Queue>,
     pub consumer: Option<Consumer>,
     connecting: bool,
+    status: watch::Sender<ConnectionStatus>,
+}
+
+// max time to wait for a broken connection to close during a teardown
+const TEARDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+// state of the connection with the AMQP server
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionStatus {
+    Disconnected,
+    Connecting,
+    Connected,
+    // waiting for lapin auto-recovery
+    Recovering,
+    // auto-recovery failed, the supervisor is rebuilding the client
+    Reconnecting { attempt: u32 },
+    Closed,
 }
 
 impl AmqpClient {
@@ -37,6 +83,14 @@ impl AmqpClient {
             connecting: false,
             consumer: None,
             consumer_tag: "".into(),
+            dead_letter: None,
+            retry: None,
+            events: None,
+            acks: None,
+            prefetch_count: 0,
+            topology: Topology::default(),
+            tls: None,
+            status: watch::Sender::new(ConnectionStatus::Disconnected),
         }
     }
 
@@ -46,6 +100,53 @@ impl AmqpClient {
         self
     }
 
+    // Use the builder pattern to init an optional param
+    pub fn dead_letter(mut self, dead_letter: Option<DeadLetterConfig>) -> AmqpClient {
+        self.dead_letter = dead_letter;
+        self
+    }
+
+    // Use the builder pattern to init an optional param
+    pub fn retry(mut self, retry: Option<RetryConfig>) -> AmqpClient {
+        self.retry = retry;
+        self
+    }
+
+    // Use the builder pattern to init an optional param.
+    // Exchange where domain events are published
+    pub fn events(mut self, events: Option<EventsConfig>) -> AmqpClient {
+        self.events = events;
+        self
+    }
+
+    // Use the builder pattern to init an optional param.
+    // Successful deliveries are acked together with multiple-acks
+    pub fn acks(mut self, acks: Option<AckBatchConfig>) -> AmqpClient {
+        self.acks = acks;
+        self
+    }
+
+    // Use the builder pattern to init an optional param.
+    // Max number of unacked deliveries sent by the server to this client (0 means unlimited)
+    pub fn prefetch(mut self, prefetch_count: u16) -> AmqpClient {
+        self.prefetch_count = prefetch_count;
+        self
+    }
+
+    // Use the builder pattern to init an optional param.
+    // Exchanges, queues and bindings declared at connect time
+    pub fn topology(mut self, topology: Topology) -> AmqpClient {
+        self.topology = topology;
+        self
+    }
+
+    // Use the builder pattern to init an optional param.
+    // Custom CA, client certificate and server name used with `amqps://` URIs
+    pub fn tls(mut self, tls: Option<TlsConfig>) -> AmqpClient {
+        self.tls = tls;
+        self
+    }
+
     pub fn is_connected(&self, with_consumer: bool) -> bool {
         // check if you are calling this method on an initialized amqp_client instance
         // (with both connection, channel and queue)
@@ -56,32 +157,79 @@ impl AmqpClient {
         self.connection.as_ref().unwrap().status().connected() && self.channel.as_ref().unwrap().status().connected()
     }
 
-    pub async fn connect(&mut self, is_consumer: bool) {
+    // observe connection state changes
+    pub fn status(&self) -> watch::Receiver<ConnectionStatus> {
+        self.status.subscribe()
+    }
+
+    fn set_status(&self, status: ConnectionStatus) {
+        self.status.send_replace(status);
+    }
+
+    pub async fn connect(&mut self, is_consumer: bool) -> Result<(), AmqpError> {
         info!(target: "app", "connect - trying to connect to amqp_uri={} with queue={}", &self.amqp_uri, &self.amqp_queue_name);
         self.connecting = true;
-        self.create_connection().await.expect("cannot create connection");
+        self.set_status(ConnectionStatus::Connecting);
+        let connect_result: Result<(), AmqpError> = self.create_all(is_consumer).await;
+        self.connecting = false;
+        match connect_result {
+            Ok(_) => {
+                self.set_status(ConnectionStatus::Connected);
+                info!(target: "app", "connect - AMQP connection done!");
+                Ok(())
+            }
+            Err(err) => {
+                self.set_status(ConnectionStatus::Disconnected);
+                error!(target: "app", "connect - cannot connect. Err = {:?}", err);
+                Err(err)
+            }
+        }
+    }
+
+    async fn create_all(&mut self, is_consumer: bool) -> Result<(), AmqpError> {
+        self.create_connection().await?;
         info!(target: "app", "connect - creating channel...");
-        self.create_channel().await.expect("cannot create channel");
+        self.create_channel().await?;
         info!(target: "app", "connect - declaring queue...");
-        self.declare_queue().await.expect("cannot declare queue");
+        self.declare_queue().await?;
         if is_consumer {
             info!(target: "app", "connect - creating consumer...");
-            self.create_consumer().await.expect("cannot declare consumer");
+            self.create_consumer().await?;
         }
-        self.connecting = false;
-        info!(target: "app", "connect - AMQP connection done!");
+        Ok(())
+    }
+
+    // close the connection (if any) ignoring errors, because it's probably already broken,
+    // and drop connection, channel, queue and consumer, so `connect` can rebuild them
+    pub async fn teardown(&mut self) {
+        info!(target: "app", "teardown - dropping AMQP connection, channel, queue and consumer...");
+        if let Some(connection) = self.connection.as_ref()
+            && connection.status().connected()
+        {
+            let close_result = timeout(TEARDOWN_TIMEOUT, connection.close(0, "teardown".into())).await;
+            debug!(target: "app", "teardown - close result = {:?}", close_result);
+        }
+        self.consumer = None;
+        self.queue = None;
+        self.channel = None;
+        self.connection = None;
+        self.set_status(ConnectionStatus::Disconnected);
     }
 
     async fn create_connection(&mut self) -> Result<(), AmqpError> {
         info!(target: "app", "create_connection - creating AMQP connection...");
-        self.connection = match Connection::connect(&self.amqp_uri, self.properties.clone()).await {
+        let connect_result = match self.tls.as_ref() {
+            Some(tls) => connect_tls(&self.amqp_uri, self.properties.clone(), tls).await,
+            None => Connection::connect(&self.amqp_uri, self.properties.clone()).await,
+        };
+        self.connection = match connect_result {
             Ok(connection) => {
                 info!(target: "app", "create_connection - AMQP connection established");
                 Some(connection)
             }
             Err(err) => {
                 error!(target: "app", "create_connection - cannot create AMQP connection. Err = {:?}", err);
-                None
+                return Err(AmqpError::ConnectionError(err.to_string()));
             }
         };
         Ok(())
@@ -104,9 +252,26 @@ impl AmqpClient {
             }
             Err(err) => {
                 error!(target: "app", "create_channel - cannot create AMQP channel. Err = {:?}", err);
-                None
+                return Err(AmqpError::ConnectionError(err.to_string()));
             }
         };
+        // limit the number of in-flight deliveries (basic.qos)
+        if self.prefetch_count > 0
+            && let Some(channel) = self.channel.as_ref()
+            && let Err(err) = channel.basic_qos(self.prefetch_count, BasicQosOptions::default()).await
+        {
+            error!(target: "app", "create_channel - cannot set AMQP prefetch count. Err = {:?}", err);
+            return Err(AmqpError::DeclareError(String::from("cannot set prefetch count")));
+        }
+        // enable publisher confirms, so published messages are acknowledged by the server
+        if let Some(channel) = self.channel.as_ref()
+            && let Err(err) = channel.confirm_select(ConfirmSelectOptions::default()).await
+        {
+            error!(target: "app", "create_channel - cannot enable publisher confirms. Err = {:?}", err);
+            return Err(AmqpError::ConnectionError(String::from(
+                "cannot enable publisher confirms",
+            )));
+        }
         Ok(())
     }
 
@@ -121,26 +286,72 @@ impl AmqpClient {
         // instead of the verbose syntax
         // if let Err(err) = init_result { return Err(err); }
         init_result?;
-        self.queue = match self
-            .channel
-            .as_ref()
-            .unwrap()
-            .queue_declare(
-                self.amqp_queue_name.clone(),
-                QueueDeclareOptions::default(),
-                FieldTable::default(),
-            )
-            .await
+        let channel: &Channel = self.channel.as_ref().unwrap();
+        // declare exchanges and secondary queues defined in the topology, before the main queue
+        if let Err(err) = declare_exchanges(channel, &self.topology).await {
+            error!(target: "app", "declare_queue - cannot declare AMQP exchanges. Err = {:?}", err);
+            return Err(AmqpError::DeclareError(String::from("cannot declare exchanges")));
+        }
+        for queue_config in self
+            .topology
+            .queues
+            .iter()
+            .filter(|queue| queue.name != self.amqp_queue_name.as_str())
         {
+            if let Err(err) = topology::declare_queue(channel, queue_config).await {
+                error!(target: "app", "declare_queue - cannot declare AMQP queue {}. Err = {:?}", &queue_config.name, err);
+                return Err(AmqpError::DeclareError(format!(
+                    "cannot declare queue {}",
+                    &queue_config.name
+                )));
+            }
+        }
+        // the main queue uses default options, if not defined in the topology
+        let queue_config: QueueConfig = self
+            .topology
+            .queue(self.amqp_queue_name.as_str())
+            .cloned()
+            .unwrap_or_else(|| QueueConfig::with_defaults(self.amqp_queue_name.as_str()));
+        self.queue = match topology::declare_queue(channel, &queue_config).await {
             Ok(channel) => {
                 info!(target: "app", "declare_queue - AMQP queue created");
                 Some(channel)
             }
             Err(err) => {
                 error!(target: "app", "declare_queue - cannot create AMQP queue. Err = {:?}", err);
-                None
+                return Err(AmqpError::DeclareError(format!(
+                    "cannot declare queue {}",
+                    &queue_config.name
+                )));
             }
         };
+        if let Err(err) = declare_bindings(channel, &self.topology).await {
+            error!(target: "app", "declare_queue - cannot declare AMQP bindings. Err = {:?}", err);
+            return Err(AmqpError::DeclareError(String::from("cannot declare bindings")));
+        }
+        // declare the dead-letter exchange and queue alongside the main queue
+        if let Some(dead_letter) = self.dead_letter.as_ref()
+            && let Err(err) = declare_dead_letter(self.channel.as_ref().unwrap(), dead_letter).await
+        {
+            error!(target: "app", "declare_queue - cannot declare dead-letter exchange and queue. Err = {:?}", err);
+            return Err(AmqpError::DeclareError(String::from(
+                "cannot declare dead-letter exchange and queue",
+            )));
+        }
+        // declare the delay queues used to retry messages with exponential backoff
+        if let Some(retry) = self.retry.as_ref()
+            && let Err(err) = declare_retry(self.channel.as_ref().unwrap(), retry).await
+        {
+            error!(target: "app", "declare_queue - cannot declare retry queues. Err = {:?}", err);
+            return Err(AmqpError::DeclareError(String::from("cannot declare retry queues")));
+        }
+        // declare the exchange used to publish domain events
+        if let Some(events) = self.events.as_ref()
+            && let Err(err) = declare_events(self.channel.as_ref().unwrap(), events).await
+        {
+            error!(target: "app", "declare_queue - cannot declare events exchange. Err = {:?}", err);
+            return Err(AmqpError::DeclareError(String::from("cannot declare events exchange")));
+        }
         Ok(())
     }
 
@@ -173,35 +384,44 @@ impl AmqpClient {
             }
             Err(err) => {
                 error!(target: "app", "create_consumer - cannot create AMQP consumer. Err = {:?}", err);
-                None
+                return Err(AmqpError::ConnectionError(err.to_string()));
             }
         };
         Ok(())
     }
 
-    // before calling this method you must be sure that a channel has been created
-    pub async fn publish_message(&mut self, amqp_queue_name: &str, msg_byte: Vec<u8>) -> Result<(), AmqpError> {
-        debug!(target: "app", "publish_message - publishing byte message to queue {}...", amqp_queue_name);
+    // before calling this method you must be sure that a channel has been created.
+    // Use "" as `exchange` to publish directly to the queue `routing_key` via the default exchange.
+    // It returns when the server has confirmed the message (publisher confirms)
+    pub async fn publish_message(
+        &mut self,
+        exchange: &str,
+        routing_key: &str,
+        msg_byte: Vec<u8>,
+    ) -> Result<(), AmqpError> {
+        debug!(target: "app", "publish_message - publishing byte message to exchange = {} with routing_key = {}...", exchange, routing_key);
         if self.connecting {
             error!(target: "app", "publish_message - cannot publish while amqp_client is not initialized");
             return Err(AmqpError::Uninitialized(String::from(
                 "cannot publish while amqp_client is not initialized",
             )));
         }
+        // check if you are calling this method on an initialized amqp_client instance (with both connection and channel)
+        self.is_initialized(true, true, false, false)?;
         let publish_result = self
             .channel
             .as_ref()
             .unwrap()
             .basic_publish(
-                "".into(),
-                amqp_queue_name.into(),
+                exchange.into(),
+                routing_key.into(),
                 BasicPublishOptions::default(),
                 msg_byte.as_slice(),
                 BasicProperties::default(),
             )
             .await;
         match publish_result {
-            Ok(_) => Ok(()),
+            Ok(confirm) => wait_for_confirm(confirm).await,
             Err(err) => {
                 self.connecting = true;
                 error!(target: "app", "publish_message - cannot publish, waiting for recovery...");
@@ -221,6 +441,30 @@ impl AmqpClient {
         }
     }
 
+    // create a publisher bound to the current channel, used by workers to publish domain events.
+    // Returns None if events are disabled
+    pub fn event_publisher(&self) -> Result<Option<EventPublisher>, AmqpError> {
+        // check if you are calling this method on an initialized amqp_client instance (with both connection and channel)
+        self.is_initialized(true, true, false, false)?;
+        Ok(self
+            .events
+            .clone()
+            .map(|events| EventPublisher::new(self.channel.as_ref().unwrap().clone(), events)))
+    }
+
+    // create a settler bound to the current channel, used to ack/nack/reject deliveries
+    // and to republish them to the retry or dead-letter queues
+    pub fn settler(&self) -> Result<DeliverySettler, AmqpError> {
+        // check if you are calling this method on an initialized amqp_client instance (with both connection and channel)
+        self.is_initialized(true, true, false, false)?;
+        Ok(DeliverySettler::new(
+            self.channel.as_ref().unwrap().clone(),
+            self.dead_letter.clone(),
+            self.retry.clone(),
+            self.acks.clone(),
+        ))
+    }
+
     fn is_initialized(
         &self,
         check_connection: bool,
@@ -259,6 +503,46 @@ impl AmqpClient {
         self.connection.as_ref().unwrap().close(0, "".into()).await
     }
 
+    // stop receiving new deliveries (basic.cancel).
+    // Deliveries already received can still be settled, because the channel remains open
+    pub async fn cancel_consumer(&mut self) -> Result<(), AmqpError> {
+        info!(target: "app", "cancel_consumer - cancelling AMQP consumer...");
+        // check if you are calling this method on an initialized amqp_client instance (with all fields)
+        self.is_initialized(true, true, true, true)?;
+        let consumer_tag: ShortString = self.consumer.as_ref().unwrap().tag();
+        self.channel
+            .as_ref()
+            .unwrap()
+            .basic_cancel(consumer_tag, BasicCancelOptions::default())
+            .await
+            .map_err(|err| {
+                error!(target: "app", "cancel_consumer - cannot cancel AMQP consumer. Err = {:?}", err);
+                AmqpError::CloseError(err.to_string())
+            })
+    }
+
+    // close both channel and connection
+    pub async fn close(&mut self) -> Result<(), AmqpError> {
+        info!(target: "app", "close - closing AMQP channel and connection...");
+        // check if you are calling this method on an initialized amqp_client instance (with both connection and channel)
+        self.is_initialized(true, true, false, false)?;
+        if let Err(err) = self.channel.as_ref().unwrap().close(200, "shutdown".into()).await {
+            error!(target: "app", "close - cannot close AMQP channel. Err = {:?}", err);
+        }
+        let close_result = self
+            .connection
+            .as_ref()
+            .unwrap()
+            .close(200, "shutdown".into())
+            .await
+            .map_err(|err| {
+                error!(target: "app", "close - cannot close AMQP connection. Err = {:?}", err);
+                AmqpError::CloseError(err.to_string())
+            });
+        self.set_status(ConnectionStatus::Closed);
+        close_result
+    }
+
     pub async fn wait_for_recovery(&mut self, err: Error) -> Result<(), AmqpError> {
         info!(target: "app", "wait_for_recovery");
         // check if you are calling this method on an initialized amqp_client instance
@@ -268,35 +552,129 @@ impl AmqpClient {
         // I'm using the '?' operator as https://rust-lang.github.io/rust-clippy/master/index.html#/question_mark
         // instead of the verbose syntax
         // if let Err(err) = init_result { return Err(err); }
-        // init_result?;
+        init_result?;
+        self.set_status(ConnectionStatus::Recovering);
         let recovery_result = self.channel.as_ref().unwrap().wait_for_recovery(err).await;
         match recovery_result {
             Ok(_) => {
                 self.connecting = false;
+                self.set_status(ConnectionStatus::Connected);
                 Err(AmqpError::ErrorButRecovered(String::from(
                     "amqp_client error, but connection recovered",
                 )))
             }
-            Err(_) => Err(AmqpError::ErrorCannotRecover(String::from(
-                "amqp_client error, cannot auto recover",
-            ))),
+            Err(_) => {
+                self.set_status(ConnectionStatus::Disconnected);
+                Err(AmqpError::ErrorCannotRecover(String::from(
+                    "amqp_client error, cannot auto recover",
+                )))
+            }
         }
     }
 }
 
-pub async fn read_message(delivery: &Delivery) -> &str {
-    delivery.ack(BasicAckOptions::default()).await.expect("basic_ack");
+// the decision taken for a delivery after its processing,
+// used to settle the delivery with the AMQP server (manual ack/nack/reject)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AckDecision {
+    // message processed and persisted, remove it from the queue
+    Ack,
+    // transient failure, put the message back in the queue to process it again
+    Requeue,
+    // permanent failure, processing the message again won't change the result
+    Reject,
+}
+
+impl From<&MessageError> for AckDecision {
+    fn from(err: &MessageError) -> Self {
+        // a newer reading has already been stored, or the reading has been stored until its sensor is registered,
+        // so there is nothing to retry or inspect
+        if let MessageError::StaleReadingError(_) | MessageError::SensorPendingError(_) = err {
+            return AckDecision::Ack;
+        }
+        if err.is_transient() {
+            AckDecision::Requeue
+        } else {
+            AckDecision::Reject
+        }
+    }
+}
+
+// deliveries are completed in background after their processing (see `KeyedTasks`),
+// so the prefetch count is the only limit of the deliveries in progress and cannot be unlimited (0)
+pub fn prefetch_count(env: &Env) -> Result<u16, String> {
+    match env.amqp_prefetch_count {
+        0 => Err("prefetch count must be greater than 0".to_string()),
+        prefetch_count => Ok(prefetch_count),
+    }
+}
+
+// Receive time of a message, kept in the headers of its retried and dead-lettered copies.
+// Readings without a device timestamp are observed at the receive time, so when the message
+// is processed again they keep the same observation time and the history doesn't store them twice.
+// Deliveries requeued by the server (nack or connection loss) come back with their original headers,
+// so they get a new receive time
+pub const HEADER_RECEIVED_AT: &str = "x-received-at";
+
+// set the receive time of a delivery, unless it has already been received before (e.g. it's a retry)
+pub fn stamp_received_at(delivery: &mut Delivery) {
+    if received_at_header(delivery).is_some() {
+        return;
+    }
+    let mut headers: FieldTable = delivery.properties.headers().clone().unwrap_or_default();
+    headers.insert(
+        HEADER_RECEIVED_AT.into(),
+        AMQPValue::LongLongInt(DateTime::now().timestamp_millis()),
+    );
+    delivery.properties = delivery.properties.clone().with_headers(headers);
+}
+
+// receive time of a delivery, or now if it hasn't been stamped
+pub fn received_at(delivery: &Delivery) -> DateTime {
+    received_at_header(delivery).unwrap_or_else(DateTime::now)
+}
+
+fn received_at_header(delivery: &Delivery) -> Option<DateTime> {
+    let key: ShortString = HEADER_RECEIVED_AT.into();
+    match delivery.properties.headers().as_ref()?.inner().get(&key)? {
+        AMQPValue::LongLongInt(millis) => Some(DateTime::from_millis(*millis)),
+        _ => None,
+    }
+}
+
+// read the payload of a delivery without acking it.
+// The delivery must be settled via `settle_delivery` after its processing.
+pub fn read_message(delivery: &Delivery) -> &str {
     std::str::from_utf8(&delivery.data).unwrap_or_else(|err| {
         error!(target: "app", "read_message - cannot read payload as utf8. Error = {}", err);
         ""
     })
 }
 
+pub async fn settle_delivery(delivery: &Delivery, decision: AckDecision) -> Result<bool, Error> {
+    debug!(target: "app", "settle_delivery - delivery_tag = {}, decision = {:?}", delivery.delivery_tag, decision);
+    match decision {
+        AckDecision::Ack => delivery.ack(BasicAckOptions::default()).await,
+        AckDecision::Requeue => {
+            delivery
+                .nack(BasicNackOptions {
+                    multiple: false,
+                    requeue: true,
+                })
+                .await
+        }
+        AckDecision::Reject => delivery.reject(BasicRejectOptions { requeue: false }).await,
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::amqp::AmqpClient;
+    use crate::amqp::{AckDecision, AmqpClient, received_at, stamp_received_at};
     use crate::config::{Env, init};
     use crate::errors::amqp_error::AmqpError;
+    use crate::errors::db_error::DbError;
+    use crate::errors::message_error::MessageError;
+    use lapin::message::Delivery;
     use pretty_assertions::assert_eq;
 
     #[test]
@@ -342,4 +720,54 @@ mod tests {
             .to_string()
         );
     }
+
+    #[test]
+    #[test_log::test]
+    fn ok_stamp_received_at() {
+        let mut delivery = Delivery::mock(1, "amq.topic".into(), "sensors".into(), false, vec![]);
+        stamp_received_at(&mut delivery);
+        let first_received_at = received_at(&delivery);
+        // a retried delivery keeps the receive time of the first one
+        std::thread::sleep(std::time::Duration::from_millis(5));
+        stamp_received_at(&mut delivery);
+        assert_eq!(received_at(&delivery), first_received_at);
+    }
+
+    #[test]
+    #[test_log::test]
+    fn ack_decision_from_message_error() {
+        assert_eq!(
+            AckDecision::from(&MessageError::MessageParsingError),
+            AckDecision::Reject
+        );
+        assert_eq!(
+            AckDecision::from(&MessageError::NoneValuePayloadError),
+            AckDecision::Reject
+        );
+        assert_eq!(
+            AckDecision::from(&MessageError::UpdateDbError(DbError::from(
+                mongodb::error::Error::custom("db error")
+            ))),
+            AckDecision::Requeue
+        );
+        // a concurrent upsert has inserted the same document first
+        assert_eq!(
+            AckDecision::from(&MessageError::UpdateDbError(DbError::Duplicate(
+                mongodb::error::Error::custom("duplicate key")
+            ))),
+            AckDecision::Requeue
+        );
+        assert_eq!(
+            AckDecision::from(&MessageError::from(DbError::NotFound("sensor".to_string()))),
+            AckDecision::Reject
+        );
+        assert_eq!(
+            AckDecision::from(&MessageError::StaleReadingError("older reading".to_string())),
+            AckDecision::Ack
+        );
+        assert_eq!(
+            AckDecision::from(&MessageError::SensorPendingError("not registered".to_string())),
+            AckDecision::Ack
+        );
+    }
 }
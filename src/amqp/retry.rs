@@ -0,0 +1,266 @@
+use lapin::message::Delivery;
+use lapin::options::QueueDeclareOptions;
+use lapin::types::{AMQPValue, FieldTable, ShortString};
+use lapin::{BasicProperties, Channel};
+use tracing::{debug, error, info};
+
+use crate::amqp::dead_letter::{dead_letter_headers, insert_origin, unix_timestamp};
+use crate::amqp::events::publish_confirmed;
+use crate::config::Env;
+use crate::errors::amqp_error::AmqpError;
+use crate::errors::message_error::MessageError;
+
+// number of times a message has already been retried
+pub const HEADER_RETRY_COUNT: &str = "x-retry-count";
+
+// persistent delivery mode, so messages waiting for a retry survive a broker restart
+const PERSISTENT_DELIVERY_MODE: u8 = 2;
+
+// Messages that failed because of a transient error are republished to a delay queue
+// with a TTL (`x-message-ttl`). When the TTL expires, RabbitMQ dead-letters them
+// back to the main queue via the default exchange, so they are processed again.
+// The original exchange and routing key are kept in the headers, so parked messages report the real origin.
+// Every retry uses a longer delay (exponential backoff), until `max_attempts` is reached
+// and the message is moved to the parking queue.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryConfig {
+    pub queue_name: String,
+    pub max_attempts: u32,
+    pub initial_delay_ms: u64,
+    pub multiplier: u32,
+    pub max_delay_ms: u64,
+    pub parking_queue: String,
+}
+
+// where a message has been republished
+#[derive(Debug, Clone, PartialEq)]
+pub enum RetryTarget {
+    Delay { queue: String, delay_ms: u64, attempt: u32 },
+    Parking { queue: String },
+}
+
+impl RetryConfig {
+    // retries are enabled only if `amqp_retry_max_attempts` is greater than 0.
+    // If the parking queue is not defined, it will be called `<amqp_queue_name>.parking`
+    pub fn from_env(env: &Env) -> Option<Self> {
+        if env.amqp_retry_max_attempts == 0 {
+            return None;
+        }
+        let parking_queue = env
+            .amqp_retry_parking_queue
+            .clone()
+            .filter(|queue| !queue.is_empty())
+            .unwrap_or_else(|| format!("{}.parking", env.amqp_queue_name));
+        Some(Self {
+            queue_name: env.amqp_queue_name.clone(),
+            max_attempts: env.amqp_retry_max_attempts,
+            initial_delay_ms: env.amqp_retry_initial_delay_ms,
+            multiplier: env.amqp_retry_multiplier.max(1),
+            max_delay_ms: env.amqp_retry_max_delay_ms,
+            parking_queue,
+        })
+    }
+
+    // delay before the `attempt`-th retry (starting from 1), capped to `max_delay_ms`
+    pub fn delay_ms(&self, attempt: u32) -> u64 {
+        let factor: u64 = (self.multiplier as u64).saturating_pow(attempt.saturating_sub(1));
+        self.initial_delay_ms.saturating_mul(factor).min(self.max_delay_ms)
+    }
+
+    pub fn delay_queue_name(&self, delay_ms: u64) -> String {
+        format!("{}.retry.{}ms", self.queue_name, delay_ms)
+    }
+
+    // distinct delays used by all attempts, because capped delays share the same queue
+    pub fn delays_ms(&self) -> Vec<u64> {
+        let mut delays: Vec<u64> = (1..=self.max_attempts).map(|attempt| self.delay_ms(attempt)).collect();
+        delays.dedup();
+        delays
+    }
+
+    // where to republish a message that has already been retried `retry_count` times
+    pub fn next_target(&self, retry_count: u32) -> RetryTarget {
+        if retry_count >= self.max_attempts {
+            RetryTarget::Parking {
+                queue: self.parking_queue.clone(),
+            }
+        } else {
+            let attempt: u32 = retry_count + 1;
+            let delay_ms: u64 = self.delay_ms(attempt);
+            RetryTarget::Delay {
+                queue: self.delay_queue_name(delay_ms),
+                delay_ms,
+                attempt,
+            }
+        }
+    }
+}
+
+// declare a durable delay queue for every distinct delay and the parking queue
+pub async fn declare_retry(channel: &Channel, config: &RetryConfig) -> Result<(), lapin::Error> {
+    for delay_ms in config.delays_ms() {
+        let delay_queue: String = config.delay_queue_name(delay_ms);
+        info!(target: "app", "declare_retry - declaring delay queue={}", &delay_queue);
+        let mut arguments = FieldTable::default();
+        arguments.insert("x-message-ttl".into(), AMQPValue::LongLongInt(delay_ms as i64));
+        // when the TTL expires, send the message back to the main queue via the default exchange
+        arguments.insert("x-dead-letter-exchange".into(), AMQPValue::LongString("".into()));
+        arguments.insert(
+            "x-dead-letter-routing-key".into(),
+            AMQPValue::LongString(config.queue_name.as_str().into()),
+        );
+        channel
+            .queue_declare(
+                delay_queue.into(),
+                QueueDeclareOptions {
+                    durable: true,
+                    ..QueueDeclareOptions::default()
+                },
+                arguments,
+            )
+            .await?;
+    }
+    info!(target: "app", "declare_retry - declaring parking queue={}", &config.parking_queue);
+    channel
+        .queue_declare(
+            config.parking_queue.clone().into(),
+            QueueDeclareOptions {
+                durable: true,
+                ..QueueDeclareOptions::default()
+            },
+            FieldTable::default(),
+        )
+        .await?;
+    info!(target: "app", "declare_retry - delay and parking queues declared");
+    Ok(())
+}
+
+// republish the delivery to the next delay queue (or to the parking queue, if there are no attempts left).
+// The original delivery must be acked by the caller only if this function returns Ok,
+// that is, only if the server has confirmed the republished message.
+pub async fn publish_retry(
+    channel: &Channel,
+    config: &RetryConfig,
+    delivery: &Delivery,
+    err: &MessageError,
+) -> Result<RetryTarget, AmqpError> {
+    let retry_count: u32 = retry_count(delivery);
+    let target: RetryTarget = config.next_target(retry_count);
+    debug!(target: "app", "publish_retry - delivery_tag = {}, retry_count = {}, target = {:?}", delivery.delivery_tag, retry_count, &target);
+    let (queue, headers): (&String, FieldTable) = match &target {
+        RetryTarget::Delay { queue, attempt, .. } => {
+            let mut headers: FieldTable = delivery.properties.headers().clone().unwrap_or_default();
+            headers.insert(HEADER_RETRY_COUNT.into(), AMQPValue::LongLongInt(*attempt as i64));
+            // the message comes back through the default exchange, so its origin is kept in the headers
+            insert_origin(&mut headers, delivery);
+            (queue, headers)
+        }
+        // parked messages carry the same headers of dead-lettered ones, so they can be inspected and replayed
+        RetryTarget::Parking { queue } => (queue, dead_letter_headers(delivery, err, unix_timestamp())),
+    };
+    let properties: BasicProperties = delivery
+        .properties
+        .clone()
+        .with_headers(headers)
+        .with_delivery_mode(PERSISTENT_DELIVERY_MODE);
+    // the original delivery is acked after this call, so the retry must be confirmed by the server
+    publish_confirmed(channel, "", queue.as_str(), delivery.data.as_slice(), properties)
+        .await
+        .inspect_err(|publish_err| {
+            error!(target: "app", "publish_retry - cannot publish to queue {}. Err = {:?}", queue, publish_err);
+        })?;
+    Ok(target)
+}
+
+// number of retries already done, read from the `x-retry-count` header (0 if missing)
+pub fn retry_count(delivery: &Delivery) -> u32 {
+    let key: ShortString = HEADER_RETRY_COUNT.into();
+    let value: Option<&AMQPValue> = delivery
+        .properties
+        .headers()
+        .as_ref()
+        .and_then(|headers| headers.inner().get(&key));
+    match value {
+        Some(AMQPValue::ShortShortUInt(count)) => *count as u32,
+        Some(AMQPValue::ShortUInt(count)) => *count as u32,
+        Some(AMQPValue::LongUInt(count)) => *count,
+        Some(AMQPValue::ShortShortInt(count)) => (*count).max(0) as u32,
+        Some(AMQPValue::ShortInt(count)) => (*count).max(0) as u32,
+        Some(AMQPValue::LongInt(count)) => (*count).max(0) as u32,
+        Some(AMQPValue::LongLongInt(count)) => (*count).clamp(0, u32::MAX as i64) as u32,
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::amqp::retry::{HEADER_RETRY_COUNT, RetryConfig, RetryTarget, retry_count};
+    use lapin::BasicProperties;
+    use lapin::message::Delivery;
+    use lapin::types::{AMQPValue, FieldTable};
+    use pretty_assertions::assert_eq;
+
+    fn retry_config() -> RetryConfig {
+        RetryConfig {
+            queue_name: "ks89".to_string(),
+            max_attempts: 5,
+            initial_delay_ms: 1000,
+            multiplier: 2,
+            max_delay_ms: 5000,
+            parking_queue: "ks89.parking".to_string(),
+        }
+    }
+
+    #[test]
+    #[test_log::test]
+    fn ok_exponential_delays() {
+        let config = retry_config();
+        assert_eq!(config.delay_ms(1), 1000);
+        assert_eq!(config.delay_ms(2), 2000);
+        assert_eq!(config.delay_ms(3), 4000);
+        // capped to max_delay_ms
+        assert_eq!(config.delay_ms(4), 5000);
+        assert_eq!(config.delay_ms(5), 5000);
+        assert_eq!(config.delays_ms(), vec![1000, 2000, 4000, 5000]);
+    }
+
+    #[test]
+    #[test_log::test]
+    fn ok_next_target() {
+        let config = retry_config();
+        assert_eq!(
+            config.next_target(0),
+            RetryTarget::Delay {
+                queue: "ks89.retry.1000ms".to_string(),
+                delay_ms: 1000,
+                attempt: 1
+            }
+        );
+        assert_eq!(
+            config.next_target(2),
+            RetryTarget::Delay {
+                queue: "ks89.retry.4000ms".to_string(),
+                delay_ms: 4000,
+                attempt: 3
+            }
+        );
+        assert_eq!(
+            config.next_target(5),
+            RetryTarget::Parking {
+                queue: "ks89.parking".to_string()
+            }
+        );
+    }
+
+    #[test]
+    #[test_log::test]
+    fn ok_retry_count() {
+        let mut delivery = Delivery::mock(1, "".into(), "ks89".into(), false, vec![]);
+        assert_eq!(retry_count(&delivery), 0);
+
+        let mut headers = FieldTable::default();
+        headers.insert(HEADER_RETRY_COUNT.into(), AMQPValue::LongLongInt(3));
+        delivery.properties = BasicProperties::default().with_headers(headers);
+        assert_eq!(retry_count(&delivery), 3);
+    }
+}
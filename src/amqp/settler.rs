@@ -0,0 +1,168 @@
+use std::sync::Arc;
+
+use lapin::Channel;
+use lapin::message::Delivery;
+use tracing::{error, info, warn};
+
+use crate::amqp::acks::{AckBatchConfig, AckBatcher};
+use crate::amqp::dead_letter::{DeadLetterConfig, publish_dead_letter, publish_failed_readings};
+use crate::amqp::retry::{RetryConfig, publish_retry};
+use crate::amqp::{AckDecision, settle_delivery};
+use crate::errors::amqp_error::AmqpError;
+use crate::errors::message_error::MessageError;
+use crate::models::sensor::ReadingFailure;
+
+// Settles deliveries based on the outcome of their processing.
+// It's cheap to clone, so every worker can own one.
+#[derive(Clone)]
+pub struct DeliverySettler {
+    channel: Channel,
+    dead_letter: Option<DeadLetterConfig>,
+    retry: Option<RetryConfig>,
+    // shared by all clones, because delivery tags are scoped to the channel
+    acks: Option<Arc<AckBatcher>>,
+}
+
+impl DeliverySettler {
+    pub fn new(
+        channel: Channel,
+        dead_letter: Option<DeadLetterConfig>,
+        retry: Option<RetryConfig>,
+        acks: Option<AckBatchConfig>,
+    ) -> Self {
+        let acks: Option<Arc<AckBatcher>> = acks.map(|acks| AckBatcher::spawn(channel.clone(), acks));
+        Self {
+            channel,
+            dead_letter,
+            retry,
+            acks,
+        }
+    }
+
+    pub fn channel(&self) -> &Channel {
+        &self.channel
+    }
+
+    // ack, nack or reject the delivery, where `err` is None if the message has been processed successfully.
+    // Returns the decision used to settle the delivery
+    pub async fn settle(&self, delivery: &Delivery, err: Option<&MessageError>) -> AckDecision {
+        let mut decision: AckDecision = match err {
+            None => AckDecision::Ack,
+            Some(err) => AckDecision::from(err),
+        };
+        // retry messages that failed because of transient errors later, using delay queues with exponential backoff
+        if let (AckDecision::Requeue, Some(err), Some(retry)) = (decision, err, self.retry.as_ref()) {
+            let result = publish_retry(&self.channel, retry, delivery, err).await;
+            match result.as_ref() {
+                Ok(target) => {
+                    info!(target: "app", "settle - message scheduled for retry, target = {:?}", target);
+                }
+                Err(retry_err) => {
+                    error!(target: "app", "settle - cannot schedule message for retry, requeuing it. Err = {:?}", retry_err);
+                }
+            }
+            decision = republished_decision(&result);
+        }
+        // move rejected messages to the dead-letter queue, so they can be inspected and replayed
+        if let (AckDecision::Reject, Some(err), Some(dead_letter)) = (decision, err, self.dead_letter.as_ref()) {
+            let result = publish_dead_letter(&self.channel, dead_letter, delivery, err).await;
+            if let Err(dead_letter_err) = result.as_ref() {
+                error!(target: "app", "settle - cannot dead-letter message, requeuing it. Err = {:?}", dead_letter_err);
+            }
+            decision = republished_decision(&result);
+        }
+        self.settle_with(delivery, decision).await;
+        decision
+    }
+
+    // the readings discarded from a message stored partially would be lost once the delivery is acked,
+    // so the ones that would have been rejected on their own are moved to the dead-letter queue
+    pub async fn dead_letter_failures(&self, delivery: &Delivery, failures: &[ReadingFailure]) {
+        let rejected: Vec<&ReadingFailure> = failures
+            .iter()
+            .filter(|failure| AckDecision::from(&failure.error) == AckDecision::Reject)
+            .collect();
+        if rejected.is_empty() {
+            return;
+        }
+        let Some(dead_letter) = self.dead_letter.as_ref() else {
+            warn!(target: "app", "dead_letter_failures - {} readings discarded, but dead-lettering is disabled", rejected.len());
+            return;
+        };
+        // the stored readings cannot be rolled back, so the delivery is settled anyway
+        if let Err(dead_letter_err) = publish_failed_readings(&self.channel, dead_letter, delivery, &rejected).await {
+            error!(target: "app", "dead_letter_failures - cannot dead-letter {} discarded readings. Err = {:?}", rejected.len(), dead_letter_err);
+        }
+    }
+
+    // must be called when the delivery is received, so it's never acked by the multiple-ack of a newer delivery
+    pub fn track(&self, delivery: &Delivery) {
+        if let Some(acks) = self.acks.as_ref() {
+            acks.track(delivery.delivery_tag);
+        }
+    }
+
+    // settle the delivery with the given decision.
+    // If acks are batched, successful deliveries are acked later, together with the other deliveries of the batch
+    pub async fn settle_with(&self, delivery: &Delivery, decision: AckDecision) {
+        if let (AckDecision::Ack, Some(acks)) = (decision, self.acks.as_ref()) {
+            acks.complete(delivery.delivery_tag).await;
+            return;
+        }
+        if let Err(settle_err) = settle_delivery(delivery, decision).await {
+            error!(target: "app", "settle_with - cannot settle delivery with decision = {:?}, err = {:?}", decision, settle_err);
+        }
+        if let Some(acks) = self.acks.as_ref() {
+            acks.release(delivery.delivery_tag);
+        }
+    }
+
+    // ack the successful deliveries still waiting for their batch, e.g. before closing the channel
+    pub async fn flush(&self) {
+        if let Some(acks) = self.acks.as_ref() {
+            acks.flush().await;
+        }
+    }
+}
+
+// a delivery republished to another queue (retry or dead-letter) can be acked only if the server confirmed the copy,
+// otherwise it's requeued, so the message is never lost
+fn republished_decision<T>(result: &Result<T, AmqpError>) -> AckDecision {
+    match result {
+        Ok(_) => AckDecision::Ack,
+        Err(_) => AckDecision::Requeue,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::amqp::AckDecision;
+    use crate::amqp::events::confirmation_result;
+    use crate::amqp::retry::RetryTarget;
+    use crate::amqp::settler::republished_decision;
+    use lapin::Confirmation;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    #[test_log::test]
+    fn ok_republished_decision() {
+        let result = confirmation_result(Ok(Confirmation::Ack(None))).map(|_| RetryTarget::Delay {
+            queue: "ks89.retry.1000ms".to_string(),
+            delay_ms: 1000,
+            attempt: 1,
+        });
+        assert_eq!(republished_decision(&result), AckDecision::Ack);
+    }
+
+    #[test]
+    #[test_log::test]
+    fn bad_republished_decision_nack() {
+        // the retry has been nacked by the server, so the original delivery must not be acked
+        let result = confirmation_result(Ok(Confirmation::Nack(None))).map(|_| RetryTarget::Delay {
+            queue: "ks89.retry.1000ms".to_string(),
+            delay_ms: 1000,
+            attempt: 1,
+        });
+        assert_eq!(republished_decision(&result), AckDecision::Requeue);
+    }
+}
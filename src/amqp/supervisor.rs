@@ -0,0 +1,88 @@
+use std::time::Duration;
+
+use tokio::time::sleep;
+use tracing::{info, warn};
+
+use crate::amqp::{AmqpClient, ConnectionStatus};
+use crate::config::Env;
+
+// Backoff used by the supervisor to rebuild the AMQP client
+// when the connection cannot be established or lapin auto-recovery fails.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReconnectPolicy {
+    pub initial_delay_ms: u64,
+    pub max_delay_ms: u64,
+    pub multiplier: u32,
+}
+
+impl ReconnectPolicy {
+    pub fn from_env(env: &Env) -> Self {
+        Self {
+            initial_delay_ms: env.amqp_reconnect_initial_delay_ms,
+            max_delay_ms: env.amqp_reconnect_max_delay_ms,
+            multiplier: 2,
+        }
+    }
+
+    // delay before the `attempt`-th reconnection (starting from 1), capped to `max_delay_ms`
+    pub fn delay_ms(&self, attempt: u32) -> u64 {
+        let factor: u64 = (self.multiplier as u64).saturating_pow(attempt.saturating_sub(1));
+        self.initial_delay_ms.saturating_mul(factor).min(self.max_delay_ms)
+    }
+
+    // "equal jitter": half of the delay is fixed, the other half is random,
+    // so many consumers don't reconnect to the broker at the same time
+    pub fn delay_with_jitter(&self, attempt: u32, random: u64) -> Duration {
+        let delay_ms: u64 = self.delay_ms(attempt);
+        let half: u64 = delay_ms / 2;
+        Duration::from_millis(half + random % (delay_ms - half + 1))
+    }
+}
+
+// tear the client down and rebuild connection, channel, queue and consumer,
+// retrying with backoff and jitter until the broker is reachable again
+pub async fn reconnect(amqp_client: &mut AmqpClient, policy: &ReconnectPolicy) {
+    let mut attempt: u32 = 0;
+    loop {
+        attempt = attempt.saturating_add(1);
+        amqp_client.teardown().await;
+        amqp_client.set_status(ConnectionStatus::Reconnecting { attempt });
+        info!(target: "app", "reconnect - attempt {} to rebuild the AMQP client...", attempt);
+        match amqp_client.connect(true).await {
+            Ok(_) => {
+                info!(target: "app", "reconnect - AMQP client rebuilt after {} attempts", attempt);
+                return;
+            }
+            Err(err) => {
+                let delay: Duration = policy.delay_with_jitter(attempt, rand::random::<u64>());
+                warn!(target: "app", "reconnect - attempt {} failed, retrying in {:?}. Err = {:?}", attempt, delay, err);
+                sleep(delay).await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::amqp::supervisor::ReconnectPolicy;
+    use pretty_assertions::assert_eq;
+    use std::time::Duration;
+
+    #[test]
+    #[test_log::test]
+    fn ok_delay_with_jitter() {
+        let policy = ReconnectPolicy {
+            initial_delay_ms: 1000,
+            max_delay_ms: 30000,
+            multiplier: 2,
+        };
+        assert_eq!(policy.delay_ms(1), 1000);
+        assert_eq!(policy.delay_ms(3), 4000);
+        assert_eq!(policy.delay_ms(10), 30000);
+        for random in [0, 1, 999, 12345, u64::MAX] {
+            let delay = policy.delay_with_jitter(3, random);
+            assert!(delay >= Duration::from_millis(2000));
+            assert!(delay <= Duration::from_millis(4000));
+        }
+    }
+}
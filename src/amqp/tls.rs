@@ -0,0 +1,182 @@
+use std::fs;
+
+use lapin::tcp::{AsyncTcpStream, OwnedIdentity, OwnedTLSConfig};
+use lapin::uri::AMQPUri;
+use lapin::{Connection, ConnectionProperties};
+use rustls_pki_types::pem::PemObject;
+use rustls_pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use tracing::info;
+
+use crate::config::Env;
+use crate::errors::amqp_error::AmqpError;
+
+// TLS material used to connect to the AMQP server with an `amqps://` URI:
+// - a custom CA bundle to verify the server certificate (added to the system roots, not replacing them)
+// - a client certificate and key for mutual TLS
+// - a server name used to verify the server certificate (SNI), if different from the URI host
+#[derive(Debug, Clone, PartialEq)]
+pub struct TlsConfig {
+    pub config: OwnedTLSConfig,
+    pub server_name: Option<String>,
+}
+
+impl TlsConfig {
+    // TLS is customized only if at least one of the `amqp_tls_*` env vars is defined
+    pub fn from_env(env: &Env) -> Result<Option<Self>, AmqpError> {
+        Self::load(
+            &env.amqp_uri,
+            non_empty(&env.amqp_tls_ca_file),
+            non_empty(&env.amqp_tls_client_cert_file),
+            non_empty(&env.amqp_tls_client_key_file),
+            non_empty(&env.amqp_tls_server_name),
+        )
+    }
+
+    // read and validate the TLS material, so invalid files are reported at startup
+    // and not as handshake errors on every connection attempt
+    pub fn load(
+        amqp_uri: &str,
+        ca_file: Option<&str>,
+        client_cert_file: Option<&str>,
+        client_key_file: Option<&str>,
+        server_name: Option<&str>,
+    ) -> Result<Option<Self>, AmqpError> {
+        if ca_file.is_none() && client_cert_file.is_none() && client_key_file.is_none() && server_name.is_none() {
+            return Ok(None);
+        }
+        if !amqp_uri.starts_with("amqps://") {
+            return Err(AmqpError::InvalidTls(String::from(
+                "TLS settings require an amqps:// AMQP URI",
+            )));
+        }
+        let cert_chain: Option<String> = match ca_file {
+            Some(ca_file) => {
+                info!(target: "app", "TlsConfig - loading CA bundle from file = {}", ca_file);
+                let pem: Vec<u8> = read_file(ca_file)?;
+                parse_certificates(&pem, ca_file)?;
+                Some(
+                    String::from_utf8(pem)
+                        .map_err(|_| AmqpError::InvalidTls(format!("CA bundle {} is not a valid PEM file", ca_file)))?,
+                )
+            }
+            None => None,
+        };
+        let identity: Option<OwnedIdentity> = match (client_cert_file, client_key_file) {
+            (Some(client_cert_file), Some(client_key_file)) => {
+                info!(target: "app", "TlsConfig - loading client certificate = {} and key = {}", client_cert_file, client_key_file);
+                let pem: Vec<u8> = read_file(client_cert_file)?;
+                parse_certificates(&pem, client_cert_file)?;
+                let key: Vec<u8> = read_file(client_key_file)?;
+                PrivateKeyDer::from_pem_slice(&key).map_err(|err| {
+                    AmqpError::InvalidTls(format!("invalid private key in {}: {}", client_key_file, err))
+                })?;
+                Some(OwnedIdentity::PKCS8 { pem, key })
+            }
+            (None, None) => None,
+            _ => {
+                return Err(AmqpError::InvalidTls(String::from(
+                    "client certificate and key must be defined together",
+                )));
+            }
+        };
+        if let Some(server_name) = server_name {
+            ServerName::try_from(server_name)
+                .map_err(|err| AmqpError::InvalidTls(format!("invalid server name {}: {}", server_name, err)))?;
+        }
+        Ok(Some(Self {
+            config: OwnedTLSConfig { identity, cert_chain },
+            server_name: server_name.map(String::from),
+        }))
+    }
+}
+
+// connect using the TLS material. If the server name is overridden, it's used instead of
+// the URI host to verify the server certificate, while the TCP connection still goes to the URI host
+pub async fn connect_tls(
+    amqp_uri: &str,
+    properties: ConnectionProperties,
+    tls: &TlsConfig,
+) -> Result<Connection, lapin::Error> {
+    let runtime = lapin::runtime::default_runtime()?;
+    let config: OwnedTLSConfig = tls.config.clone();
+    match tls.server_name.clone() {
+        None => Connection::connect_with_config(amqp_uri, properties, config, runtime).await,
+        Some(server_name) => {
+            let uri: AMQPUri = amqp_uri.parse().map_err(std::io::Error::other)?;
+            Connection::connector(
+                uri,
+                runtime,
+                async move |uri, runtime| {
+                    let addrs = runtime.to_socket_addrs((uri.authority.host.clone(), uri.authority.port));
+                    let stream = AsyncTcpStream::connect(&runtime, addrs).await?;
+                    Ok(stream.into_tls(&server_name, config.as_ref()).await?)
+                },
+                properties,
+            )
+            .await
+        }
+    }
+}
+
+fn non_empty(value: &Option<String>) -> Option<&str> {
+    value.as_deref().filter(|value| !value.is_empty())
+}
+
+fn read_file(path: &str) -> Result<Vec<u8>, AmqpError> {
+    fs::read(path).map_err(|err| AmqpError::InvalidTls(format!("cannot read {}: {}", path, err)))
+}
+
+// check that the PEM content contains at least one valid certificate
+fn parse_certificates(pem: &[u8], path: &str) -> Result<(), AmqpError> {
+    let certificates: Vec<CertificateDer> = CertificateDer::pem_slice_iter(pem)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|err| AmqpError::InvalidTls(format!("invalid certificate in {}: {}", path, err)))?;
+    if certificates.is_empty() {
+        return Err(AmqpError::InvalidTls(format!("no certificates found in {}", path)));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::amqp::tls::TlsConfig;
+    use crate::errors::amqp_error::AmqpError;
+    use pretty_assertions::assert_eq;
+    use std::fs;
+
+    #[test]
+    #[test_log::test]
+    fn bad_tls_config() {
+        let amqps_uri = "amqps://localhost:5671";
+        // TLS is not customized
+        assert!(TlsConfig::load(amqps_uri, None, None, None, None).unwrap().is_none());
+        // TLS settings with a plain URI
+        assert!(matches!(
+            TlsConfig::load("amqp://localhost:5672", None, None, None, Some("rabbitmq.local")),
+            Err(AmqpError::InvalidTls(_))
+        ));
+        // missing files
+        assert!(matches!(
+            TlsConfig::load(amqps_uri, Some("./missing-ca.pem"), None, None, None),
+            Err(AmqpError::InvalidTls(_))
+        ));
+        // client certificate without key
+        assert!(matches!(
+            TlsConfig::load(amqps_uri, None, Some("./client-cert.pem"), None, None),
+            Err(AmqpError::InvalidTls(_))
+        ));
+        // invalid PEM content
+        let not_a_pem = std::env::temp_dir().join("consumer-bad-tls-config.pem");
+        fs::write(&not_a_pem, "not a certificate").unwrap();
+        assert!(matches!(
+            TlsConfig::load(amqps_uri, not_a_pem.to_str(), None, None, None),
+            Err(AmqpError::InvalidTls(_))
+        ));
+        fs::remove_file(&not_a_pem).unwrap();
+        // only the server name is overridden
+        let tls = TlsConfig::load(amqps_uri, None, None, None, Some("rabbitmq.local"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(tls.server_name, Some(String::from("rabbitmq.local")));
+    }
+}
@@ -0,0 +1,332 @@
+use std::fs;
+
+use lapin::options::{ExchangeDeclareOptions, QueueBindOptions, QueueDeclareOptions};
+use lapin::types::{AMQPValue, FieldArray, FieldTable};
+use lapin::{Channel, ExchangeKind, Queue};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use tracing::{debug, info};
+
+use crate::errors::amqp_error::AmqpError;
+
+// AMQP topology (exchanges, queues and bindings) declared at connect time.
+// It's loaded from a JSON file, for example:
+// {
+//   "exchanges": [{ "name": "sensors", "kind": "topic", "durable": true }],
+//   "queues": [{ "name": "ks89", "durable": true, "queueType": "quorum", "arguments": { "x-max-length": 100000 } }],
+//   "bindings": [{ "queue": "ks89", "exchange": "sensors", "routingKey": "sensors.*.temperature" }]
+// }
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Topology {
+    #[serde(default)]
+    pub exchanges: Vec<ExchangeConfig>,
+    #[serde(default)]
+    pub queues: Vec<QueueConfig>,
+    #[serde(default)]
+    pub bindings: Vec<BindingConfig>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ExchangeConfig {
+    pub name: String,
+    #[serde(default)]
+    pub kind: ExchangeType,
+    #[serde(default)]
+    pub durable: bool,
+    #[serde(default)]
+    pub auto_delete: bool,
+    #[serde(default)]
+    pub internal: bool,
+    #[serde(default)]
+    pub arguments: Map<String, Value>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExchangeType {
+    #[default]
+    Direct,
+    Topic,
+    Fanout,
+    Headers,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct QueueConfig {
+    pub name: String,
+    #[serde(default)]
+    pub queue_type: QueueType,
+    #[serde(default)]
+    pub durable: bool,
+    #[serde(default)]
+    pub exclusive: bool,
+    #[serde(default)]
+    pub auto_delete: bool,
+    #[serde(default)]
+    pub arguments: Map<String, Value>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum QueueType {
+    #[default]
+    Classic,
+    Quorum,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BindingConfig {
+    pub queue: String,
+    pub exchange: String,
+    #[serde(default)]
+    pub routing_key: String,
+    #[serde(default)]
+    pub arguments: Map<String, Value>,
+}
+
+impl Topology {
+    pub fn load(path: &str) -> Result<Self, AmqpError> {
+        info!(target: "app", "Topology - loading AMQP topology from file = {}", path);
+        let content: String = fs::read_to_string(path)
+            .map_err(|err| AmqpError::InvalidTopology(format!("cannot read topology file {}: {}", path, err)))?;
+        Self::parse(content.as_str())
+    }
+
+    pub fn parse(content: &str) -> Result<Self, AmqpError> {
+        let topology: Topology = serde_json::from_str(content)
+            .map_err(|err| AmqpError::InvalidTopology(format!("cannot parse topology: {}", err)))?;
+        topology.validate()?;
+        Ok(topology)
+    }
+
+    fn validate(&self) -> Result<(), AmqpError> {
+        for queue in &self.queues {
+            if queue.queue_type == QueueType::Quorum && (!queue.durable || queue.exclusive || queue.auto_delete) {
+                return Err(AmqpError::InvalidTopology(format!(
+                    "quorum queue {} must be durable, not exclusive and not auto-delete",
+                    queue.name
+                )));
+            }
+        }
+        for binding in &self.bindings {
+            if !self.queues.iter().any(|queue| queue.name == binding.queue) {
+                return Err(AmqpError::InvalidTopology(format!(
+                    "binding refers to undeclared queue {}",
+                    binding.queue
+                )));
+            }
+            // predefined exchanges (e.g. `amq.topic`) don't need to be declared
+            if !binding.exchange.starts_with("amq.")
+                && !self.exchanges.iter().any(|exchange| exchange.name == binding.exchange)
+            {
+                return Err(AmqpError::InvalidTopology(format!(
+                    "binding refers to undeclared exchange {}",
+                    binding.exchange
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn queue(&self, name: &str) -> Option<&QueueConfig> {
+        self.queues.iter().find(|queue| queue.name == name)
+    }
+}
+
+impl QueueConfig {
+    // queue declared with default options, as the consumer did before topologies were configurable
+    pub fn with_defaults(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            queue_type: QueueType::Classic,
+            durable: false,
+            exclusive: false,
+            auto_delete: false,
+            arguments: Map::new(),
+        }
+    }
+
+    fn field_table(&self) -> FieldTable {
+        let mut arguments: FieldTable = json_to_field_table(&self.arguments);
+        if self.queue_type == QueueType::Quorum {
+            arguments.insert("x-queue-type".into(), AMQPValue::LongString("quorum".into()));
+        }
+        arguments
+    }
+}
+
+impl From<&ExchangeType> for ExchangeKind {
+    fn from(exchange_type: &ExchangeType) -> Self {
+        match exchange_type {
+            ExchangeType::Direct => ExchangeKind::Direct,
+            ExchangeType::Topic => ExchangeKind::Topic,
+            ExchangeType::Fanout => ExchangeKind::Fanout,
+            ExchangeType::Headers => ExchangeKind::Headers,
+        }
+    }
+}
+
+pub async fn declare_exchanges(channel: &Channel, topology: &Topology) -> Result<(), lapin::Error> {
+    for exchange in &topology.exchanges {
+        debug!(target: "app", "declare_exchanges - declaring exchange = {:?}", exchange);
+        channel
+            .exchange_declare(
+                exchange.name.clone().into(),
+                ExchangeKind::from(&exchange.kind),
+                ExchangeDeclareOptions {
+                    durable: exchange.durable,
+                    auto_delete: exchange.auto_delete,
+                    internal: exchange.internal,
+                    ..ExchangeDeclareOptions::default()
+                },
+                json_to_field_table(&exchange.arguments),
+            )
+            .await?;
+    }
+    Ok(())
+}
+
+pub async fn declare_queue(channel: &Channel, queue: &QueueConfig) -> Result<Queue, lapin::Error> {
+    debug!(target: "app", "declare_queue - declaring queue = {:?}", queue);
+    channel
+        .queue_declare(
+            queue.name.clone().into(),
+            QueueDeclareOptions {
+                durable: queue.durable,
+                exclusive: queue.exclusive,
+                auto_delete: queue.auto_delete,
+                ..QueueDeclareOptions::default()
+            },
+            queue.field_table(),
+        )
+        .await
+}
+
+pub async fn declare_bindings(channel: &Channel, topology: &Topology) -> Result<(), lapin::Error> {
+    for binding in &topology.bindings {
+        debug!(target: "app", "declare_bindings - binding = {:?}", binding);
+        channel
+            .queue_bind(
+                binding.queue.clone().into(),
+                binding.exchange.clone().into(),
+                binding.routing_key.clone().into(),
+                QueueBindOptions::default(),
+                json_to_field_table(&binding.arguments),
+            )
+            .await?;
+    }
+    Ok(())
+}
+
+// convert JSON x-arguments to an AMQP FieldTable
+pub fn json_to_field_table(arguments: &Map<String, Value>) -> FieldTable {
+    let mut table = FieldTable::default();
+    for (key, value) in arguments {
+        table.insert(key.clone().into(), json_to_amqp_value(value));
+    }
+    table
+}
+
+fn json_to_amqp_value(value: &Value) -> AMQPValue {
+    match value {
+        Value::Null => AMQPValue::Void,
+        Value::Bool(value) => AMQPValue::Boolean(*value),
+        Value::Number(number) => match number.as_i64() {
+            Some(value) => AMQPValue::LongLongInt(value),
+            None => AMQPValue::Double(number.as_f64().unwrap_or_default()),
+        },
+        Value::String(value) => AMQPValue::LongString(value.as_str().into()),
+        Value::Array(values) => {
+            let mut array = FieldArray::default();
+            for value in values {
+                array.push(json_to_amqp_value(value));
+            }
+            AMQPValue::FieldArray(array)
+        }
+        Value::Object(values) => AMQPValue::FieldTable(json_to_field_table(values)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::amqp::topology::{ExchangeType, QueueType, Topology, json_to_field_table};
+    use lapin::types::{AMQPValue, ShortString};
+    use pretty_assertions::assert_eq;
+    use serde_json::json;
+
+    #[test]
+    #[test_log::test]
+    fn ok_parse_topology() {
+        let content = json!({
+            "exchanges": [{ "name": "sensors", "kind": "topic", "durable": true }],
+            "queues": [{
+                "name": "ks89",
+                "durable": true,
+                "queueType": "quorum",
+                "arguments": { "x-max-length": 100000 }
+            }],
+            "bindings": [{ "queue": "ks89", "exchange": "sensors", "routingKey": "sensors.*.temperature" }]
+        })
+        .to_string();
+        let topology = Topology::parse(content.as_str()).unwrap();
+        assert_eq!(topology.exchanges[0].kind, ExchangeType::Topic);
+        assert_eq!(topology.queue("ks89").unwrap().queue_type, QueueType::Quorum);
+        assert_eq!(topology.bindings[0].routing_key, "sensors.*.temperature");
+
+        let arguments = topology.queue("ks89").unwrap().field_table();
+        assert_eq!(
+            arguments.inner().get(&ShortString::from("x-queue-type")),
+            Some(&AMQPValue::LongString("quorum".into()))
+        );
+        assert_eq!(
+            arguments.inner().get(&ShortString::from("x-max-length")),
+            Some(&AMQPValue::LongLongInt(100000))
+        );
+    }
+
+    #[test]
+    #[test_log::test]
+    fn bad_topology() {
+        // quorum queues must be durable
+        let content = json!({ "queues": [{ "name": "ks89", "queueType": "quorum" }] }).to_string();
+        assert!(Topology::parse(content.as_str()).is_err());
+        // bindings must refer to declared exchanges and queues
+        let content = json!({
+            "queues": [{ "name": "ks89" }],
+            "bindings": [{ "queue": "ks89", "exchange": "sensors", "routingKey": "#" }]
+        })
+        .to_string();
+        assert!(Topology::parse(content.as_str()).is_err());
+        // predefined exchanges can be used without declaring them
+        let content = json!({
+            "queues": [{ "name": "ks89" }],
+            "bindings": [{ "queue": "ks89", "exchange": "amq.topic", "routingKey": "sensors.#" }]
+        })
+        .to_string();
+        assert!(Topology::parse(content.as_str()).is_ok());
+    }
+
+    #[test]
+    #[test_log::test]
+    fn ok_json_to_field_table() {
+        let arguments =
+            json!({ "x-message-ttl": 60000, "x-overflow": "reject-publish", "x-single-active-consumer": true });
+        let table = json_to_field_table(arguments.as_object().unwrap());
+        assert_eq!(
+            table.inner().get(&ShortString::from("x-message-ttl")),
+            Some(&AMQPValue::LongLongInt(60000))
+        );
+        assert_eq!(
+            table.inner().get(&ShortString::from("x-overflow")),
+            Some(&AMQPValue::LongString("reject-publish".into()))
+        );
+        assert_eq!(
+            table.inner().get(&ShortString::from("x-single-active-consumer")),
+            Some(&AMQPValue::Boolean(true))
+        );
+    }
+}
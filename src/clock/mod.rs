@@ -0,0 +1,141 @@
+use mongodb::bson::DateTime;
+use serde_json::Value;
+use tracing::warn;
+
+use crate::config::Env;
+
+// Decides the observation time of a reading, using the timestamp sent by the device
+// (e.g. readings buffered during a Wi-Fi outage) and falling back to the receive time
+// when the device clock is clearly wrong.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ClockPolicy {
+    // max difference allowed for timestamps in the future (device clock ahead of the server)
+    pub max_skew_ms: i64,
+    // max age of buffered readings, older timestamps are considered wrong (e.g. RTC not set after a reboot)
+    pub max_age_ms: i64,
+}
+
+impl ClockPolicy {
+    pub fn from_env(env: &Env) -> Self {
+        Self {
+            max_skew_ms: secs_to_millis(env.max_clock_skew_secs),
+            max_age_ms: secs_to_millis(env.max_observation_age_secs),
+        }
+    }
+
+    // observation time of a reading received at `received_at`
+    pub fn observed_at(&self, timestamp: Option<DateTime>, received_at: DateTime) -> DateTime {
+        self.device_timestamp(timestamp, received_at).unwrap_or(received_at)
+    }
+
+    // timestamp sent by the device, if any and if it can be trusted.
+    // Otherwise the reading is observed at the receive time, that is later than the real observation time
+    pub fn device_timestamp(&self, timestamp: Option<DateTime>, received_at: DateTime) -> Option<DateTime> {
+        let timestamp: DateTime = timestamp?;
+        // device timestamps can be anything, so the offset must not overflow
+        let offset_ms: i64 = timestamp
+            .timestamp_millis()
+            .saturating_sub(received_at.timestamp_millis());
+        if offset_ms > self.max_skew_ms || offset_ms.saturating_neg() > self.max_age_ms {
+            warn!(target: "app", "device_timestamp - device timestamp = {} is {}ms away from receive time = {}, using receive time",
+                timestamp, offset_ms, received_at);
+            return None;
+        }
+        Some(timestamp)
+    }
+}
+
+// huge values (e.g. to disable a check) are clamped instead of overflowing into negative limits
+fn secs_to_millis(secs: u64) -> i64 {
+    i64::try_from(secs.saturating_mul(1000)).unwrap_or(i64::MAX)
+}
+
+// parse a device timestamp, sent as milliseconds since the Unix epoch or as RFC 3339 string
+pub fn parse_timestamp(timestamp: &Value) -> Option<DateTime> {
+    match timestamp {
+        Value::Number(millis) => millis.as_i64().map(DateTime::from_millis),
+        Value::String(rfc3339) => DateTime::parse_rfc3339_str(rfc3339).ok(),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::clock::{ClockPolicy, parse_timestamp, secs_to_millis};
+    use mongodb::bson::DateTime;
+    use pretty_assertions::assert_eq;
+    use serde_json::json;
+
+    #[test]
+    #[test_log::test]
+    fn ok_observed_at() {
+        let policy = ClockPolicy {
+            max_skew_ms: 60_000,
+            max_age_ms: 3_600_000,
+        };
+        let received_at = DateTime::from_millis(1_700_000_000_000);
+        assert_eq!(policy.observed_at(None, received_at), received_at);
+        // buffered reading
+        let timestamp = DateTime::from_millis(1_700_000_000_000 - 600_000);
+        assert_eq!(policy.observed_at(Some(timestamp), received_at), timestamp);
+        // small skew in the future
+        let timestamp = DateTime::from_millis(1_700_000_000_000 + 30_000);
+        assert_eq!(policy.observed_at(Some(timestamp), received_at), timestamp);
+        // device clock too far in the future or in the past
+        let timestamp = DateTime::from_millis(1_700_000_000_000 + 120_000);
+        assert_eq!(policy.observed_at(Some(timestamp), received_at), received_at);
+        let timestamp = DateTime::from_millis(0);
+        assert_eq!(policy.observed_at(Some(timestamp), received_at), received_at);
+    }
+
+    #[test]
+    #[test_log::test]
+    fn ok_device_timestamp() {
+        let policy = ClockPolicy {
+            max_skew_ms: 60_000,
+            max_age_ms: 3_600_000,
+        };
+        let received_at = DateTime::from_millis(1_700_000_000_000);
+        assert_eq!(policy.device_timestamp(None, received_at), None);
+        let timestamp = DateTime::from_millis(1_700_000_000_000 - 600_000);
+        assert_eq!(policy.device_timestamp(Some(timestamp), received_at), Some(timestamp));
+        // clamped to the receive time by `observed_at`
+        let timestamp = DateTime::from_millis(0);
+        assert_eq!(policy.device_timestamp(Some(timestamp), received_at), None);
+    }
+
+    #[test]
+    #[test_log::test]
+    fn ok_observed_at_extreme_values() {
+        let policy = ClockPolicy {
+            max_skew_ms: secs_to_millis(u64::MAX),
+            max_age_ms: secs_to_millis(u64::MAX),
+        };
+        assert_eq!(policy.max_skew_ms, i64::MAX);
+        let received_at = DateTime::from_millis(1_700_000_000_000);
+        let timestamp = DateTime::from_millis(i64::MIN);
+        assert_eq!(policy.observed_at(Some(timestamp), received_at), timestamp);
+        let policy = ClockPolicy {
+            max_skew_ms: 60_000,
+            max_age_ms: 3_600_000,
+        };
+        assert_eq!(policy.observed_at(Some(timestamp), received_at), received_at);
+        let timestamp = DateTime::from_millis(i64::MAX);
+        assert_eq!(policy.observed_at(Some(timestamp), received_at), received_at);
+    }
+
+    #[test]
+    #[test_log::test]
+    fn ok_parse_timestamp() {
+        assert_eq!(
+            parse_timestamp(&json!(1_700_000_000_000_i64)),
+            Some(DateTime::from_millis(1_700_000_000_000))
+        );
+        assert_eq!(
+            parse_timestamp(&json!("2023-11-14T22:13:20Z")),
+            Some(DateTime::from_millis(1_700_000_000_000))
+        );
+        assert_eq!(parse_timestamp(&json!("yesterday")), None);
+        assert_eq!(parse_timestamp(&json!(true)), None);
+    }
+}
@@ -10,9 +10,104 @@ use tracing_subscriber::fmt::writer::MakeWriterExt;
 pub struct Env {
     pub mongo_uri: String,
     pub mongo_db_name: String,
+    #[serde(default = "default_mongo_history_granularity")]
+    pub mongo_history_granularity: String,
     pub amqp_uri: String,
     pub amqp_queue_name: String,
     pub amqp_consumer_tag: String,
+    pub amqp_dead_letter_exchange: Option<String>,
+    pub amqp_dead_letter_queue: Option<String>,
+    #[serde(default = "default_amqp_retry_max_attempts")]
+    pub amqp_retry_max_attempts: u32,
+    #[serde(default = "default_amqp_retry_initial_delay_ms")]
+    pub amqp_retry_initial_delay_ms: u64,
+    #[serde(default = "default_amqp_retry_multiplier")]
+    pub amqp_retry_multiplier: u32,
+    #[serde(default = "default_amqp_retry_max_delay_ms")]
+    pub amqp_retry_max_delay_ms: u64,
+    pub amqp_retry_parking_queue: Option<String>,
+    #[serde(default = "default_amqp_prefetch_count")]
+    pub amqp_prefetch_count: u16,
+    #[serde(default = "default_consumer_workers")]
+    pub consumer_workers: usize,
+    pub amqp_topology_file: Option<String>,
+    #[serde(default = "default_amqp_reconnect_initial_delay_ms")]
+    pub amqp_reconnect_initial_delay_ms: u64,
+    #[serde(default = "default_amqp_reconnect_max_delay_ms")]
+    pub amqp_reconnect_max_delay_ms: u64,
+    pub amqp_events_exchange: Option<String>,
+    pub features_file: Option<String>,
+    pub amqp_tls_ca_file: Option<String>,
+    pub amqp_tls_client_cert_file: Option<String>,
+    pub amqp_tls_client_key_file: Option<String>,
+    pub amqp_tls_server_name: Option<String>,
+    #[serde(default = "default_shutdown_timeout_secs")]
+    pub shutdown_timeout_secs: u64,
+    #[serde(default = "default_max_clock_skew_secs")]
+    pub max_clock_skew_secs: u64,
+    #[serde(default = "default_max_observation_age_secs")]
+    pub max_observation_age_secs: u64,
+    #[serde(default = "default_rollup_timezone")]
+    pub rollup_timezone: String,
+    #[serde(default = "default_pending_backfill_interval_secs")]
+    pub pending_backfill_interval_secs: u64,
+    #[serde(default = "default_bulk_write_max_ops")]
+    pub bulk_write_max_ops: usize,
+    #[serde(default = "default_bulk_write_window_ms")]
+    pub bulk_write_window_ms: u64,
+}
+
+// sensors send a reading every few minutes
+fn default_mongo_history_granularity() -> String {
+    "minutes".to_string()
+}
+fn default_amqp_retry_max_attempts() -> u32 {
+    5
+}
+fn default_amqp_retry_initial_delay_ms() -> u64 {
+    1000
+}
+fn default_amqp_retry_multiplier() -> u32 {
+    2
+}
+fn default_amqp_retry_max_delay_ms() -> u64 {
+    60000
+}
+fn default_amqp_prefetch_count() -> u16 {
+    10
+}
+fn default_consumer_workers() -> usize {
+    4
+}
+fn default_amqp_reconnect_initial_delay_ms() -> u64 {
+    1000
+}
+fn default_amqp_reconnect_max_delay_ms() -> u64 {
+    30000
+}
+// lower than the default Kubernetes termination grace period (30s)
+fn default_shutdown_timeout_secs() -> u64 {
+    25
+}
+fn default_max_clock_skew_secs() -> u64 {
+    300
+}
+// 7 days
+fn default_max_observation_age_secs() -> u64 {
+    604800
+}
+fn default_rollup_timezone() -> String {
+    "UTC".to_string()
+}
+fn default_pending_backfill_interval_secs() -> u64 {
+    60
+}
+fn default_bulk_write_max_ops() -> usize {
+    100
+}
+// short, because every delivery waits for its batch to be written
+fn default_bulk_write_window_ms() -> u64 {
+    5
 }
 
 pub fn init() -> Env {
@@ -62,10 +157,39 @@ fn print_env(env: &Env) {
     let amqp_uri = env.amqp_uri.clone();
     let amqp_queue_name = env.amqp_queue_name.clone();
     let amqp_consumer_tag = env.amqp_consumer_tag.clone();
+    let amqp_dead_letter_exchange = env.amqp_dead_letter_exchange.clone();
+    let amqp_dead_letter_queue = env.amqp_dead_letter_queue.clone();
+    let amqp_retry_parking_queue = env.amqp_retry_parking_queue.clone();
     info!(target: "app", "env = {:?}", env);
     info!(target: "app", "mongo_uri = {}", mongo_uri);
     info!(target: "app", "mongo_db_name = {}", mongo_db_name);
+    info!(target: "app", "mongo_history_granularity = {}", env.mongo_history_granularity);
     info!(target: "app", "amqp_uri = {}", amqp_uri);
     info!(target: "app", "amqp_queue_name = {}", amqp_queue_name);
     info!(target: "app", "amqp_consumer_tag = {}", amqp_consumer_tag);
+    info!(target: "app", "amqp_dead_letter_exchange = {:?}", amqp_dead_letter_exchange);
+    info!(target: "app", "amqp_dead_letter_queue = {:?}", amqp_dead_letter_queue);
+    info!(target: "app", "amqp_retry_max_attempts = {}", env.amqp_retry_max_attempts);
+    info!(target: "app", "amqp_retry_initial_delay_ms = {}", env.amqp_retry_initial_delay_ms);
+    info!(target: "app", "amqp_retry_multiplier = {}", env.amqp_retry_multiplier);
+    info!(target: "app", "amqp_retry_max_delay_ms = {}", env.amqp_retry_max_delay_ms);
+    info!(target: "app", "amqp_retry_parking_queue = {:?}", amqp_retry_parking_queue);
+    info!(target: "app", "amqp_prefetch_count = {}", env.amqp_prefetch_count);
+    info!(target: "app", "consumer_workers = {}", env.consumer_workers);
+    info!(target: "app", "amqp_topology_file = {:?}", env.amqp_topology_file);
+    info!(target: "app", "amqp_reconnect_initial_delay_ms = {}", env.amqp_reconnect_initial_delay_ms);
+    info!(target: "app", "amqp_reconnect_max_delay_ms = {}", env.amqp_reconnect_max_delay_ms);
+    info!(target: "app", "amqp_events_exchange = {:?}", env.amqp_events_exchange);
+    info!(target: "app", "features_file = {:?}", env.features_file);
+    info!(target: "app", "amqp_tls_ca_file = {:?}", env.amqp_tls_ca_file);
+    info!(target: "app", "amqp_tls_client_cert_file = {:?}", env.amqp_tls_client_cert_file);
+    info!(target: "app", "amqp_tls_client_key_file = {:?}", env.amqp_tls_client_key_file);
+    info!(target: "app", "amqp_tls_server_name = {:?}", env.amqp_tls_server_name);
+    info!(target: "app", "shutdown_timeout_secs = {}", env.shutdown_timeout_secs);
+    info!(target: "app", "max_clock_skew_secs = {}", env.max_clock_skew_secs);
+    info!(target: "app", "max_observation_age_secs = {}", env.max_observation_age_secs);
+    info!(target: "app", "rollup_timezone = {}", env.rollup_timezone);
+    info!(target: "app", "pending_backfill_interval_secs = {}", env.pending_backfill_interval_secs);
+    info!(target: "app", "bulk_write_max_ops = {}", env.bulk_write_max_ops);
+    info!(target: "app", "bulk_write_window_ms = {}", env.bulk_write_window_ms);
 }
@@ -0,0 +1,377 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use futures_lite::StreamExt;
+use mongodb::Database;
+use mongodb::bson::{DateTime, Document, doc};
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::{Instant, timeout_at};
+use tracing::{debug, error, info};
+
+use crate::config::Env;
+use crate::db::sensor::{SENSORS_COLLECTION, SensorWrite, UpdateOutcome, sensor_key, update_sensors};
+use crate::errors::db_error::DbError;
+use crate::models::generic_message::GenericMessage;
+use crate::models::sensor::SensorDocument;
+use crate::models::sensor_value::Measurement;
+
+// Batches the updates of single sensors sent by all workers, writing them with a single bulk write
+// when `max_ops` updates have been collected or `window` has elapsed since the first one.
+// Workers move on as soon as their update has been sent (see `SubmissionNotifier`),
+// so a batch can contain the updates of all unacked deliveries (up to the prefetch count)
+#[derive(Debug, Clone, PartialEq)]
+pub struct BulkWriteConfig {
+    pub max_ops: usize,
+    pub window: Duration,
+}
+
+impl BulkWriteConfig {
+    // bulk writes are enabled only if `bulk_write_max_ops` is greater than 1
+    pub fn from_env(env: &Env) -> Option<Self> {
+        if env.bulk_write_max_ops <= 1 {
+            return None;
+        }
+        Some(Self {
+            max_ops: env.bulk_write_max_ops,
+            window: Duration::from_millis(env.bulk_write_window_ms),
+        })
+    }
+}
+
+// a sensor is identified by its profile, device and feature (see `sensor_key`)
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct SensorKey {
+    pub api_token: String,
+    pub device_uuid: String,
+    pub feature_uuid: String,
+}
+
+impl SensorKey {
+    fn from_sensor(sensor_doc: &SensorDocument) -> Self {
+        Self {
+            api_token: sensor_doc.apiToken.clone(),
+            device_uuid: sensor_doc.deviceUuid.clone(),
+            feature_uuid: sensor_doc.featureUuid.clone(),
+        }
+    }
+
+    fn to_filter(&self) -> Document {
+        sensor_key(
+            self.api_token.as_str(),
+            self.device_uuid.as_str(),
+            self.feature_uuid.as_str(),
+        )
+    }
+}
+
+// update of a sensor waiting to be written, with the channel used to send its result back to the worker
+#[derive(Debug)]
+struct SensorOp {
+    key: SensorKey,
+    measurement: Measurement,
+    observed_at: DateTime,
+    reply: oneshot::Sender<Result<UpdateOutcome, DbError>>,
+}
+
+// handle of the task that writes the batches, cheap to clone, so every worker can own one.
+// The task stops when all handles have been dropped
+#[derive(Debug, Clone)]
+pub struct BulkWriter {
+    sender: mpsc::Sender<SensorOp>,
+}
+
+impl BulkWriter {
+    pub fn spawn(db: Database, config: BulkWriteConfig) -> Self {
+        info!(target: "app", "BulkWriter - starting with max_ops = {} and window = {:?}", config.max_ops, config.window);
+        let (sender, receiver) = mpsc::channel::<SensorOp>(config.max_ops.max(1));
+        tokio::spawn(run(db, config, receiver));
+        Self { sender }
+    }
+
+    // same result of `update_sensor`, but the update is written in bulk with the updates of other deliveries
+    pub async fn update_sensor(
+        &self,
+        generic_msg: &GenericMessage,
+        measurement: &Measurement,
+        observed_at: DateTime,
+    ) -> Result<UpdateOutcome, DbError> {
+        let key = SensorKey {
+            api_token: generic_msg.api_token.clone(),
+            device_uuid: generic_msg.device_uuid.clone(),
+            feature_uuid: generic_msg.feature_uuid.clone(),
+        };
+        let mut results = self.send(vec![(key, measurement.clone(), observed_at)]).await;
+        results.pop().unwrap_or_else(|| Err(stopped_error()))
+    }
+
+    // same results of `update_sensors`, but the updates are written in bulk with the updates of other deliveries
+    pub async fn update_sensors(&self, writes: &[SensorWrite]) -> Vec<Result<UpdateOutcome, DbError>> {
+        let updates: Vec<(SensorKey, Measurement, DateTime)> = writes
+            .iter()
+            .map(|write| {
+                (
+                    SensorKey::from_sensor(&write.sensor_doc),
+                    write.measurement.clone(),
+                    write.observed_at,
+                )
+            })
+            .collect();
+        self.send(updates).await
+    }
+
+    // all updates of a delivery are sent before notifying its worker, so they are written in the order of the deliveries
+    async fn send(&self, updates: Vec<(SensorKey, Measurement, DateTime)>) -> Vec<Result<UpdateOutcome, DbError>> {
+        let mut replies: Vec<Option<oneshot::Receiver<Result<UpdateOutcome, DbError>>>> =
+            Vec::with_capacity(updates.len());
+        for (key, measurement, observed_at) in updates {
+            let (reply, result) = oneshot::channel();
+            let op = SensorOp {
+                key,
+                measurement,
+                observed_at,
+                reply,
+            };
+            if self.sender.send(op).await.is_err() {
+                error!(target: "app", "BulkWriter - cannot send update, because the writer is not running anymore");
+                replies.push(None);
+            } else {
+                replies.push(Some(result));
+            }
+        }
+        // updates are written in the order they have been sent, so the worker can process the next delivery
+        let _ = SUBMITTED.try_with(SubmissionNotifier::notify);
+        let mut results: Vec<Result<UpdateOutcome, DbError>> = Vec::with_capacity(replies.len());
+        for reply in replies {
+            results.push(match reply {
+                Some(result) => result.await.unwrap_or_else(|_| Err(stopped_error())),
+                None => Err(stopped_error()),
+            });
+        }
+        results
+    }
+}
+
+tokio::task_local! {
+    static SUBMITTED: SubmissionNotifier;
+}
+
+// Notifies the worker of a delivery as soon as its update has been sent to a writer,
+// so the worker can move on while the delivery waits for the result of the bulk write in its own task
+#[derive(Debug)]
+pub struct SubmissionNotifier {
+    sender: Mutex<Option<oneshot::Sender<()>>>,
+}
+
+impl SubmissionNotifier {
+    // the receiver is closed without notification if the delivery is processed without sending updates
+    pub fn new() -> (Self, oneshot::Receiver<()>) {
+        let (sender, receiver) = oneshot::channel();
+        let notifier = Self {
+            sender: Mutex::new(Some(sender)),
+        };
+        (notifier, receiver)
+    }
+
+    // process a delivery, notifying the first update sent to a writer
+    pub async fn scope<F: Future>(self, future: F) -> F::Output {
+        SUBMITTED.scope(self, future).await
+    }
+
+    fn notify(&self) {
+        if let Some(sender) = self.sender.lock().unwrap().take() {
+            // the worker could have been stopped in the meantime
+            let _ = sender.send(());
+        }
+    }
+}
+
+async fn run(db: Database, config: BulkWriteConfig, mut receiver: mpsc::Receiver<SensorOp>) {
+    while let Some(op) = receiver.recv().await {
+        let deadline: Instant = Instant::now() + config.window;
+        let mut ops: Vec<SensorOp> = vec![op];
+        while ops.len() < config.max_ops {
+            match timeout_at(deadline, receiver.recv()).await {
+                Ok(Some(op)) => ops.push(op),
+                // window elapsed or all handles dropped
+                _ => break,
+            }
+        }
+        write_batch(&db, ops).await;
+    }
+    debug!(target: "app", "BulkWriter - stopped");
+}
+
+// write a batch and send the result of every update back to its worker
+async fn write_batch(db: &Database, ops: Vec<SensorOp>) {
+    info!(target: "app", "write_batch - Called with {} updates", ops.len());
+    let latest: Vec<usize> = collapse(&ops);
+    let results: Vec<Result<UpdateOutcome, DbError>> = match write_latest(db, &ops, &latest).await {
+        Ok(results) => results,
+        Err(err) => {
+            error!(target: "app", "write_batch - cannot write batch, err = {:?}", err);
+            latest.iter().map(|_| Err(err.clone())).collect()
+        }
+    };
+    let mut results: HashMap<usize, Result<UpdateOutcome, DbError>> = latest.into_iter().zip(results).collect();
+    // updates replaced by a newer update of the same sensor share its failure (e.g. the sensor has been deleted)
+    let failures: HashMap<SensorKey, DbError> = results
+        .iter()
+        .filter_map(|(index, result)| result.as_ref().err().map(|err| (ops[*index].key.clone(), err.clone())))
+        .collect();
+    for (index, op) in ops.into_iter().enumerate() {
+        let result = results.remove(&index).unwrap_or_else(|| match failures.get(&op.key) {
+            Some(err) => Err(err.clone()),
+            None => Ok(UpdateOutcome::Stale),
+        });
+        // the worker could have been stopped in the meantime
+        let _ = op.reply.send(result);
+    }
+}
+
+// updates written to the db (only the latest of every sensor), returning their results in the same order of `latest`
+async fn write_latest(
+    db: &Database,
+    ops: &[SensorOp],
+    latest: &[usize],
+) -> Result<Vec<Result<UpdateOutcome, DbError>>, DbError> {
+    let keys: Vec<&SensorKey> = latest.iter().map(|index| &ops[*index].key).collect();
+    let mut sensor_docs: HashMap<SensorKey, SensorDocument> = find_sensors_by_key(db, &keys).await?;
+    let mut writes: Vec<SensorWrite> = Vec::with_capacity(latest.len());
+    let mut positions: Vec<Option<usize>> = Vec::with_capacity(latest.len());
+    for index in latest {
+        let op: &SensorOp = &ops[*index];
+        match sensor_docs.remove(&op.key) {
+            Some(sensor_doc) => {
+                positions.push(Some(writes.len()));
+                writes.push(SensorWrite {
+                    sensor_doc,
+                    measurement: op.measurement.clone(),
+                    observed_at: op.observed_at,
+                });
+            }
+            None => positions.push(None),
+        }
+    }
+    let mut outcomes: Vec<Option<Result<UpdateOutcome, DbError>>> = if writes.is_empty() {
+        Vec::new()
+    } else {
+        update_sensors(db, &writes).await?.into_iter().map(Some).collect()
+    };
+    Ok(latest
+        .iter()
+        .zip(positions)
+        .map(
+            |(index, position)| match position.and_then(|position| outcomes[position].take()) {
+                Some(outcome) => outcome,
+                None => {
+                    let filter: Document = ops[*index].key.to_filter();
+                    error!(target: "app", "write_latest - Cannot find and update sensor with filter = {}", filter);
+                    Err(DbError::NotFound(format!("sensor with filter = {}", filter)))
+                }
+            },
+        )
+        .collect())
+}
+
+// indexes of the updates to write, keeping only the latest observation of every sensor.
+// Updates observed at the same time are applied in the order they have been received, so the last one wins
+fn collapse(ops: &[SensorOp]) -> Vec<usize> {
+    let mut latest: HashMap<&SensorKey, usize> = HashMap::with_capacity(ops.len());
+    for (index, op) in ops.iter().enumerate() {
+        latest
+            .entry(&op.key)
+            .and_modify(|latest_index| {
+                if ops[*latest_index].observed_at <= op.observed_at {
+                    *latest_index = index;
+                }
+            })
+            .or_insert(index);
+    }
+    let mut indexes: Vec<usize> = latest.into_values().collect();
+    indexes.sort_unstable();
+    indexes
+}
+
+async fn find_sensors_by_key(
+    db: &Database,
+    keys: &[&SensorKey],
+) -> Result<HashMap<SensorKey, SensorDocument>, DbError> {
+    let collection = db.collection::<SensorDocument>(SENSORS_COLLECTION);
+    let filters: Vec<Document> = keys.iter().map(|key| key.to_filter()).collect();
+    let mut cursor = collection.find(doc! { "$or": filters }).await?;
+    let mut sensor_docs: HashMap<SensorKey, SensorDocument> = HashMap::with_capacity(keys.len());
+    while let Some(sensor_doc) = cursor.next().await {
+        let sensor_doc: SensorDocument = sensor_doc?;
+        sensor_docs.insert(SensorKey::from_sensor(&sensor_doc), sensor_doc);
+    }
+    Ok(sensor_docs)
+}
+
+// the writer stops only during the shutdown, so the update can be retried later
+fn stopped_error() -> DbError {
+    DbError::Other(mongodb::error::Error::custom("bulk writer is not running"))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::db::bulk::{SUBMITTED, SensorKey, SensorOp, SubmissionNotifier, collapse};
+    use crate::models::sensor_value::{Measurement, SensorValue};
+    use mongodb::bson::DateTime;
+    use pretty_assertions::assert_eq;
+    use tokio::sync::oneshot;
+
+    fn op(feature_uuid: &str, value: f64, observed_at_ms: i64) -> SensorOp {
+        let (reply, _) = oneshot::channel();
+        SensorOp {
+            key: SensorKey {
+                api_token: "473a4861-632b-4915-b01e-cf1d418966c6".to_string(),
+                device_uuid: "246e3256-f0dd-4fcb-82c5-ee20c2267eeb".to_string(),
+                feature_uuid: feature_uuid.to_string(),
+            },
+            measurement: Measurement {
+                value: SensorValue::Float(value),
+                unit: None,
+                original_unit: None,
+            },
+            observed_at: DateTime::from_millis(observed_at_ms),
+            reply,
+        }
+    }
+
+    #[test]
+    #[test_log::test]
+    fn ok_collapse() {
+        let ops = vec![
+            op("temperature-uuid", 20.0, 2_000),
+            op("humidity-uuid", 40.0, 1_000),
+            // older than the first update of the same sensor
+            op("temperature-uuid", 19.0, 1_000),
+            op("humidity-uuid", 41.0, 3_000),
+            // same observation time, the last received wins
+            op("light-uuid", 100.0, 1_000),
+            op("light-uuid", 200.0, 1_000),
+        ];
+        assert_eq!(collapse(&ops), vec![0, 3, 5]);
+        assert_eq!(collapse(&[]), Vec::<usize>::new());
+    }
+
+    #[tokio::test]
+    #[test_log::test]
+    async fn ok_submission_notifier() {
+        let (notifier, submitted) = SubmissionNotifier::new();
+        let task = tokio::spawn(notifier.scope(async move {
+            let _ = SUBMITTED.try_with(SubmissionNotifier::notify);
+            // only the first update is notified
+            let _ = SUBMITTED.try_with(SubmissionNotifier::notify);
+        }));
+        assert_eq!(submitted.await, Ok(()));
+        task.await.unwrap();
+
+        // delivery processed without sending updates
+        let (notifier, submitted) = SubmissionNotifier::new();
+        notifier.scope(async {}).await;
+        assert!(submitted.await.is_err());
+    }
+}
@@ -0,0 +1,54 @@
+use mongodb::Database;
+use mongodb::bson::{Document, doc, to_document};
+use tracing::info;
+
+use crate::db::sensor::not_newer_than;
+use crate::errors::db_error::DbError;
+use crate::models::device_event::DeviceEventDocument;
+use crate::models::device_log::DeviceLogDocument;
+use crate::models::device_status::DeviceStatusDocument;
+
+pub const DEVICE_STATUS_COLLECTION: &str = "device_status";
+pub const DEVICE_EVENTS_COLLECTION: &str = "device_events";
+pub const DEVICE_LOGS_COLLECTION: &str = "device_logs";
+
+// every device has a single status document, with the latest status observed.
+// An older status (e.g. delivered late or requeued) never replaces a newer one
+pub async fn upsert_device_status(db: &Database, status_doc: &DeviceStatusDocument) -> Result<(), DbError> {
+    info!(target: "app", "upsert_device_status - Called with device_uuid = {}", status_doc.deviceUuid);
+    let collection = db.collection::<Document>(DEVICE_STATUS_COLLECTION);
+    let status: Document = to_document(status_doc).map_err(mongodb::error::Error::from)?;
+    let device_filter: Document = doc! { "apiToken": &status_doc.apiToken, "deviceUuid": &status_doc.deviceUuid };
+    let mut filter: Document = device_filter.clone();
+    filter.extend(not_newer_than(status_doc.observedAt));
+    let result = collection.update_one(filter, doc! { "$set": &status }).await?;
+    if result.matched_count > 0 {
+        return Ok(());
+    }
+    // the device has no status yet, or it has a newer one that must be kept
+    let result = collection
+        .update_one(device_filter, doc! { "$setOnInsert": status })
+        .upsert(true)
+        .await?;
+    if result.upserted_id.is_none() {
+        info!(target: "app", "upsert_device_status - status not updated, because the device has a status newer than {}", status_doc.observedAt);
+    }
+    Ok(())
+}
+
+pub async fn insert_device_event(db: &Database, event_doc: &DeviceEventDocument) -> Result<(), DbError> {
+    info!(target: "app", "insert_device_event - Called with device_uuid = {} and name = {}", event_doc.deviceUuid, event_doc.name);
+    let collection = db.collection::<DeviceEventDocument>(DEVICE_EVENTS_COLLECTION);
+    collection.insert_one(event_doc).await?;
+    Ok(())
+}
+
+pub async fn insert_device_logs(db: &Database, log_docs: &[DeviceLogDocument]) -> Result<(), DbError> {
+    info!(target: "app", "insert_device_logs - Called with {} lines", log_docs.len());
+    if log_docs.is_empty() {
+        return Ok(());
+    }
+    let collection = db.collection::<DeviceLogDocument>(DEVICE_LOGS_COLLECTION);
+    collection.insert_many(log_docs).await?;
+    Ok(())
+}
@@ -0,0 +1,191 @@
+use std::collections::HashSet;
+
+use futures_lite::StreamExt;
+use mongodb::Database;
+use mongodb::bson::{DateTime, Document, doc};
+use mongodb::options::{TimeseriesGranularity, TimeseriesOptions};
+use mongodb::results::{CollectionSpecification, CollectionType};
+use tracing::{debug, error, info, warn};
+
+use crate::config::Env;
+use crate::errors::db_error::DbError;
+use crate::models::sensor_history::SensorHistoryDocument;
+
+pub const HISTORY_COLLECTION: &str = "sensor_history";
+const HISTORY_TIME_FIELD: &str = "observedAt";
+const HISTORY_META_FIELD: &str = "meta";
+
+// expected interval between readings of the same sensor, used to organize the time-series buckets
+pub fn history_granularity(env: &Env) -> Result<TimeseriesGranularity, String> {
+    match env.mongo_history_granularity.as_str() {
+        "seconds" => Ok(TimeseriesGranularity::Seconds),
+        "minutes" => Ok(TimeseriesGranularity::Minutes),
+        "hours" => Ok(TimeseriesGranularity::Hours),
+        granularity => Err(format!(
+            "history granularity {} is not one of seconds, minutes, hours",
+            granularity
+        )),
+    }
+}
+
+// create the time-series history collection, if it doesn't exist yet.
+// Collections created by older versions (not time-series) are kept, but reported at startup
+pub async fn init_history_collection(db: &Database, granularity: TimeseriesGranularity) -> mongodb::error::Result<()> {
+    let mut specs: Vec<CollectionSpecification> = Vec::new();
+    let mut cursor = db
+        .list_collections()
+        .filter(doc! { "name": HISTORY_COLLECTION })
+        .await?;
+    while cursor.advance().await? {
+        specs.push(cursor.deserialize_current()?);
+    }
+    match specs.first() {
+        None => {
+            info!(target: "app", "init_history_collection - creating time-series collection = {} with granularity = {:?}", HISTORY_COLLECTION, granularity);
+            let timeseries = TimeseriesOptions::builder()
+                .time_field(HISTORY_TIME_FIELD.to_string())
+                .meta_field(Some(HISTORY_META_FIELD.to_string()))
+                .granularity(Some(granularity))
+                .build();
+            db.create_collection(HISTORY_COLLECTION).timeseries(timeseries).await
+        }
+        Some(spec) if spec.collection_type != CollectionType::Timeseries => {
+            error!(target: "app", "init_history_collection - collection = {} is not a time-series collection, drop it or rename it to enable the time-series history", HISTORY_COLLECTION);
+            Ok(())
+        }
+        Some(spec) => {
+            let current = spec.options.timeseries.as_ref();
+            if current.and_then(|timeseries| timeseries.granularity.as_ref()) != Some(&granularity) {
+                // granularity can only be increased, so a failure is reported without stopping the consumer
+                info!(target: "app", "init_history_collection - changing granularity of collection = {} to {:?}", HISTORY_COLLECTION, granularity);
+                let result = db
+                    .run_command(doc! { "collMod": HISTORY_COLLECTION, "timeseries": { "granularity": mongodb::bson::to_bson(&granularity)? } })
+                    .await;
+                if let Err(err) = result {
+                    warn!(target: "app", "init_history_collection - cannot change granularity of collection = {}, err = {:?}", HISTORY_COLLECTION, err);
+                }
+            }
+            Ok(())
+        }
+    }
+}
+
+// a reading is identified by its sensor and its observation time.
+// Time-series collections don't support unique indexes, so duplicates are skipped by `insert_history`
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct HistoryKey {
+    api_token: String,
+    device_uuid: String,
+    feature_uuid: String,
+    observed_at: DateTime,
+}
+
+impl HistoryKey {
+    fn from_history(history_doc: &SensorHistoryDocument) -> Self {
+        Self {
+            api_token: history_doc.meta.apiToken.clone(),
+            device_uuid: history_doc.meta.deviceUuid.clone(),
+            feature_uuid: history_doc.meta.featureUuid.clone(),
+            observed_at: history_doc.observedAt,
+        }
+    }
+
+    fn to_filter(&self) -> Document {
+        doc! {
+            "meta.apiToken": &self.api_token,
+            "meta.deviceUuid": &self.device_uuid,
+            "meta.featureUuid": &self.feature_uuid,
+            "observedAt": self.observed_at
+        }
+    }
+}
+
+// readings already stored (e.g. by a delivery requeued after a failure) are skipped,
+// so a message can be processed again without duplicating its history.
+// Returns the readings actually inserted
+pub async fn insert_history(
+    db: &Database,
+    history_docs: &[SensorHistoryDocument],
+) -> Result<Vec<SensorHistoryDocument>, DbError> {
+    info!(target: "app", "insert_history - Called with {} readings", history_docs.len());
+    if history_docs.is_empty() {
+        return Ok(Vec::new());
+    }
+    let collection = db.collection::<SensorHistoryDocument>(HISTORY_COLLECTION);
+    let filters: Vec<Document> = history_docs
+        .iter()
+        .map(|history_doc| HistoryKey::from_history(history_doc).to_filter())
+        .collect();
+    let mut cursor = collection.find(doc! { "$or": filters }).await?;
+    let mut stored: HashSet<HistoryKey> = HashSet::new();
+    while let Some(history_doc) = cursor.next().await {
+        stored.insert(HistoryKey::from_history(&history_doc?));
+    }
+    let new_docs: Vec<&SensorHistoryDocument> = new_readings(history_docs, stored);
+    if new_docs.len() < history_docs.len() {
+        debug!(target: "app", "insert_history - {} readings already stored", history_docs.len() - new_docs.len());
+    }
+    if !new_docs.is_empty() {
+        collection.insert_many(new_docs.iter().copied()).await?;
+    }
+    Ok(new_docs.into_iter().cloned().collect())
+}
+
+// readings not stored yet, keeping only the first one when the same reading is repeated
+fn new_readings(
+    history_docs: &[SensorHistoryDocument],
+    mut stored: HashSet<HistoryKey>,
+) -> Vec<&SensorHistoryDocument> {
+    history_docs
+        .iter()
+        .filter(|history_doc| stored.insert(HistoryKey::from_history(history_doc)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::db::history::{HistoryKey, new_readings};
+    use crate::models::sensor_history::{SensorHistoryDocument, SensorHistoryMeta};
+    use crate::models::sensor_value::{Measurement, SensorValue};
+    use mongodb::bson::DateTime;
+    use pretty_assertions::assert_eq;
+    use std::collections::HashSet;
+
+    fn history_doc(value: f64, observed_at_ms: i64) -> SensorHistoryDocument {
+        let meta = SensorHistoryMeta::new(
+            "473a4861-632b-4915-b01e-cf1d418966c6",
+            "246e3256-f0dd-4fcb-82c5-ee20c2267eeb",
+            "41cb3f47-894c-45e9-90d9-a4d4de903896",
+            "temperature",
+        );
+        let measurement = Measurement {
+            value: SensorValue::Float(value),
+            unit: None,
+            original_unit: None,
+        };
+        SensorHistoryDocument::new(
+            meta,
+            &measurement,
+            DateTime::from_millis(observed_at_ms),
+            DateTime::from_millis(3_000),
+        )
+    }
+
+    #[test]
+    #[test_log::test]
+    fn ok_new_readings() {
+        let history_docs = vec![
+            history_doc(20.0, 1_000),
+            history_doc(21.0, 2_000),
+            // repeated in the same message
+            history_doc(21.5, 2_000),
+            history_doc(22.0, 3_000),
+        ];
+        // stored by a previous delivery of the same message
+        let stored: HashSet<HistoryKey> = HashSet::from([HistoryKey::from_history(&history_doc(20.0, 1_000))]);
+        assert_eq!(
+            new_readings(&history_docs, stored),
+            vec![&history_docs[1], &history_docs[3]]
+        );
+    }
+}
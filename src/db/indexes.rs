@@ -0,0 +1,307 @@
+use std::time::Duration;
+
+use futures_lite::StreamExt;
+use mongodb::bson::{Document, doc};
+use mongodb::error::ErrorKind;
+use mongodb::options::IndexOptions;
+use mongodb::{Database, IndexModel};
+use tracing::{error, info, warn};
+
+use crate::db::device::DEVICE_STATUS_COLLECTION;
+use crate::db::pending::{PENDING_SENSOR_TTL, PENDING_SENSORS_COLLECTION};
+use crate::db::rollup::ROLLUP_COLLECTION;
+use crate::db::sensor::SENSORS_COLLECTION;
+use crate::errors::db_error::DbError;
+use crate::errors::index_error::IndexError;
+
+// error code of MongoDB when the collection doesn't exist yet
+const NAMESPACE_NOT_FOUND: i32 = 26;
+
+// index required by the queries of the consumer, otherwise every reading becomes a collection scan
+#[derive(Debug, Clone, PartialEq)]
+pub struct RequiredIndex {
+    pub collection: &'static str,
+    pub name: &'static str,
+    pub keys: Document,
+    // required by upserts, otherwise concurrent upserts could insert the same document twice
+    pub unique: bool,
+    // existing duplicates don't prevent the consumer from starting, the index is created without uniqueness
+    pub allow_duplicates: bool,
+    // TTL of the documents, removed after this time since the date of the indexed field
+    pub expire_after: Option<Duration>,
+}
+
+// state of a required index, compared to the existing indexes of its collection
+#[derive(Debug, Clone, PartialEq)]
+pub enum IndexCheck {
+    Present,
+    Missing,
+    // an index with the same keys exists, but queries may not use it
+    Degraded(String),
+    // the required index cannot be created, because it conflicts with an existing one
+    Incompatible(String),
+}
+
+// indexes of every collection queried by the consumer.
+// History is not included, because time-series collections are already indexed by metaField and timeField
+pub fn required_indexes() -> Vec<RequiredIndex> {
+    vec![
+        RequiredIndex {
+            collection: SENSORS_COLLECTION,
+            name: "sensor_key",
+            keys: doc! { "apiToken": 1, "deviceUuid": 1, "featureUuid": 1 },
+            // sensors are registered by another service, so duplicates must not prevent the consumer from starting
+            unique: false,
+            allow_duplicates: true,
+            expire_after: None,
+        },
+        RequiredIndex {
+            collection: ROLLUP_COLLECTION,
+            name: "rollup_key",
+            keys: doc! {
+                "meta.apiToken": 1,
+                "meta.deviceUuid": 1,
+                "meta.featureUuid": 1,
+                "tier": 1,
+                "timezone": 1,
+                "bucketStart": 1
+            },
+            unique: true,
+            allow_duplicates: false,
+            expire_after: None,
+        },
+        RequiredIndex {
+            collection: PENDING_SENSORS_COLLECTION,
+            name: "pending_sensor_key",
+            keys: doc! { "apiToken": 1, "deviceUuid": 1, "featureUuid": 1 },
+            unique: true,
+            allow_duplicates: false,
+            expire_after: None,
+        },
+        RequiredIndex {
+            collection: DEVICE_STATUS_COLLECTION,
+            name: "device_status_key",
+            keys: doc! { "apiToken": 1, "deviceUuid": 1 },
+            unique: true,
+            // statuses written by older versions could be duplicated, but a newer status is stored anyway
+            allow_duplicates: true,
+            expire_after: None,
+        },
+        RequiredIndex {
+            collection: PENDING_SENSORS_COLLECTION,
+            name: "pending_sensor_ttl",
+            keys: doc! { "lastSeenAt": 1 },
+            unique: false,
+            allow_duplicates: false,
+            // sensors never registered (e.g. devices of another profile) would be kept forever
+            expire_after: Some(PENDING_SENSOR_TTL),
+        },
+    ]
+}
+
+pub fn check_index(required: &RequiredIndex, existing: &[IndexModel]) -> IndexCheck {
+    if let Some(index) = existing
+        .iter()
+        .find(|index| index_options(index).name.as_deref() == Some(required.name))
+        && index.keys != required.keys
+    {
+        return IndexCheck::Incompatible(format!(
+            "index {} of collection {} has keys {}, instead of {}",
+            required.name, required.collection, index.keys, required.keys
+        ));
+    }
+    let Some(index) = existing.iter().find(|index| index.keys == required.keys) else {
+        return IndexCheck::Missing;
+    };
+    let options: IndexOptions = index_options(index);
+    let name: &str = options.name.as_deref().unwrap_or_default();
+    if required.unique && options.unique != Some(true) && required.allow_duplicates {
+        return IndexCheck::Degraded(format!(
+            "index {} of collection {} is not unique, remove the duplicates and drop it to let the consumer create the right one",
+            name, required.collection
+        ));
+    }
+    if required.unique && options.unique != Some(true) {
+        return IndexCheck::Incompatible(format!(
+            "index {} of collection {} must be unique",
+            name, required.collection
+        ));
+    }
+    if required.expire_after.is_some() && options.expire_after != required.expire_after {
+        return IndexCheck::Degraded(format!(
+            "index {} of collection {} doesn't expire documents after {:?}",
+            name, required.collection, required.expire_after
+        ));
+    }
+    if options.partial_filter_expression.is_some() || options.sparse == Some(true) {
+        return IndexCheck::Degraded(format!(
+            "index {} of collection {} doesn't include all documents",
+            name, required.collection
+        ));
+    }
+    if options.collation.is_some() || options.hidden == Some(true) {
+        return IndexCheck::Degraded(format!(
+            "index {} of collection {} is hidden or has a collation",
+            name, required.collection
+        ));
+    }
+    IndexCheck::Present
+}
+
+// create the missing indexes and validate the existing ones, before consuming messages.
+// Incompatible indexes prevent the consumer from starting, while degraded ones are only reported
+pub async fn ensure_indexes(db: &Database) -> Result<(), IndexError> {
+    for required in required_indexes() {
+        let existing: Vec<IndexModel> = list_indexes(db, required.collection)
+            .await
+            .map_err(IndexError::DbError)?;
+        match check_index(&required, &existing) {
+            IndexCheck::Present => {
+                info!(target: "app", "ensure_indexes - index = {} of collection = {} already exists", required.name, required.collection);
+            }
+            IndexCheck::Missing => {
+                info!(target: "app", "ensure_indexes - creating index = {} of collection = {}", required.name, required.collection);
+                match create_index(db, &required, required.unique).await {
+                    Err(err) if required.allow_duplicates && is_duplicate_key(&err) => {
+                        warn!(target: "app", "ensure_indexes - DEGRADED INDEX, index = {} of collection = {} created without uniqueness, because of duplicates: {}", required.name, required.collection, err);
+                        create_index(db, &required, false).await.map_err(IndexError::DbError)?;
+                    }
+                    result => result.map_err(IndexError::DbError)?,
+                }
+            }
+            IndexCheck::Degraded(reason) => {
+                warn!(target: "app", "ensure_indexes - DEGRADED INDEX, queries may scan the whole collection: {}", reason);
+            }
+            IndexCheck::Incompatible(reason) => {
+                error!(target: "app", "ensure_indexes - INCOMPATIBLE INDEX, drop it to let the consumer create the right one: {}", reason);
+                return Err(IndexError::IncompatibleIndex(reason));
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn create_index(db: &Database, required: &RequiredIndex, unique: bool) -> mongodb::error::Result<()> {
+    let index = IndexModel::builder()
+        .keys(required.keys.clone())
+        .options(
+            IndexOptions::builder()
+                .name(required.name.to_string())
+                .unique(unique)
+                .expire_after(required.expire_after)
+                .build(),
+        )
+        .build();
+    db.collection::<Document>(required.collection)
+        .create_index(index)
+        .await?;
+    Ok(())
+}
+
+fn is_duplicate_key(err: &mongodb::error::Error) -> bool {
+    matches!(DbError::from(err.clone()), DbError::Duplicate(_))
+}
+
+async fn list_indexes(db: &Database, collection: &str) -> mongodb::error::Result<Vec<IndexModel>> {
+    let mut indexes: Vec<IndexModel> = Vec::new();
+    let mut cursor = match db.collection::<Document>(collection).list_indexes().await {
+        Ok(cursor) => cursor,
+        Err(err) if matches!(*err.kind, ErrorKind::Command(ref command_error) if command_error.code == NAMESPACE_NOT_FOUND) =>
+        {
+            return Ok(indexes);
+        }
+        Err(err) => return Err(err),
+    };
+    while let Some(index) = cursor.next().await {
+        indexes.push(index?);
+    }
+    Ok(indexes)
+}
+
+fn index_options(index: &IndexModel) -> IndexOptions {
+    index.options.clone().unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::db::indexes::{IndexCheck, check_index, required_indexes};
+    use mongodb::IndexModel;
+    use mongodb::bson::doc;
+    use mongodb::options::IndexOptions;
+    use pretty_assertions::assert_eq;
+
+    fn index(name: &str, keys: mongodb::bson::Document, unique: bool) -> IndexModel {
+        IndexModel::builder()
+            .keys(keys)
+            .options(IndexOptions::builder().name(name.to_string()).unique(unique).build())
+            .build()
+    }
+
+    #[test]
+    #[test_log::test]
+    fn ok_check_index() {
+        let required = required_indexes();
+        let sensor_key = &required[0];
+        let rollup_key = &required[1];
+        let id = index("_id_", doc! { "_id": 1 }, false);
+        assert_eq!(check_index(sensor_key, std::slice::from_ref(&id)), IndexCheck::Missing);
+        // created by hand with another name
+        let existing = index("by_sensor", sensor_key.keys.clone(), false);
+        assert_eq!(check_index(sensor_key, &[id.clone(), existing]), IndexCheck::Present);
+        let existing = index("rollup_key", rollup_key.keys.clone(), true);
+        assert_eq!(check_index(rollup_key, &[id.clone(), existing]), IndexCheck::Present);
+        let pending_sensor_ttl = &required[4];
+        let existing = IndexModel::builder()
+            .keys(pending_sensor_ttl.keys.clone())
+            .options(
+                IndexOptions::builder()
+                    .name("pending_sensor_ttl".to_string())
+                    .expire_after(pending_sensor_ttl.expire_after)
+                    .build(),
+            )
+            .build();
+        assert_eq!(check_index(pending_sensor_ttl, &[id, existing]), IndexCheck::Present);
+    }
+
+    #[test]
+    #[test_log::test]
+    fn bad_check_index() {
+        let required = required_indexes();
+        let sensor_key = &required[0];
+        let rollup_key = &required[1];
+        let existing = index("sensor_key", doc! { "apiToken": 1 }, false);
+        assert!(matches!(
+            check_index(sensor_key, &[existing]),
+            IndexCheck::Incompatible(_)
+        ));
+        let existing = index("rollup_key", rollup_key.keys.clone(), false);
+        assert!(matches!(
+            check_index(rollup_key, &[existing]),
+            IndexCheck::Incompatible(_)
+        ));
+        let existing = IndexModel::builder()
+            .keys(sensor_key.keys.clone())
+            .options(
+                IndexOptions::builder()
+                    .name("sensor_key".to_string())
+                    .partial_filter_expression(doc! { "featureName": "temperature" })
+                    .build(),
+            )
+            .build();
+        assert!(matches!(check_index(sensor_key, &[existing]), IndexCheck::Degraded(_)));
+        // pending sensors must expire
+        let pending_sensor_ttl = &required[4];
+        let existing = index("pending_sensor_ttl", pending_sensor_ttl.keys.clone(), false);
+        assert!(matches!(
+            check_index(pending_sensor_ttl, &[existing]),
+            IndexCheck::Degraded(_)
+        ));
+        // device statuses can have duplicates
+        let device_status_key = &required[3];
+        let existing = index("device_status_key", device_status_key.keys.clone(), false);
+        assert!(matches!(
+            check_index(device_status_key, &[existing]),
+            IndexCheck::Degraded(_)
+        ));
+    }
+}
@@ -8,6 +8,13 @@ use tracing::{error, info, warn};
 
 use crate::config::Env;
 
+pub mod bulk;
+pub mod device;
+pub mod history;
+pub mod indexes;
+pub mod pending;
+pub mod retention;
+pub mod rollup;
 pub mod sensor;
 
 pub async fn connect(env_config: &Env) -> mongodb::error::Result<Database> {
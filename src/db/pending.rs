@@ -0,0 +1,203 @@
+use std::collections::{BTreeMap, HashSet};
+use std::time::Duration;
+
+use futures_lite::StreamExt;
+use mongodb::Database;
+use mongodb::bson::{Bson, Document, doc, to_bson};
+use tracing::{error, info};
+
+use crate::db::sensor::{SENSORS_COLLECTION, UpdateOutcome, backfill_sensor, sensor_key};
+use crate::errors::db_error::DbError;
+use crate::models::pending_sensor::PendingSensorDocument;
+
+pub const PENDING_SENSORS_COLLECTION: &str = "pending_sensors";
+// pending sensors not seen for this time are removed (see the `pending_sensor_ttl` index)
+pub const PENDING_SENSOR_TTL: Duration = Duration::from_secs(30 * 86_400);
+// max pending sensors checked with a single query of the sensors
+const PENDING_CHUNK_SIZE: usize = 500;
+
+// store the reading of a sensor that isn't registered yet, keeping the first time the sensor has been seen.
+// The latest value is replaced only by newer readings, because devices can send buffered readings
+pub async fn upsert_pending_sensor(db: &Database, pending_doc: &PendingSensorDocument) -> Result<(), DbError> {
+    info!(target: "app", "upsert_pending_sensor - Called with device_uuid = {} and feature_uuid = {}", pending_doc.deviceUuid, pending_doc.featureUuid);
+    let collection = db.collection::<Document>(PENDING_SENSORS_COLLECTION);
+    let is_newer: Document = doc! {
+        "$or": [
+            { "$eq": [{ "$type": "$observedAt" }, "missing"] },
+            { "$gte": [pending_doc.observedAt, "$observedAt"] }
+        ]
+    };
+    // a pipeline is used to update the latest value conditionally in a single atomic update
+    let latest = |field: &str, value: Bson| -> Document {
+        doc! { "$cond": [is_newer.clone(), { "$literal": value }, format!("${}", field)] }
+    };
+    let value: Bson = to_bson(&pending_doc.value).map_err(mongodb::error::Error::from)?;
+    let update: Vec<Document> = vec![doc! { "$set": {
+        "featureName": &pending_doc.featureName,
+        "value": latest("value", value),
+        "unit": latest("unit", pending_doc.unit.clone().into()),
+        "originalUnit": latest("originalUnit", pending_doc.originalUnit.clone().into()),
+        "observedAt": latest("observedAt", pending_doc.observedAt.into()),
+        "firstSeenAt": { "$ifNull": ["$firstSeenAt", pending_doc.firstSeenAt] },
+        "lastSeenAt": pending_doc.lastSeenAt,
+    } }];
+    collection
+        .update_one(
+            sensor_key(
+                pending_doc.apiToken.as_str(),
+                pending_doc.deviceUuid.as_str(),
+                pending_doc.featureUuid.as_str(),
+            ),
+            update,
+        )
+        .upsert(true)
+        .await?;
+    Ok(())
+}
+
+// pending sensors whose sensor has been registered in the meantime.
+// Pending sensors are checked in chunks, querying the sensors by profile and device (see the `sensor_key` index)
+async fn find_registered_pending_sensors(db: &Database) -> Result<Vec<PendingSensorDocument>, DbError> {
+    let collection = db.collection::<PendingSensorDocument>(PENDING_SENSORS_COLLECTION);
+    let mut cursor = collection.find(doc! {}).await?;
+    let mut registered: Vec<PendingSensorDocument> = Vec::new();
+    let mut chunk: Vec<PendingSensorDocument> = Vec::with_capacity(PENDING_CHUNK_SIZE);
+    while let Some(pending_doc) = cursor.next().await {
+        chunk.push(pending_doc?);
+        if chunk.len() == PENDING_CHUNK_SIZE {
+            registered.extend(retain_registered(db, std::mem::take(&mut chunk)).await?);
+        }
+    }
+    if !chunk.is_empty() {
+        registered.extend(retain_registered(db, chunk).await?);
+    }
+    Ok(registered)
+}
+
+async fn retain_registered(
+    db: &Database,
+    pending_docs: Vec<PendingSensorDocument>,
+) -> Result<Vec<PendingSensorDocument>, DbError> {
+    let collection = db.collection::<Document>(SENSORS_COLLECTION);
+    let mut cursor = collection
+        .find(registered_filter(&pending_docs))
+        .projection(doc! { "_id": 0, "apiToken": 1, "deviceUuid": 1, "featureUuid": 1 })
+        .await?;
+    let mut sensor_keys: HashSet<(String, String, String)> = HashSet::with_capacity(pending_docs.len());
+    while let Some(sensor_doc) = cursor.next().await {
+        let sensor_doc: Document = sensor_doc?;
+        if let (Ok(api_token), Ok(device_uuid), Ok(feature_uuid)) = (
+            sensor_doc.get_str("apiToken"),
+            sensor_doc.get_str("deviceUuid"),
+            sensor_doc.get_str("featureUuid"),
+        ) {
+            sensor_keys.insert((api_token.to_string(), device_uuid.to_string(), feature_uuid.to_string()));
+        }
+    }
+    Ok(pending_docs
+        .into_iter()
+        .filter(|pending_doc| {
+            sensor_keys.contains(&(
+                pending_doc.apiToken.clone(),
+                pending_doc.deviceUuid.clone(),
+                pending_doc.featureUuid.clone(),
+            ))
+        })
+        .collect())
+}
+
+// sensors of the pending ones, grouped by profile and device, so every group is an equality on the prefix of `sensor_key`
+fn registered_filter(pending_docs: &[PendingSensorDocument]) -> Document {
+    let mut devices: BTreeMap<(&str, &str), Vec<&str>> = BTreeMap::new();
+    for pending_doc in pending_docs {
+        devices
+            .entry((pending_doc.apiToken.as_str(), pending_doc.deviceUuid.as_str()))
+            .or_default()
+            .push(pending_doc.featureUuid.as_str());
+    }
+    let filters: Vec<Document> = devices
+        .into_iter()
+        .map(|((api_token, device_uuid), feature_uuids)| {
+            doc! { "apiToken": api_token, "deviceUuid": device_uuid, "featureUuid": { "$in": feature_uuids } }
+        })
+        .collect();
+    doc! { "$or": filters }
+}
+
+// set the latest value of the sensors registered after their first readings,
+// removing them from the pending sensors. It returns the number of updated sensors
+pub async fn backfill_pending_sensors(db: &Database) -> Result<usize, DbError> {
+    let pending_docs: Vec<PendingSensorDocument> = find_registered_pending_sensors(db).await?;
+    if pending_docs.is_empty() {
+        return Ok(0);
+    }
+    info!(target: "app", "backfill_pending_sensors - {} pending sensors have been registered", pending_docs.len());
+    let collection = db.collection::<Document>(PENDING_SENSORS_COLLECTION);
+    let mut updated: usize = 0;
+    for pending_doc in pending_docs {
+        match backfill_sensor(db, &pending_doc).await {
+            Ok(UpdateOutcome::Updated(_)) => updated += 1,
+            // the device has already sent a newer reading to the registered sensor
+            Ok(UpdateOutcome::Stale) => {}
+            // removed between the lookup and the update, the reading is kept until it's registered again
+            Err(DbError::NotFound(_)) => continue,
+            Err(err) => {
+                error!(target: "app", "backfill_pending_sensors - cannot back-fill sensor with feature_uuid = {}, err = {:?}", pending_doc.featureUuid, err);
+                return Err(err);
+            }
+        }
+        // a newer reading received in the meantime is kept, so it will be back-filled at the next run
+        let mut filter: Document = sensor_key(
+            pending_doc.apiToken.as_str(),
+            pending_doc.deviceUuid.as_str(),
+            pending_doc.featureUuid.as_str(),
+        );
+        filter.insert("observedAt", pending_doc.observedAt);
+        collection.delete_one(filter).await?;
+    }
+    Ok(updated)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::db::pending::registered_filter;
+    use crate::models::pending_sensor::PendingSensorDocument;
+    use crate::models::sensor_value::{Measurement, SensorValue};
+    use mongodb::bson::{DateTime, doc};
+    use pretty_assertions::assert_eq;
+
+    fn pending_doc(device_uuid: &str, feature_uuid: &str) -> PendingSensorDocument {
+        let measurement = Measurement {
+            value: SensorValue::Float(21.5),
+            unit: None,
+            original_unit: None,
+        };
+        let now = DateTime::from_millis(1_700_000_000_000);
+        PendingSensorDocument::new(
+            "473a4861-632b-4915-b01e-cf1d418966c6",
+            device_uuid,
+            feature_uuid,
+            "temperature",
+            &measurement,
+            now,
+            now,
+        )
+    }
+
+    #[test]
+    #[test_log::test]
+    fn ok_registered_filter() {
+        let pending_docs = vec![
+            pending_doc("device-2", "feature-3"),
+            pending_doc("device-1", "feature-1"),
+            pending_doc("device-2", "feature-2"),
+        ];
+        assert_eq!(
+            registered_filter(&pending_docs),
+            doc! { "$or": [
+                { "apiToken": "473a4861-632b-4915-b01e-cf1d418966c6", "deviceUuid": "device-1", "featureUuid": { "$in": ["feature-1"] } },
+                { "apiToken": "473a4861-632b-4915-b01e-cf1d418966c6", "deviceUuid": "device-2", "featureUuid": { "$in": ["feature-3", "feature-2"] } }
+            ] }
+        );
+    }
+}
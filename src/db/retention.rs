@@ -0,0 +1,166 @@
+use std::time::Duration;
+
+use futures_lite::StreamExt;
+use mongodb::bson::{Document, doc};
+use mongodb::options::IndexOptions;
+use mongodb::{Database, IndexModel};
+use tracing::info;
+
+use crate::db::history::HISTORY_COLLECTION;
+use crate::db::rollup::ROLLUP_COLLECTION;
+use crate::features::FeatureRegistry;
+use crate::rollups::RollupTier;
+
+// prefix of the TTL indexes managed by the consumer, so indexes created by hand are never dropped
+const TTL_INDEX_PREFIX: &str = "ttl_";
+const SECS_PER_DAY: u64 = 86_400;
+
+// TTL index that enforces the retention of a feature in a data tier
+#[derive(Debug, Clone, PartialEq)]
+pub struct TtlIndex {
+    pub collection: &'static str,
+    pub name: String,
+    pub field: &'static str,
+    pub filter: Document,
+    pub expire_after_secs: u64,
+}
+
+impl TtlIndex {
+    fn to_index_model(&self) -> IndexModel {
+        IndexModel::builder()
+            .keys(doc! { self.field: 1 })
+            .options(
+                IndexOptions::builder()
+                    .name(self.name.clone())
+                    .expire_after(Duration::from_secs(self.expire_after_secs))
+                    .partial_filter_expression(self.filter.clone())
+                    .build(),
+            )
+            .build()
+    }
+
+    // true if the existing index enforces the same retention
+    fn matches(&self, index: &IndexModel) -> bool {
+        let options: Option<&IndexOptions> = index.options.as_ref();
+        index.keys == doc! { self.field: 1 }
+            && options.and_then(|options| options.expire_after) == Some(Duration::from_secs(self.expire_after_secs))
+            && options.and_then(|options| options.partial_filter_expression.as_ref()) == Some(&self.filter)
+    }
+}
+
+// TTL indexes required by the retention of the features.
+// Raw readings are partial TTL indexes on the time-series collection, filtered by the metaField
+pub fn ttl_indexes(registry: &FeatureRegistry) -> Vec<TtlIndex> {
+    let mut indexes: Vec<TtlIndex> = Vec::new();
+    for feature in registry.features() {
+        let retention = feature.storage.retention;
+        if let Some(raw_days) = retention.raw_days {
+            indexes.push(TtlIndex {
+                collection: HISTORY_COLLECTION,
+                name: format!("{}raw_{}", TTL_INDEX_PREFIX, feature.name),
+                field: "observedAt",
+                filter: doc! { "meta.featureName": &feature.name },
+                expire_after_secs: raw_days * SECS_PER_DAY,
+            });
+        }
+        for (tier, days) in [
+            (RollupTier::Hour, retention.hourly_days),
+            (RollupTier::Day, retention.daily_days),
+        ] {
+            if let Some(days) = days {
+                indexes.push(TtlIndex {
+                    collection: ROLLUP_COLLECTION,
+                    name: format!("{}{}_{}", TTL_INDEX_PREFIX, tier.as_str(), feature.name),
+                    field: "bucketStart",
+                    filter: doc! { "meta.featureName": &feature.name, "tier": tier.as_str() },
+                    expire_after_secs: days * SECS_PER_DAY,
+                });
+            }
+        }
+    }
+    indexes.sort_by(|a, b| a.name.cmp(&b.name));
+    indexes
+}
+
+// create, change and drop the TTL indexes of history and rollups, to match the retention of the features.
+// Changed indexes are recreated, because the retention of a partial index cannot always be modified in place
+pub async fn reconcile_retention(db: &Database, registry: &FeatureRegistry) -> mongodb::error::Result<()> {
+    let required: Vec<TtlIndex> = ttl_indexes(registry);
+    info!(target: "app", "reconcile_retention - Called with {} TTL indexes", required.len());
+    for collection_name in [HISTORY_COLLECTION, ROLLUP_COLLECTION] {
+        let collection = db.collection::<Document>(collection_name);
+        let mut existing: Vec<IndexModel> = Vec::new();
+        let mut cursor = collection.list_indexes().await?;
+        while let Some(index) = cursor.next().await {
+            let index: IndexModel = index?;
+            if index_name(&index).is_some_and(|name| name.starts_with(TTL_INDEX_PREFIX)) {
+                existing.push(index);
+            }
+        }
+        for index in &existing {
+            let Some(name) = index_name(index) else {
+                continue;
+            };
+            let keep: bool = required.iter().any(|ttl_index| {
+                ttl_index.collection == collection_name && ttl_index.name == name && ttl_index.matches(index)
+            });
+            if !keep {
+                info!(target: "app", "reconcile_retention - dropping TTL index = {} of collection = {}", name, collection_name);
+                collection.drop_index(name).await?;
+            }
+        }
+        for ttl_index in required
+            .iter()
+            .filter(|ttl_index| ttl_index.collection == collection_name)
+        {
+            if existing
+                .iter()
+                .any(|index| ttl_index.matches(index) && index_name(index) == Some(ttl_index.name.as_str()))
+            {
+                continue;
+            }
+            info!(target: "app", "reconcile_retention - creating TTL index = {} of collection = {} with expireAfterSeconds = {}",
+                ttl_index.name, collection_name, ttl_index.expire_after_secs);
+            collection.create_index(ttl_index.to_index_model()).await?;
+        }
+    }
+    Ok(())
+}
+
+fn index_name(index: &IndexModel) -> Option<&str> {
+    index.options.as_ref().and_then(|options| options.name.as_deref())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::db::retention::ttl_indexes;
+    use crate::features::FeatureRegistry;
+    use mongodb::bson::doc;
+    use pretty_assertions::assert_eq;
+    use serde_json::json;
+
+    #[test]
+    #[test_log::test]
+    fn ok_ttl_indexes() {
+        let content = json!({
+            "features": [
+                { "name": "temperature", "valueType": "float" },
+                { "name": "motion", "valueType": "integer", "storage": { "retention": { "rawDays": 30, "dailyDays": 365 } } }
+            ]
+        })
+        .to_string();
+        let indexes = ttl_indexes(&FeatureRegistry::parse(content.as_str()).unwrap());
+        assert_eq!(indexes.len(), 2);
+        assert_eq!(indexes[0].collection, "sensor_rollups");
+        assert_eq!(indexes[0].name, "ttl_day_motion");
+        assert_eq!(indexes[0].field, "bucketStart");
+        assert_eq!(indexes[0].filter, doc! { "meta.featureName": "motion", "tier": "day" });
+        assert_eq!(indexes[0].expire_after_secs, 365 * 86_400);
+        assert_eq!(indexes[1].collection, "sensor_history");
+        assert_eq!(indexes[1].name, "ttl_raw_motion");
+        assert_eq!(indexes[1].field, "observedAt");
+        assert_eq!(indexes[1].filter, doc! { "meta.featureName": "motion" });
+        assert_eq!(indexes[1].expire_after_secs, 30 * 86_400);
+        assert!(ttl_indexes(&FeatureRegistry::default()).is_empty());
+    }
+}
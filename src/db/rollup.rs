@@ -0,0 +1,59 @@
+use mongodb::bson::{DateTime, Document, doc};
+use mongodb::options::UpdateOneModel;
+use mongodb::{Database, Namespace};
+use tracing::info;
+
+use crate::errors::db_error::DbError;
+use crate::models::sensor_history::SensorHistoryDocument;
+use crate::rollups::{RollupCalendar, RollupTier};
+
+pub const ROLLUP_COLLECTION: &str = "sensor_rollups";
+
+// add the numeric readings of the history to the rollups of their hour and day.
+// Updates are commutative, so readings can arrive in any order (e.g. buffered by the device),
+// but they aren't idempotent, so only readings inserted in the history for the first time must be added
+// (see `insert_history`), otherwise the readings of a message processed again would be counted twice
+pub async fn update_rollups(
+    db: &Database,
+    calendar: &RollupCalendar,
+    history_docs: &[SensorHistoryDocument],
+) -> Result<(), DbError> {
+    let namespace: Namespace = db.collection::<Document>(ROLLUP_COLLECTION).namespace();
+    let modified_at: DateTime = DateTime::now();
+    let mut models: Vec<UpdateOneModel> = Vec::with_capacity(history_docs.len() * RollupTier::ALL.len());
+    for history_doc in history_docs {
+        // booleans, strings and objects cannot be aggregated
+        let Some(value) = history_doc.value.as_f64() else {
+            continue;
+        };
+        for tier in RollupTier::ALL {
+            models.push(
+                UpdateOneModel::builder()
+                    .namespace(namespace.clone())
+                    .filter(doc! {
+                        "meta.apiToken": &history_doc.meta.apiToken,
+                        "meta.deviceUuid": &history_doc.meta.deviceUuid,
+                        "meta.featureUuid": &history_doc.meta.featureUuid,
+                        "tier": tier.as_str(),
+                        "timezone": calendar.timezone.name(),
+                        "bucketStart": calendar.bucket_start(tier, history_doc.observedAt),
+                    })
+                    .update(doc! {
+                        "$min": { "min": value, "firstObservedAt": history_doc.observedAt },
+                        "$max": { "max": value, "lastObservedAt": history_doc.observedAt },
+                        "$inc": { "sum": value, "count": 1_i64 },
+                        "$set": { "unit": &history_doc.unit, "modifiedAt": modified_at },
+                        "$setOnInsert": { "meta.featureName": &history_doc.meta.featureName },
+                    })
+                    .upsert(true)
+                    .build(),
+            );
+        }
+    }
+    info!(target: "app", "update_rollups - Called with {} readings, {} rollups to update", history_docs.len(), models.len());
+    if models.is_empty() {
+        return Ok(());
+    }
+    db.client().bulk_write(models).await?;
+    Ok(())
+}
@@ -1,48 +1,333 @@
+use std::collections::HashMap;
+
+use futures_lite::StreamExt;
 use tracing::{error, info};
 
 use mongodb::Database;
-use mongodb::bson::{Bson, DateTime, doc};
-use mongodb::options::ReturnDocument;
+use mongodb::bson::oid::ObjectId;
+use mongodb::bson::{Bson, DateTime, Document, doc, to_bson};
+use mongodb::options::{ReturnDocument, UpdateOneModel};
 
+use crate::errors::db_error::DbError;
 use crate::models::generic_message::GenericMessage;
+use crate::models::pending_sensor::PendingSensorDocument;
 use crate::models::sensor::Sensor;
 use crate::models::sensor::SensorDocument;
+use crate::models::sensor::SensorUpdate;
+use crate::models::sensor_value::Measurement;
+use crate::validation::PreviousReading;
+
+pub const SENSORS_COLLECTION: &str = "sensors";
+
+// bulk writes of the sensors changed since they have been read, before giving up
+const MAX_UPDATE_ATTEMPTS: usize = 3;
+
+// result of a conditional update of a sensor
+#[derive(Debug)]
+pub enum UpdateOutcome {
+    Updated(Box<SensorUpdate>),
+    // the sensor has a newer observation, so the reading has been discarded
+    Stale,
+}
+
+// reading of a message with multiple readings, ready to be stored
+#[derive(Debug)]
+pub struct SensorWrite {
+    // current document, read with `find_sensors`
+    pub sensor_doc: SensorDocument,
+    pub measurement: Measurement,
+    pub observed_at: DateTime,
+}
 
 pub async fn update_sensor(
     db: &Database,
     generic_msg: &GenericMessage,
-    value: &Bson,
-) -> mongodb::error::Result<Option<Sensor>> {
+    measurement: &Measurement,
+    observed_at: DateTime,
+) -> Result<UpdateOutcome, DbError> {
     info!(target: "app", "update_sensor - Called with generic_msg = {:?}", generic_msg);
+    let sensor_filter: Document = sensor_key(
+        generic_msg.api_token.as_str(),
+        generic_msg.device_uuid.as_str(),
+        generic_msg.feature_uuid.as_str(),
+    );
+    update_matching_sensor(db, sensor_filter, measurement, observed_at).await
+}
 
-    let collection = db.collection::<SensorDocument>("sensors");
-
-    let api_token: String = generic_msg.api_token.clone();
-    let device_uuid: String = generic_msg.device_uuid.clone();
-    let feature_uuid: String = generic_msg.feature_uuid.clone();
-    let sensor_doc = collection
-        .find_one_and_update(
-            doc! { "apiToken": api_token, "deviceUuid": device_uuid, "featureUuid": feature_uuid },
-            doc! { "$set": {
-                    "value": value,
-                    "modifiedAt": DateTime::now()
-                }
-            },
-        )
-        .return_document(ReturnDocument::After)
-        .await
-        .unwrap(); // TODO ATTENTION I should check and return a custom DbError here Err(....) and not unwrap and ignore the error.
+// set the latest value of a sensor registered after its first readings (see `pending_sensors`)
+pub async fn backfill_sensor(db: &Database, pending_doc: &PendingSensorDocument) -> Result<UpdateOutcome, DbError> {
+    info!(target: "app", "backfill_sensor - Called with device_uuid = {} and feature_uuid = {}", pending_doc.deviceUuid, pending_doc.featureUuid);
+    let sensor_filter: Document = sensor_key(
+        pending_doc.apiToken.as_str(),
+        pending_doc.deviceUuid.as_str(),
+        pending_doc.featureUuid.as_str(),
+    );
+    update_matching_sensor(db, sensor_filter, &pending_doc.measurement(), pending_doc.observedAt).await
+}
+
+async fn update_matching_sensor(
+    db: &Database,
+    sensor_filter: Document,
+    measurement: &Measurement,
+    observed_at: DateTime,
+) -> Result<UpdateOutcome, DbError> {
+    let collection = db.collection::<SensorDocument>(SENSORS_COLLECTION);
+    let mut filter: Document = sensor_filter.clone();
+    filter.extend(not_newer_than(observed_at));
+    let modified_at: DateTime = DateTime::now();
+    let previous_doc = collection
+        .find_one_and_update(filter, set_measurement(measurement, modified_at, observed_at)?)
+        // the previous value is required to publish the `sensor.updated` event
+        .return_document(ReturnDocument::Before)
+        .await?;
 
     // return result
-    match sensor_doc {
-        Some(sensor_doc) => Ok(Some(document_to_json(&sensor_doc))),
+    match previous_doc {
+        Some(previous_doc) => Ok(UpdateOutcome::Updated(Box::new(to_sensor_update(
+            &previous_doc,
+            measurement,
+            modified_at,
+            observed_at,
+        )))),
         None => {
-            error!(target: "app", "update_sensor - Cannot find and update sensor with device_uuid = {} and feature_uuid = {}", 
-                generic_msg.device_uuid, generic_msg.feature_uuid);
-            // TODO ATTENTION I should return a custom DbError here Err(....) and not Ok.
-            Ok(None)
+            // the update is conditional, so the sensor could exist with a newer observation
+            match collection.count_documents(sensor_filter.clone()).await? {
+                0 => {
+                    error!(target: "app", "update_matching_sensor - Cannot find and update sensor with filter = {}", sensor_filter);
+                    Err(DbError::NotFound(format!("sensor with filter = {}", sensor_filter)))
+                }
+                _ => Ok(UpdateOutcome::Stale),
+            }
+        }
+    }
+}
+
+// true if the profile has registered at least one sensor (served by the prefix of the `sensor_key` index)
+pub async fn is_known_profile(db: &Database, api_token: &str) -> Result<bool, DbError> {
+    let collection = db.collection::<Document>(SENSORS_COLLECTION);
+    let sensor_doc: Option<Document> = collection
+        .find_one(doc! { "apiToken": api_token })
+        .projection(doc! { "_id": 1 })
+        .await?;
+    Ok(sensor_doc.is_some())
+}
+
+pub async fn find_sensor(db: &Database, generic_msg: &GenericMessage) -> Result<Option<SensorDocument>, DbError> {
+    let collection = db.collection::<SensorDocument>(SENSORS_COLLECTION);
+    let sensor_doc: Option<SensorDocument> = collection
+        .find_one(sensor_key(
+            generic_msg.api_token.as_str(),
+            generic_msg.device_uuid.as_str(),
+            generic_msg.feature_uuid.as_str(),
+        ))
+        .await?;
+    Ok(sensor_doc)
+}
+
+// value currently stored for the sensor, used to validate the rate of change of a new reading
+pub async fn get_previous_reading(
+    db: &Database,
+    generic_msg: &GenericMessage,
+    observed_at: DateTime,
+) -> Result<Option<PreviousReading>, DbError> {
+    let sensor_doc: Option<SensorDocument> = find_sensor(db, generic_msg).await?;
+    Ok(sensor_doc.map(|sensor_doc| previous_reading(&sensor_doc, observed_at)))
+}
+
+// sensors of the device with the given features, by `featureUuid`
+pub async fn find_sensors(
+    db: &Database,
+    generic_msg: &GenericMessage,
+    feature_uuids: &[&str],
+) -> Result<HashMap<String, SensorDocument>, DbError> {
+    let collection = db.collection::<SensorDocument>(SENSORS_COLLECTION);
+    let mut cursor = collection
+        .find(doc! {
+            "apiToken": &generic_msg.api_token,
+            "deviceUuid": &generic_msg.device_uuid,
+            "featureUuid": { "$in": feature_uuids }
+        })
+        .await?;
+    let mut sensor_docs: HashMap<String, SensorDocument> = HashMap::with_capacity(feature_uuids.len());
+    while let Some(sensor_doc) = cursor.next().await {
+        let sensor_doc: SensorDocument = sensor_doc?;
+        sensor_docs.insert(sensor_doc.featureUuid.clone(), sensor_doc);
+    }
+    Ok(sensor_docs)
+}
+
+// update multiple sensors in a single bulk write, returning the outcome of every write.
+// Sensors must have been read with `find_sensors`, because their current documents are used
+// to build the result without reading them again. A sensor is written only if it hasn't changed since it has been read,
+// otherwise the previous value of its update would be wrong, so sensors changed in the meantime are read again
+// and written with another bulk write, up to `MAX_UPDATE_ATTEMPTS` times
+pub async fn update_sensors(
+    db: &Database,
+    writes: &[SensorWrite],
+) -> Result<Vec<Result<UpdateOutcome, DbError>>, DbError> {
+    info!(target: "app", "update_sensors - Called with {} writes", writes.len());
+
+    let collection = db.collection::<SensorDocument>(SENSORS_COLLECTION);
+    let mut sensor_docs: Vec<SensorDocument> = writes.iter().map(|write| write.sensor_doc.clone()).collect();
+    let mut outcomes: Vec<Option<Result<UpdateOutcome, DbError>>> = writes.iter().map(|_| None).collect();
+    let mut pending: Vec<usize> = (0..writes.len()).collect();
+    for _ in 0..MAX_UPDATE_ATTEMPTS {
+        if pending.is_empty() {
+            break;
+        }
+        let modified_at: DateTime = DateTime::now();
+        let mut models: Vec<UpdateOneModel> = Vec::with_capacity(pending.len());
+        for index in pending.iter() {
+            let write: &SensorWrite = &writes[*index];
+            models.push(
+                UpdateOneModel::builder()
+                    .namespace(collection.namespace())
+                    .filter(unchanged_since_read(&sensor_docs[*index], write.observed_at))
+                    .update(set_measurement(&write.measurement, modified_at, write.observed_at)?)
+                    .build(),
+            );
+        }
+        let result = db.client().bulk_write(models).verbose_results().await?;
+        let mut unmatched: Vec<usize> = Vec::new();
+        for (position, index) in pending.iter().enumerate() {
+            let write: &SensorWrite = &writes[*index];
+            match result.update_results.get(&position) {
+                Some(update_result) if update_result.matched_count > 0 => {
+                    outcomes[*index] = Some(Ok(UpdateOutcome::Updated(Box::new(to_sensor_update(
+                        &sensor_docs[*index],
+                        &write.measurement,
+                        modified_at,
+                        write.observed_at,
+                    )))));
+                }
+                _ => unmatched.push(*index),
+            }
+        }
+        if unmatched.is_empty() {
+            break;
+        }
+
+        // sensors updated or deleted since they have been read
+        let ids: Vec<ObjectId> = unmatched.iter().map(|index| sensor_docs[*index]._id).collect();
+        let current_docs: HashMap<ObjectId, SensorDocument> = find_sensors_by_id(db, &ids).await?;
+        pending.clear();
+        for index in unmatched {
+            let write: &SensorWrite = &writes[index];
+            match current_docs.get(&sensor_docs[index]._id) {
+                None => {
+                    error!(target: "app", "update_sensors - Cannot find and update sensor with _id = {}", sensor_docs[index]._id);
+                    outcomes[index] = Some(Err(DbError::NotFound(format!(
+                        "sensor with _id = {}",
+                        sensor_docs[index]._id
+                    ))));
+                }
+                Some(current_doc) if is_newer(current_doc, write.observed_at) => {
+                    outcomes[index] = Some(Ok(UpdateOutcome::Stale));
+                }
+                Some(current_doc) => {
+                    sensor_docs[index] = current_doc.clone();
+                    pending.push(index);
+                }
+            }
         }
     }
+
+    Ok(outcomes
+        .into_iter()
+        .enumerate()
+        .map(|(index, outcome)| {
+            outcome.unwrap_or_else(|| {
+                // the message can be retried later, when the sensor is not updated so frequently
+                error!(target: "app", "update_sensors - Sensor with _id = {} updated concurrently too many times", sensor_docs[index]._id);
+                Err(DbError::Other(mongodb::error::Error::custom(format!(
+                    "sensor with _id = {} updated concurrently",
+                    sensor_docs[index]._id
+                ))))
+            })
+        })
+        .collect())
+}
+
+// filter of a sensor that hasn't changed since `sensor_doc` has been read and has no observations newer than `observed_at`
+fn unchanged_since_read(sensor_doc: &SensorDocument, observed_at: DateTime) -> Document {
+    let mut filter: Document = doc! {
+        "_id": sensor_doc._id,
+        "modifiedAt": sensor_doc.modifiedAt,
+        "observedAt": sensor_doc.observedAt.map(Bson::DateTime).unwrap_or(Bson::Null),
+    };
+    filter.extend(not_newer_than(observed_at));
+    filter
+}
+
+async fn find_sensors_by_id(db: &Database, ids: &[ObjectId]) -> Result<HashMap<ObjectId, SensorDocument>, DbError> {
+    let collection = db.collection::<SensorDocument>(SENSORS_COLLECTION);
+    let mut cursor = collection.find(doc! { "_id": { "$in": ids } }).await?;
+    let mut sensor_docs: HashMap<ObjectId, SensorDocument> = HashMap::with_capacity(ids.len());
+    while let Some(sensor_doc) = cursor.next().await {
+        let sensor_doc: SensorDocument = sensor_doc?;
+        sensor_docs.insert(sensor_doc._id, sensor_doc);
+    }
+    Ok(sensor_docs)
+}
+
+// value currently stored for the sensor, used to validate the rate of change of a reading observed at `observed_at`
+pub fn previous_reading(sensor_doc: &SensorDocument, observed_at: DateTime) -> PreviousReading {
+    let previous_observed_at: DateTime = sensor_doc.observedAt.unwrap_or(sensor_doc.modifiedAt);
+    PreviousReading {
+        value: sensor_doc.value.clone(),
+        elapsed_secs: (observed_at.timestamp_millis() - previous_observed_at.timestamp_millis()) as f64 / 1000.0,
+    }
+}
+
+// a sensor is identified by its profile, device and feature (see the `sensor_key` index)
+pub fn sensor_key(api_token: &str, device_uuid: &str, feature_uuid: &str) -> Document {
+    doc! { "apiToken": api_token, "deviceUuid": device_uuid, "featureUuid": feature_uuid }
+}
+
+// true if the sensor already has an observation newer than `observed_at`
+pub fn is_newer(sensor_doc: &SensorDocument, observed_at: DateTime) -> bool {
+    sensor_doc.observedAt.is_some_and(|stored| stored > observed_at)
+}
+
+// filter of documents without observations newer than `observed_at`, so an older observation never replaces a newer one.
+// Sensors never updated (or updated by older versions) don't have `observedAt`
+pub fn not_newer_than(observed_at: DateTime) -> Document {
+    doc! { "$or": [{ "observedAt": null }, { "observedAt": { "$lte": observed_at } }] }
+}
+
+fn set_measurement(
+    measurement: &Measurement,
+    modified_at: DateTime,
+    observed_at: DateTime,
+) -> mongodb::error::Result<Document> {
+    let bson_value: Bson = to_bson(&measurement.value)?;
+    Ok(doc! { "$set": {
+            "value": bson_value,
+            "unit": &measurement.unit,
+            "originalUnit": &measurement.original_unit,
+            "modifiedAt": modified_at,
+            "observedAt": observed_at
+        }
+    })
+}
+
+// only value, units and dates are updated, so the current sensor is derived from the previous one
+fn to_sensor_update(
+    previous_doc: &SensorDocument,
+    measurement: &Measurement,
+    modified_at: DateTime,
+    observed_at: DateTime,
+) -> SensorUpdate {
+    let mut current_doc: SensorDocument = previous_doc.clone();
+    current_doc.value = measurement.value.clone();
+    current_doc.unit = measurement.unit.clone();
+    current_doc.originalUnit = measurement.original_unit.clone();
+    current_doc.modifiedAt = modified_at;
+    current_doc.observedAt = Some(observed_at);
+    SensorUpdate {
+        previous: document_to_json(previous_doc),
+        current: document_to_json(&current_doc),
+    }
 }
 
 fn document_to_json(sensor_doc: &SensorDocument) -> Sensor {
@@ -59,10 +344,13 @@ fn document_to_json(sensor_doc: &SensorDocument) -> Sensor {
         // feature info
         featureUuid: sensor_doc.featureUuid.to_string(),
         featureName: sensor_doc.featureName.to_string(),
-        value: sensor_doc.value,
+        value: sensor_doc.value.clone(),
+        unit: sensor_doc.unit.clone(),
+        originalUnit: sensor_doc.originalUnit.clone(),
         // dates
         createdAt: sensor_doc.createdAt.to_string(),
         modifiedAt: sensor_doc.modifiedAt.to_string(),
+        observedAt: sensor_doc.observedAt.map(|observed_at| observed_at.to_string()),
     }
 }
 
@@ -70,6 +358,7 @@ fn document_to_json(sensor_doc: &SensorDocument) -> Sensor {
 mod tests {
     use crate::db::sensor::document_to_json;
     use crate::models::sensor::{Sensor, SensorDocument};
+    use crate::models::sensor_value::SensorValue;
     use mongodb::bson::DateTime;
     use mongodb::bson::oid::ObjectId;
     use pretty_assertions::assert_eq;
@@ -86,7 +375,7 @@ mod tests {
         let profile_owner_id = ObjectId::from_str("620d710e4e8fe8f3394084bc").unwrap();
         let api_token = "473a4861-632b-4915-b01e-cf1d418966c6";
         let date = DateTime::now();
-        let value: f64 = 10.2;
+        let value = SensorValue::Float(10.2);
         let feature_uuid = "41cb3f47-894c-45e9-90d9-a4d4de903896";
         let feature_name = "temperature";
         let sensor_doc = SensorDocument {
@@ -102,10 +391,13 @@ mod tests {
             // feature info
             featureUuid: feature_uuid.to_string(),
             featureName: feature_name.to_string(),
-            value,
+            value: value.clone(),
+            unit: Some("°C".to_string()),
+            originalUnit: Some("°F".to_string()),
             // dates
             createdAt: date,
             modifiedAt: date,
+            observedAt: Some(date),
         };
         let sensor: Sensor = document_to_json(&sensor_doc);
         assert_eq!(sensor._id, oid.to_string());
@@ -121,8 +413,11 @@ mod tests {
         assert_eq!(sensor.featureUuid, feature_uuid.to_string());
         assert_eq!(sensor.featureName, feature_name.to_string());
         assert_eq!(sensor.value, value);
+        assert_eq!(sensor.unit, Some("°C".to_string()));
+        assert_eq!(sensor.originalUnit, Some("°F".to_string()));
 
         assert_eq!(sensor.createdAt, date.to_string());
         assert_eq!(sensor.modifiedAt, date.to_string());
+        assert_eq!(sensor.observedAt, Some(date.to_string()));
     }
 }
@@ -9,4 +9,16 @@ pub enum AmqpError {
     ErrorButRecovered(String),
     #[error("amqp_client error, cannot auto recover")]
     ErrorCannotRecover(String),
+    #[error("amqp_client cannot connect error")]
+    ConnectionError(String),
+    #[error("amqp_client cannot declare topology error")]
+    DeclareError(String),
+    #[error("amqp_client cannot publish message error")]
+    PublishError(String),
+    #[error("amqp_client invalid topology error")]
+    InvalidTopology(String),
+    #[error("amqp_client invalid TLS configuration error")]
+    InvalidTls(String),
+    #[error("amqp_client cannot close error")]
+    CloseError(String),
 }
@@ -0,0 +1,127 @@
+use mongodb::error::{ErrorKind, RETRYABLE_WRITE_ERROR, TRANSIENT_TRANSACTION_ERROR, WriteFailure};
+use thiserror::Error;
+
+// error codes returned by MongoDB
+const UNAUTHORIZED: i32 = 13;
+const AUTHENTICATION_FAILED: i32 = 18;
+const DUPLICATE_KEY: i32 = 11000;
+// not primary, shutting down, network timeouts, ... (the same codes retried by the driver)
+const TRANSIENT_CODES: [i32; 13] = [6, 7, 89, 91, 134, 189, 262, 9001, 10107, 11600, 11602, 13435, 13436];
+
+// custom error, based on 'thiserror' library
+#[derive(Error, Debug, Clone)]
+pub enum DbError {
+    #[error("document not found error")]
+    NotFound(String),
+    #[error("duplicate key error")]
+    Duplicate(#[source] mongodb::error::Error),
+    #[error("network or transient db error")]
+    Transient(#[source] mongodb::error::Error),
+    #[error("db authentication or authorization error")]
+    Auth(#[source] mongodb::error::Error),
+    #[error("db write concern error")]
+    WriteConcern(#[source] mongodb::error::Error),
+    #[error("db error")]
+    Other(#[source] mongodb::error::Error),
+}
+
+impl DbError {
+    // true if the same operation could succeed later.
+    // Unique indexes are only written by upserts, so a duplicate key means that a concurrent upsert has inserted
+    // the same document first, and the retry updates it. Authentication errors are retried too,
+    // because credentials and roles can be fixed without losing messages.
+    // Unknown errors are retried too, the retry policy of the queue limits the attempts
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            DbError::NotFound(_) => false,
+            DbError::Duplicate(_)
+            | DbError::Auth(_)
+            | DbError::Transient(_)
+            | DbError::WriteConcern(_)
+            | DbError::Other(_) => true,
+        }
+    }
+
+    fn from_code(code: i32, err: mongodb::error::Error) -> Self {
+        match code {
+            DUPLICATE_KEY => DbError::Duplicate(err),
+            UNAUTHORIZED | AUTHENTICATION_FAILED => DbError::Auth(err),
+            code if TRANSIENT_CODES.contains(&code) => DbError::Transient(err),
+            _ => DbError::Other(err),
+        }
+    }
+}
+
+impl From<mongodb::error::Error> for DbError {
+    fn from(err: mongodb::error::Error) -> Self {
+        if err.contains_label(RETRYABLE_WRITE_ERROR) || err.contains_label(TRANSIENT_TRANSACTION_ERROR) {
+            return DbError::Transient(err);
+        }
+        let code: Option<i32> = match err.kind.as_ref() {
+            ErrorKind::Authentication { .. } => return DbError::Auth(err),
+            ErrorKind::Io(_)
+            | ErrorKind::ConnectionPoolCleared { .. }
+            | ErrorKind::ServerSelection { .. }
+            | ErrorKind::DnsResolve { .. } => return DbError::Transient(err),
+            ErrorKind::Write(WriteFailure::WriteConcernError(_)) => return DbError::WriteConcern(err),
+            ErrorKind::InsertMany(insert_error) if insert_error.write_concern_error.is_some() => {
+                return DbError::WriteConcern(err);
+            }
+            ErrorKind::BulkWrite(bulk_error) if !bulk_error.write_concern_errors.is_empty() => {
+                return DbError::WriteConcern(err);
+            }
+            ErrorKind::Command(command_error) => Some(command_error.code),
+            ErrorKind::Write(WriteFailure::WriteError(write_error)) => Some(write_error.code),
+            ErrorKind::InsertMany(insert_error) => insert_error
+                .write_errors
+                .as_ref()
+                .and_then(|write_errors| write_errors.first())
+                .map(|write_error| write_error.code),
+            ErrorKind::BulkWrite(bulk_error) => bulk_error
+                .write_errors
+                .values()
+                .next()
+                .map(|write_error| write_error.code),
+            _ => None,
+        };
+        match code {
+            Some(code) => DbError::from_code(code, err),
+            None => DbError::Other(err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::errors::db_error::DbError;
+    use mongodb::bson::{doc, from_document};
+    use mongodb::error::{CommandError, Error, ErrorKind};
+
+    fn command_error(code: i32) -> Error {
+        let command_error: CommandError =
+            from_document(doc! { "code": code, "codeName": "TestError", "errmsg": "test error" }).unwrap();
+        Error::from(ErrorKind::Command(command_error))
+    }
+
+    #[test]
+    #[test_log::test]
+    fn ok_db_error_classification() {
+        let err = DbError::from(command_error(11000));
+        assert!(matches!(err, DbError::Duplicate(_)));
+        assert!(err.is_retryable());
+        let err = DbError::from(command_error(13));
+        assert!(matches!(err, DbError::Auth(_)));
+        assert!(err.is_retryable());
+        // not primary
+        let err = DbError::from(command_error(10107));
+        assert!(matches!(err, DbError::Transient(_)));
+        assert!(err.is_retryable());
+        let err = DbError::from(Error::from(std::io::Error::from(std::io::ErrorKind::ConnectionReset)));
+        assert!(matches!(err, DbError::Transient(_)));
+        assert!(err.is_retryable());
+        let err = DbError::from(Error::custom("unknown error"));
+        assert!(matches!(err, DbError::Other(_)));
+        assert!(err.is_retryable());
+        assert!(!DbError::NotFound("sensor".to_string()).is_retryable());
+    }
+}
@@ -0,0 +1,8 @@
+use thiserror::Error;
+
+// custom error, based on 'thiserror' library
+#[derive(Error, Debug)]
+pub enum FeatureError {
+    #[error("invalid feature registry error")]
+    InvalidRegistry(String),
+}
@@ -0,0 +1,10 @@
+use thiserror::Error;
+
+// custom error, based on 'thiserror' library
+#[derive(Error, Debug)]
+pub enum IndexError {
+    #[error("incompatible index error")]
+    IncompatibleIndex(String),
+    #[error("cannot manage indexes error")]
+    DbError(#[source] mongodb::error::Error),
+}
@@ -1,5 +1,7 @@
 use thiserror::Error;
 
+use crate::errors::db_error::DbError;
+
 // custom error, based on 'thiserror' library
 #[derive(Error, Debug)]
 pub enum MessageError {
@@ -7,6 +9,72 @@ pub enum MessageError {
     NoneValuePayloadError,
     #[error("Cannot parse message as JSON error")]
     MessageParsingError,
+    #[error("Topic not valid error")]
+    InvalidTopicError(String),
+    #[error("Topic family not supported error")]
+    UnknownFamilyError(String),
+    #[error("Feature name not supported error")]
+    UnknownFeatureError(String),
+    #[error("Value not valid for the feature error")]
+    InvalidValueError(String),
+    #[error("Unit not supported for the feature error")]
+    UnsupportedUnitError(String),
+    #[error("Reading of the same feature repeated in the message error")]
+    DuplicateReadingError(String),
+    #[error("Cannot find sensor to update error")]
+    SensorNotFoundError,
+    #[error("Sensor not registered yet, reading stored as pending error")]
+    SensorPendingError(String),
+    #[error("Reading older than the stored one error")]
+    StaleReadingError(String),
     #[error("Cannot update db with message error")]
-    UpdateDbError(mongodb::error::Error),
+    UpdateDbError(#[source] DbError),
+}
+
+impl MessageError {
+    // transient errors are caused by external systems (e.g. MongoDB temporary unavailable),
+    // so the same message could be processed successfully later
+    pub fn is_transient(&self) -> bool {
+        match self {
+            MessageError::NoneValuePayloadError
+            | MessageError::MessageParsingError
+            | MessageError::InvalidTopicError(_)
+            | MessageError::UnknownFamilyError(_)
+            | MessageError::UnknownFeatureError(_)
+            | MessageError::InvalidValueError(_)
+            | MessageError::UnsupportedUnitError(_)
+            | MessageError::DuplicateReadingError(_)
+            | MessageError::SensorNotFoundError
+            | MessageError::SensorPendingError(_)
+            | MessageError::StaleReadingError(_) => false,
+            MessageError::UpdateDbError(err) => err.is_retryable(),
+        }
+    }
+
+    // name of the variant, used to describe the error outside the application (e.g. in AMQP headers)
+    pub fn kind(&self) -> &'static str {
+        match self {
+            MessageError::NoneValuePayloadError => "NoneValuePayloadError",
+            MessageError::MessageParsingError => "MessageParsingError",
+            MessageError::InvalidTopicError(_) => "InvalidTopicError",
+            MessageError::UnknownFamilyError(_) => "UnknownFamilyError",
+            MessageError::UnknownFeatureError(_) => "UnknownFeatureError",
+            MessageError::InvalidValueError(_) => "InvalidValueError",
+            MessageError::UnsupportedUnitError(_) => "UnsupportedUnitError",
+            MessageError::DuplicateReadingError(_) => "DuplicateReadingError",
+            MessageError::SensorNotFoundError => "SensorNotFoundError",
+            MessageError::SensorPendingError(_) => "SensorPendingError",
+            MessageError::StaleReadingError(_) => "StaleReadingError",
+            MessageError::UpdateDbError(_) => "UpdateDbError",
+        }
+    }
+}
+
+impl From<DbError> for MessageError {
+    fn from(err: DbError) -> Self {
+        match err {
+            DbError::NotFound(_) => MessageError::SensorNotFoundError,
+            err => MessageError::UpdateDbError(err),
+        }
+    }
 }
@@ -1,2 +1,5 @@
 pub mod amqp_error;
+pub mod db_error;
+pub mod feature_error;
+pub mod index_error;
 pub mod message_error;
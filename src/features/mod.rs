@@ -0,0 +1,352 @@
+use std::collections::HashMap;
+use std::fs;
+
+use serde::Deserialize;
+use tracing::info;
+
+use crate::errors::feature_error::FeatureError;
+
+// Registry of the supported features (sensor types), so a new sensor type
+// only requires a change to the registry file and not a new release.
+// It's loaded from a JSON file, for example:
+// {
+//   "features": [
+//     { "name": "temperature", "valueType": "float", "unit": "°C", "min": -40, "max": 85, "storage": { "precision": 2, "history": true } },
+//     { "name": "motion", "valueType": "integer", "allowedValues": [0, 1], "storage": { "rollups": false, "retention": { "rawDays": 30 } } }
+//   ]
+// }
+#[derive(Debug, Clone, PartialEq)]
+pub struct FeatureRegistry {
+    features: HashMap<String, FeatureKind>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FeatureKind {
+    pub name: String,
+    pub value_type: ValueType,
+    #[serde(default)]
+    pub unit: Option<String>,
+    // valid range of values (inclusive)
+    #[serde(default)]
+    pub min: Option<f64>,
+    #[serde(default)]
+    pub max: Option<f64>,
+    // the only valid values of an integer feature (e.g. 0/1 for motion)
+    #[serde(default)]
+    pub allowed_values: Option<Vec<i64>>,
+    // max absolute change per second compared to the stored value, to discard implausible readings
+    #[serde(default)]
+    pub max_rate_of_change: Option<f64>,
+    #[serde(default)]
+    pub storage: StorageOptions,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ValueType {
+    Float,
+    Integer,
+    Boolean,
+    String,
+    Object,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StorageOptions {
+    // number of decimal digits stored for float values (all digits if not defined)
+    #[serde(default)]
+    pub precision: Option<u32>,
+    // store every reading in the history collection, besides the latest value of the sensor
+    #[serde(default = "default_history")]
+    pub history: bool,
+    // aggregate the numeric readings in hourly and daily rollups.
+    // Rollups are computed from the history, so they are enabled by default only if the history is stored
+    #[serde(default)]
+    pub rollups: Option<bool>,
+    #[serde(default)]
+    pub retention: RetentionOptions,
+}
+
+// days of data kept for every tier (history, hourly and daily rollups), forever if not defined
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RetentionOptions {
+    #[serde(default)]
+    pub raw_days: Option<u64>,
+    #[serde(default)]
+    pub hourly_days: Option<u64>,
+    #[serde(default)]
+    pub daily_days: Option<u64>,
+}
+
+// retention is enforced by TTL indexes, whose `expireAfterSeconds` is a 32-bit integer
+pub const MAX_RETENTION_DAYS: u64 = i32::MAX as u64 / 86_400;
+
+fn default_history() -> bool {
+    true
+}
+
+impl Default for StorageOptions {
+    fn default() -> Self {
+        Self {
+            precision: None,
+            history: default_history(),
+            rollups: None,
+            retention: RetentionOptions::default(),
+        }
+    }
+}
+
+impl StorageOptions {
+    pub fn rollups_enabled(&self) -> bool {
+        self.rollups.unwrap_or(self.history)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct FeatureRegistryFile {
+    features: Vec<FeatureKind>,
+}
+
+impl FeatureRegistry {
+    pub fn load(path: &str) -> Result<Self, FeatureError> {
+        info!(target: "app", "FeatureRegistry - loading features from file = {}", path);
+        let content: String = fs::read_to_string(path)
+            .map_err(|err| FeatureError::InvalidRegistry(format!("cannot read features file {}: {}", path, err)))?;
+        Self::parse(content.as_str())
+    }
+
+    pub fn parse(content: &str) -> Result<Self, FeatureError> {
+        let file: FeatureRegistryFile = serde_json::from_str(content)
+            .map_err(|err| FeatureError::InvalidRegistry(format!("cannot parse features: {}", err)))?;
+        Self::from_features(file.features)
+    }
+
+    fn from_features(features: Vec<FeatureKind>) -> Result<Self, FeatureError> {
+        let mut registry: HashMap<String, FeatureKind> = HashMap::with_capacity(features.len());
+        for feature in features {
+            if let (Some(min), Some(max)) = (feature.min, feature.max)
+                && min > max
+            {
+                return Err(FeatureError::InvalidRegistry(format!(
+                    "feature {} has min greater than max",
+                    feature.name
+                )));
+            }
+            if !feature.is_numeric()
+                && (feature.min.is_some() || feature.max.is_some() || feature.max_rate_of_change.is_some())
+            {
+                return Err(FeatureError::InvalidRegistry(format!(
+                    "feature {} has numeric rules, but its values are not numbers",
+                    feature.name
+                )));
+            }
+            if feature.value_type != ValueType::Integer && feature.allowed_values.is_some() {
+                return Err(FeatureError::InvalidRegistry(format!(
+                    "feature {} has allowed values, but its values are not integers",
+                    feature.name
+                )));
+            }
+            if feature.value_type != ValueType::Float && feature.storage.precision.is_some() {
+                return Err(FeatureError::InvalidRegistry(format!(
+                    "feature {} has precision, but its values are not floats",
+                    feature.name
+                )));
+            }
+            if feature.storage.rollups == Some(true) && !feature.storage.history {
+                return Err(FeatureError::InvalidRegistry(format!(
+                    "feature {} has rollups, but they are computed from the history, that is not stored",
+                    feature.name
+                )));
+            }
+            let retention: RetentionOptions = feature.storage.retention;
+            if (retention.raw_days.is_some() && !feature.storage.history)
+                || ((retention.hourly_days.is_some() || retention.daily_days.is_some())
+                    && !feature.storage.rollups_enabled())
+            {
+                return Err(FeatureError::InvalidRegistry(format!(
+                    "feature {} has a retention of data that is not stored",
+                    feature.name
+                )));
+            }
+            if [retention.raw_days, retention.hourly_days, retention.daily_days].contains(&Some(0)) {
+                return Err(FeatureError::InvalidRegistry(format!(
+                    "feature {} has a retention of 0 days",
+                    feature.name
+                )));
+            }
+            if [retention.raw_days, retention.hourly_days, retention.daily_days]
+                .iter()
+                .flatten()
+                .any(|days| *days > MAX_RETENTION_DAYS)
+            {
+                return Err(FeatureError::InvalidRegistry(format!(
+                    "feature {} has a retention longer than {} days",
+                    feature.name, MAX_RETENTION_DAYS
+                )));
+            }
+            if let Some(duplicate) = registry.insert(feature.name.clone(), feature) {
+                return Err(FeatureError::InvalidRegistry(format!(
+                    "feature {} defined more than once",
+                    duplicate.name
+                )));
+            }
+        }
+        Ok(Self { features: registry })
+    }
+
+    pub fn get(&self, name: &str) -> Option<&FeatureKind> {
+        self.features.get(name)
+    }
+
+    pub fn features(&self) -> impl Iterator<Item = &FeatureKind> {
+        self.features.values()
+    }
+}
+
+impl Default for FeatureRegistry {
+    // features supported before the registry was configurable, with plausible ranges
+    fn default() -> Self {
+        let features: Vec<FeatureKind> = vec![
+            FeatureKind::new("temperature", ValueType::Float, Some("°C")).range(Some(-40.0), Some(85.0)),
+            FeatureKind::new("humidity", ValueType::Float, Some("%")).range(Some(0.0), Some(100.0)),
+            FeatureKind::new("light", ValueType::Float, Some("lx")).range(Some(0.0), None),
+            FeatureKind::new("airpressure", ValueType::Float, Some("hPa")).range(Some(300.0), Some(1100.0)),
+            FeatureKind::new("motion", ValueType::Integer, None).allowed_values(vec![0, 1]),
+            FeatureKind::new("airquality", ValueType::Integer, None).range(Some(0.0), None),
+            FeatureKind::new("online", ValueType::Integer, None).allowed_values(vec![0, 1]),
+        ];
+        Self {
+            features: features
+                .into_iter()
+                .map(|feature| (feature.name.clone(), feature))
+                .collect(),
+        }
+    }
+}
+
+impl FeatureKind {
+    pub fn new(name: &str, value_type: ValueType, unit: Option<&str>) -> Self {
+        Self {
+            name: name.to_string(),
+            value_type,
+            unit: unit.map(String::from),
+            min: None,
+            max: None,
+            allowed_values: None,
+            max_rate_of_change: None,
+            storage: StorageOptions::default(),
+        }
+    }
+
+    // Use the builder pattern to init an optional param
+    pub fn range(mut self, min: Option<f64>, max: Option<f64>) -> Self {
+        self.min = min;
+        self.max = max;
+        self
+    }
+
+    // Use the builder pattern to init an optional param
+    pub fn allowed_values(mut self, allowed_values: Vec<i64>) -> Self {
+        self.allowed_values = Some(allowed_values);
+        self
+    }
+
+    pub fn is_numeric(&self) -> bool {
+        matches!(self.value_type, ValueType::Float | ValueType::Integer)
+    }
+
+    // round the value to the configured precision
+    pub fn round(&self, value: f64) -> f64 {
+        match self.storage.precision {
+            Some(precision) => {
+                let factor: f64 = 10_f64.powi(precision as i32);
+                (value * factor).round() / factor
+            }
+            None => value,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::features::{FeatureRegistry, RetentionOptions, ValueType};
+    use pretty_assertions::assert_eq;
+    use serde_json::json;
+
+    #[test]
+    #[test_log::test]
+    fn ok_parse_registry() {
+        let content = json!({
+            "features": [
+                { "name": "temperature", "valueType": "float", "unit": "°C", "min": -40, "max": 85, "storage": { "precision": 1 } },
+                { "name": "co2", "valueType": "integer", "unit": "ppm", "min": 0, "storage": { "history": false } },
+                { "name": "motion", "valueType": "integer", "storage": { "retention": { "rawDays": 30, "dailyDays": 24_855 } } }
+            ]
+        })
+        .to_string();
+        let registry = FeatureRegistry::parse(content.as_str()).unwrap();
+        let temperature = registry.get("temperature").unwrap();
+        assert_eq!(temperature.value_type, ValueType::Float);
+        assert_eq!(temperature.unit.as_deref(), Some("°C"));
+        assert_eq!(temperature.round(21.26), 21.3);
+        let co2 = registry.get("co2").unwrap();
+        assert_eq!(co2.value_type, ValueType::Integer);
+        assert_eq!(co2.max, None);
+        assert!(!co2.storage.history);
+        assert!(!co2.storage.rollups_enabled());
+        assert!(temperature.storage.history);
+        assert!(temperature.storage.rollups_enabled());
+        assert!(registry.get("humidity").is_none());
+        assert_eq!(temperature.storage.retention, RetentionOptions::default());
+        assert_eq!(
+            registry.get("motion").unwrap().storage.retention,
+            RetentionOptions {
+                raw_days: Some(30),
+                hourly_days: None,
+                daily_days: Some(24_855),
+            }
+        );
+    }
+
+    #[test]
+    #[test_log::test]
+    fn bad_registry() {
+        let content = json!({ "features": [{ "name": "humidity", "valueType": "float", "min": 100, "max": 0 }] });
+        assert!(FeatureRegistry::parse(content.to_string().as_str()).is_err());
+        let content =
+            json!({ "features": [{ "name": "motion", "valueType": "integer", "storage": { "precision": 2 } }] });
+        assert!(FeatureRegistry::parse(content.to_string().as_str()).is_err());
+        let content = json!({ "features": [
+            { "name": "light", "valueType": "float" },
+            { "name": "light", "valueType": "integer" }
+        ] });
+        assert!(FeatureRegistry::parse(content.to_string().as_str()).is_err());
+        let content = json!({ "features": [{ "name": "door", "valueType": "string", "max": 1 }] });
+        assert!(FeatureRegistry::parse(content.to_string().as_str()).is_err());
+        let content = json!({ "features": [{ "name": "light", "valueType": "float", "allowedValues": [0, 1] }] });
+        assert!(FeatureRegistry::parse(content.to_string().as_str()).is_err());
+        let content = json!({ "features": [{ "name": "light", "valueType": "double" }] });
+        assert!(FeatureRegistry::parse(content.to_string().as_str()).is_err());
+        let content = json!({ "features": [{ "name": "motion", "valueType": "integer", "storage": { "retention": { "rawDays": 0 } } }] });
+        assert!(FeatureRegistry::parse(content.to_string().as_str()).is_err());
+        let content = json!({ "features": [{ "name": "motion", "valueType": "integer", "storage": { "retention": { "dailyDays": 24_856 } } }] });
+        assert!(FeatureRegistry::parse(content.to_string().as_str()).is_err());
+        // rollups are computed from the history
+        let content = json!({ "features": [{ "name": "co2", "valueType": "integer", "storage": { "history": false, "rollups": true } }] });
+        assert!(FeatureRegistry::parse(content.to_string().as_str()).is_err());
+        let content = json!({ "features": [{ "name": "co2", "valueType": "integer", "storage": { "rollups": false, "retention": { "dailyDays": 365 } } }] });
+        assert!(FeatureRegistry::parse(content.to_string().as_str()).is_err());
+    }
+
+    #[test]
+    #[test_log::test]
+    fn ok_default_registry() {
+        let registry = FeatureRegistry::default();
+        assert_eq!(registry.get("temperature").unwrap().value_type, ValueType::Float);
+        assert_eq!(registry.get("online").unwrap().value_type, ValueType::Integer);
+        assert!(registry.get("unknowntype").is_none());
+    }
+}
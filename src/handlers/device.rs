@@ -0,0 +1,74 @@
+use std::future::Future;
+use std::marker::PhantomData;
+
+use mongodb::Database;
+use mongodb::bson::DateTime;
+use tracing::error;
+
+use crate::clock::ClockPolicy;
+use crate::errors::db_error::DbError;
+use crate::errors::message_error::MessageError;
+use crate::handlers::{HandlerFuture, MessageHandler};
+use crate::models::generic_message::GenericMessage;
+use crate::models::sensor::MessageReport;
+
+// Family of device messages (status, events, logs...), converted to documents and stored as they are received.
+// They never update sensors, so their report is always empty
+pub trait DeviceFamily: Send + Sync + 'static {
+    // first segment of the topic of the family
+    const FAMILY: &'static str;
+
+    type Document: Send + Sync;
+
+    fn to_document(
+        generic_msg: &GenericMessage,
+        clock: &ClockPolicy,
+        received_at: DateTime,
+    ) -> Result<Self::Document, MessageError>;
+
+    fn store(database: &Database, document: &Self::Document) -> impl Future<Output = Result<(), DbError>> + Send;
+}
+
+// handler of the messages of a device family
+pub struct DeviceHandler<F: DeviceFamily> {
+    clock: ClockPolicy,
+    family: PhantomData<F>,
+}
+
+impl<F: DeviceFamily> DeviceHandler<F> {
+    pub fn new(clock: ClockPolicy) -> Self {
+        Self {
+            clock,
+            family: PhantomData,
+        }
+    }
+
+    async fn process(
+        &self,
+        database: &Database,
+        generic_msg: &GenericMessage,
+        received_at: DateTime,
+    ) -> Result<MessageReport, MessageError> {
+        let document: F::Document = F::to_document(generic_msg, &self.clock, received_at)?;
+        F::store(database, &document).await.map_err(|err| {
+            error!(target: "app", "DeviceHandler - cannot store message of family = {} db, err = {:?}", F::FAMILY, err);
+            MessageError::from(err)
+        })?;
+        Ok(MessageReport::default())
+    }
+}
+
+impl<F: DeviceFamily> MessageHandler for DeviceHandler<F> {
+    fn family(&self) -> &'static str {
+        F::FAMILY
+    }
+
+    fn handle<'a>(
+        &'a self,
+        database: &'a Database,
+        generic_msg: &'a GenericMessage,
+        received_at: DateTime,
+    ) -> HandlerFuture<'a> {
+        Box::pin(self.process(database, generic_msg, received_at))
+    }
+}
@@ -0,0 +1,45 @@
+use std::future::Future;
+
+use mongodb::Database;
+use mongodb::bson::DateTime;
+
+use crate::clock::ClockPolicy;
+use crate::db::device::insert_device_event;
+use crate::errors::db_error::DbError;
+use crate::errors::message_error::MessageError;
+use crate::handlers::device::{DeviceFamily, DeviceHandler};
+use crate::models::device_event::DeviceEventDocument;
+use crate::models::generic_message::GenericMessage;
+
+pub const EVENTS_FAMILY: &str = "events";
+
+// `events/...` messages, with discrete events of a device (the name of the event is the last segment of the topic)
+pub type DeviceEventsHandler = DeviceHandler<DeviceEvents>;
+
+pub struct DeviceEvents;
+
+impl DeviceFamily for DeviceEvents {
+    const FAMILY: &'static str = EVENTS_FAMILY;
+
+    type Document = DeviceEventDocument;
+
+    fn to_document(
+        generic_msg: &GenericMessage,
+        clock: &ClockPolicy,
+        received_at: DateTime,
+    ) -> Result<DeviceEventDocument, MessageError> {
+        Ok(DeviceEventDocument {
+            apiToken: generic_msg.api_token.clone(),
+            deviceUuid: generic_msg.device_uuid.clone(),
+            channel: generic_msg.topic.channel.clone(),
+            name: generic_msg.topic.feature_name.clone(),
+            data: generic_msg.payload.get("data").cloned(),
+            observedAt: clock.observed_at(generic_msg.get_timestamp(), received_at),
+            receivedAt: received_at,
+        })
+    }
+
+    fn store(database: &Database, document: &DeviceEventDocument) -> impl Future<Output = Result<(), DbError>> + Send {
+        insert_device_event(database, document)
+    }
+}
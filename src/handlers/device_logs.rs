@@ -0,0 +1,57 @@
+use std::future::Future;
+
+use mongodb::Database;
+use mongodb::bson::DateTime;
+use tracing::error;
+
+use crate::clock::ClockPolicy;
+use crate::db::device::insert_device_logs;
+use crate::errors::db_error::DbError;
+use crate::errors::message_error::MessageError;
+use crate::handlers::device::{DeviceFamily, DeviceHandler};
+use crate::models::device_log::{DeviceLogDocument, LogLine, log_lines};
+use crate::models::generic_message::GenericMessage;
+
+pub const LOGS_FAMILY: &str = "logs";
+
+// `logs/...` messages, with log lines of a device (the source of the lines is the last segment of the topic)
+pub type DeviceLogsHandler = DeviceHandler<DeviceLogs>;
+
+pub struct DeviceLogs;
+
+impl DeviceFamily for DeviceLogs {
+    const FAMILY: &'static str = LOGS_FAMILY;
+
+    type Document = Vec<DeviceLogDocument>;
+
+    fn to_document(
+        generic_msg: &GenericMessage,
+        clock: &ClockPolicy,
+        received_at: DateTime,
+    ) -> Result<Vec<DeviceLogDocument>, MessageError> {
+        let lines: Vec<LogLine> = log_lines(&generic_msg.payload).map_err(|err| {
+            error!(target: "app", "DeviceLogs - cannot convert log lines. Error = {:?}", err);
+            MessageError::MessageParsingError
+        })?;
+        let message_timestamp: Option<DateTime> = generic_msg.get_timestamp();
+        Ok(lines
+            .into_iter()
+            .map(|line| DeviceLogDocument {
+                apiToken: generic_msg.api_token.clone(),
+                deviceUuid: generic_msg.device_uuid.clone(),
+                source: generic_msg.topic.feature_name.clone(),
+                observedAt: clock.observed_at(line.get_timestamp().or(message_timestamp), received_at),
+                receivedAt: received_at,
+                level: line.level,
+                message: line.message,
+            })
+            .collect())
+    }
+
+    fn store(
+        database: &Database,
+        document: &Vec<DeviceLogDocument>,
+    ) -> impl Future<Output = Result<(), DbError>> + Send {
+        insert_device_logs(database, document)
+    }
+}
@@ -0,0 +1,48 @@
+use std::future::Future;
+
+use mongodb::Database;
+use mongodb::bson::DateTime;
+use tracing::error;
+
+use crate::clock::ClockPolicy;
+use crate::db::device::upsert_device_status;
+use crate::errors::db_error::DbError;
+use crate::errors::message_error::MessageError;
+use crate::handlers::device::{DeviceFamily, DeviceHandler};
+use crate::models::device_status::{DeviceStatusDocument, DeviceStatusPayload};
+use crate::models::generic_message::GenericMessage;
+
+pub const STATUS_FAMILY: &str = "status";
+
+// `status/...` messages, with the health of a device (online, signal strength, uptime...)
+pub type DeviceStatusHandler = DeviceHandler<DeviceStatus>;
+
+pub struct DeviceStatus;
+
+impl DeviceFamily for DeviceStatus {
+    const FAMILY: &'static str = STATUS_FAMILY;
+
+    type Document = DeviceStatusDocument;
+
+    fn to_document(
+        generic_msg: &GenericMessage,
+        clock: &ClockPolicy,
+        received_at: DateTime,
+    ) -> Result<DeviceStatusDocument, MessageError> {
+        let payload: DeviceStatusPayload = serde_json::from_value(generic_msg.payload.clone()).map_err(|err| {
+            error!(target: "app", "DeviceStatus - cannot convert status payload. Error = {:?}", err);
+            MessageError::MessageParsingError
+        })?;
+        Ok(DeviceStatusDocument::new(
+            generic_msg.api_token.as_str(),
+            generic_msg.device_uuid.as_str(),
+            payload,
+            clock.observed_at(generic_msg.get_timestamp(), received_at),
+            received_at,
+        ))
+    }
+
+    fn store(database: &Database, document: &DeviceStatusDocument) -> impl Future<Output = Result<(), DbError>> + Send {
+        upsert_device_status(database, document)
+    }
+}
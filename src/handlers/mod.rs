@@ -0,0 +1,120 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use mongodb::Database;
+use mongodb::bson::DateTime;
+use tracing::info;
+
+use crate::clock::ClockPolicy;
+use crate::db::bulk::BulkWriter;
+use crate::errors::message_error::MessageError;
+use crate::features::FeatureRegistry;
+use crate::handlers::device_events::DeviceEventsHandler;
+use crate::handlers::device_logs::DeviceLogsHandler;
+use crate::handlers::device_status::DeviceStatusHandler;
+use crate::handlers::sensors::SensorsHandler;
+use crate::models::generic_message::GenericMessage;
+use crate::models::sensor::MessageReport;
+use crate::rollups::RollupCalendar;
+use crate::validation::Validator;
+
+pub mod device;
+pub mod device_events;
+pub mod device_logs;
+pub mod device_status;
+pub mod sensors;
+
+pub type HandlerFuture<'a> = Pin<Box<dyn Future<Output = Result<MessageReport, MessageError>> + Send + 'a>>;
+
+// Handler of the messages of a topic family (e.g. `sensors/...`).
+// Every family has its own model and collection.
+pub trait MessageHandler: Send + Sync {
+    // first segment of the topic handled by this handler
+    fn family(&self) -> &'static str;
+
+    // process a message received at `received_at`.
+    // Only sensor updates are returned in the report, because they are published as events
+    fn handle<'a>(
+        &'a self,
+        database: &'a Database,
+        generic_msg: &'a GenericMessage,
+        received_at: DateTime,
+    ) -> HandlerFuture<'a>;
+}
+
+// handlers of all supported topic families, shared by all workers
+#[derive(Default)]
+pub struct Handlers {
+    handlers: HashMap<&'static str, Box<dyn MessageHandler>>,
+}
+
+impl Handlers {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // handlers of all families supported by the consumer
+    pub fn with_defaults(
+        registry: Arc<FeatureRegistry>,
+        validator: Arc<Validator>,
+        clock: ClockPolicy,
+        calendar: RollupCalendar,
+        writer: Option<BulkWriter>,
+    ) -> Self {
+        Self::new()
+            .register(Box::new(
+                SensorsHandler::new(registry, validator, clock, calendar).bulk_writer(writer),
+            ))
+            .register(Box::new(DeviceStatusHandler::new(clock)))
+            .register(Box::new(DeviceEventsHandler::new(clock)))
+            .register(Box::new(DeviceLogsHandler::new(clock)))
+    }
+
+    // Use the builder pattern to init an optional param
+    pub fn register(mut self, handler: Box<dyn MessageHandler>) -> Self {
+        info!(target: "app", "Handlers - registering handler of family = {}", handler.family());
+        self.handlers.insert(handler.family(), handler);
+        self
+    }
+
+    pub fn get(&self, family: &str) -> Option<&dyn MessageHandler> {
+        self.handlers.get(family).map(|handler| handler.as_ref())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::clock::ClockPolicy;
+    use crate::features::FeatureRegistry;
+    use crate::handlers::Handlers;
+    use crate::rollups::RollupCalendar;
+    use crate::validation::Validator;
+    use pretty_assertions::assert_eq;
+    use std::sync::Arc;
+
+    #[test]
+    #[test_log::test]
+    fn ok_default_handlers() {
+        let clock = ClockPolicy {
+            max_skew_ms: 60_000,
+            max_age_ms: 3_600_000,
+        };
+        let calendar = RollupCalendar {
+            timezone: chrono_tz::UTC,
+        };
+        let handlers = Handlers::with_defaults(
+            Arc::new(FeatureRegistry::default()),
+            Arc::new(Validator::new()),
+            clock,
+            calendar,
+            None,
+        );
+        for family in ["sensors", "status", "events", "logs"] {
+            assert_eq!(handlers.get(family).unwrap().family(), family);
+        }
+        assert!(handlers.get("unknown").is_none());
+        assert!(Handlers::new().get("sensors").is_none());
+    }
+}
@@ -0,0 +1,627 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+use mongodb::Database;
+use mongodb::bson::DateTime;
+use tracing::{debug, error, info, warn};
+
+use crate::clock::ClockPolicy;
+use crate::db::bulk::BulkWriter;
+use crate::db::history::insert_history;
+use crate::db::pending::upsert_pending_sensor;
+use crate::db::rollup::update_rollups;
+use crate::db::sensor::{
+    SensorWrite, UpdateOutcome, find_sensor, find_sensors, get_previous_reading, is_known_profile, is_newer,
+    previous_reading, update_sensor, update_sensors,
+};
+use crate::errors::db_error::DbError;
+use crate::errors::message_error::MessageError;
+use crate::features::{FeatureKind, FeatureRegistry};
+use crate::handlers::{HandlerFuture, MessageHandler};
+use crate::models::generic_message::{BatchEntry, GenericMessage, Reading};
+use crate::models::pending_sensor::PendingSensorDocument;
+use crate::models::sensor::{MessageReport, ReadingFailure, SensorDocument, SensorUpdate};
+use crate::models::sensor_history::{SensorHistoryDocument, SensorHistoryMeta};
+use crate::models::sensor_value::{Measurement, SensorValue};
+use crate::rollups::RollupCalendar;
+use crate::units::normalize;
+use crate::validation::{PreviousReading, Validator};
+
+pub const SENSORS_FAMILY: &str = "sensors";
+
+// `sensors/...` messages, with the readings of the sensors of a device
+pub struct SensorsHandler {
+    registry: Arc<FeatureRegistry>,
+    validator: Arc<Validator>,
+    clock: ClockPolicy,
+    calendar: RollupCalendar,
+    writer: Option<BulkWriter>,
+}
+
+impl SensorsHandler {
+    pub fn new(
+        registry: Arc<FeatureRegistry>,
+        validator: Arc<Validator>,
+        clock: ClockPolicy,
+        calendar: RollupCalendar,
+    ) -> Self {
+        Self {
+            registry,
+            validator,
+            clock,
+            calendar,
+            writer: None,
+        }
+    }
+
+    // Use the builder pattern to init an optional param.
+    // Updates of single sensors are written in bulk with the updates of other deliveries
+    pub fn bulk_writer(mut self, writer: Option<BulkWriter>) -> Self {
+        self.writer = writer;
+        self
+    }
+
+    async fn process(
+        &self,
+        database: &Database,
+        generic_msg: &GenericMessage,
+        received_at: DateTime,
+    ) -> Result<MessageReport, MessageError> {
+        if let Some(readings) = generic_msg.readings() {
+            let readings: Vec<Reading> = readings.map_err(|err| {
+                error!(target: "app", "SensorsHandler - cannot convert readings. Error = {:?}", err);
+                MessageError::MessageParsingError
+            })?;
+            return self
+                .process_readings(generic_msg, readings, database, received_at)
+                .await;
+        }
+        if let Some(batch) = generic_msg.batch() {
+            let batch: Vec<BatchEntry> = batch.map_err(|err| {
+                error!(target: "app", "SensorsHandler - cannot convert batch. Error = {:?}", err);
+                MessageError::MessageParsingError
+            })?;
+            return self.process_batch(generic_msg, batch, database, received_at).await;
+        }
+        self.process_reading(
+            generic_msg,
+            database,
+            self.clock.observed_at(generic_msg.get_timestamp(), received_at),
+            received_at,
+        )
+        .await
+        .map(|update| MessageReport {
+            updates: vec![update],
+            failures: vec![],
+        })
+    }
+
+    // message with a single reading
+    async fn process_reading(
+        &self,
+        generic_msg: &GenericMessage,
+        database: &Database,
+        observed_at: DateTime,
+        received_at: DateTime,
+    ) -> Result<SensorUpdate, MessageError> {
+        let registry: &FeatureRegistry = &self.registry;
+        let validator: &Validator = &self.validator;
+        let feature: &FeatureKind = get_feature(registry, generic_msg.topic.feature_name.as_str())?;
+        let value_opt: Option<SensorValue> = generic_msg.get_value(feature);
+        debug!(target: "app", "process_reading - value_opt = {:?}", &value_opt);
+        let measurement: Measurement = to_measurement(feature, value_opt, generic_msg.get_unit())?;
+        // the stored value is read only if required to check the rate of change.
+        // It cannot be checked if the stored value is newer than the reading
+        let previous: Option<PreviousReading> = match feature.max_rate_of_change {
+            Some(_) => get_previous_reading(database, generic_msg, observed_at)
+                .await
+                .map_err(|err| {
+                    error!(target: "app", "process_reading - cannot read sensor db, err = {:?}", err);
+                    MessageError::from(err)
+                })?,
+            None => None,
+        }
+        .filter(|previous| previous.elapsed_secs >= 0.0);
+        validator.validate(&generic_msg.device_uuid, feature, &measurement.value, previous.as_ref())?;
+        // history is written first, so the value of the sensor is never ahead of its history
+        // and a delivery requeued because of the history doesn't update the sensor twice.
+        // Readings older than the stored value are part of the history anyway
+        if feature.storage.history {
+            let meta = SensorHistoryMeta::new(
+                generic_msg.api_token.as_str(),
+                generic_msg.device_uuid.as_str(),
+                generic_msg.feature_uuid.as_str(),
+                feature.name.as_str(),
+            );
+            let history_doc = SensorHistoryDocument::new(meta, &measurement, observed_at, received_at);
+            self.store_history(database, &[history_doc]).await?;
+        }
+        match self
+            .update_sensor(database, generic_msg, &measurement, observed_at)
+            .await
+        {
+            Ok(UpdateOutcome::Updated(update)) => {
+                debug!(target: "app", "process_reading - sensor db updated with result = {:?}", update.current);
+                Ok(*update)
+            }
+            Ok(UpdateOutcome::Stale) => Err(stale_reading(observed_at)),
+            Err(DbError::NotFound(_)) => {
+                let pending_doc = PendingSensorDocument::new(
+                    generic_msg.api_token.as_str(),
+                    generic_msg.device_uuid.as_str(),
+                    generic_msg.feature_uuid.as_str(),
+                    feature.name.as_str(),
+                    &measurement,
+                    observed_at,
+                    received_at,
+                );
+                Err(store_pending(database, &pending_doc).await)
+            }
+            Err(err) => {
+                error!(target: "app", "process_reading - cannot update sensor db, err = {:?}", err);
+                Err(MessageError::from(err))
+            }
+        }
+    }
+
+    // message with multiple readings of the same device.
+    // Every reading is validated on its own and the valid ones are stored in a single batch,
+    // so the message fails only if no reading can be stored or the batch cannot be written
+    async fn process_readings(
+        &self,
+        generic_msg: &GenericMessage,
+        readings: Vec<Reading>,
+        database: &Database,
+        received_at: DateTime,
+    ) -> Result<MessageReport, MessageError> {
+        let registry: &FeatureRegistry = &self.registry;
+        let validator: &Validator = &self.validator;
+        // readings without timestamp are observed at the time of the message
+        let message_timestamp: Option<DateTime> = generic_msg.get_timestamp();
+        debug!(target: "app", "process_readings - {} readings received", readings.len());
+        let feature_uuids: Vec<&str> = readings.iter().map(|reading| reading.feature_uuid.as_str()).collect();
+        let mut sensor_docs: HashMap<String, SensorDocument> = find_sensors(database, generic_msg, &feature_uuids)
+            .await
+            .map_err(|err| {
+                error!(target: "app", "process_readings - cannot read sensors db, err = {:?}", err);
+                MessageError::from(err)
+            })?;
+
+        // sensors found before preparing the readings, because prepared sensors are removed from `sensor_docs`
+        let registered: HashSet<String> = sensor_docs.keys().cloned().collect();
+
+        let mut writes: Vec<SensorWrite> = Vec::with_capacity(readings.len());
+        let mut failures: Vec<ReadingFailure> = Vec::new();
+        let mut seen: HashSet<String> = HashSet::with_capacity(readings.len());
+        for reading in readings {
+            // a sensor can be updated at most once per message, so repeated features make the message invalid
+            if !seen.insert(reading.feature_uuid.clone()) {
+                error!(target: "app", "process_readings - reading of feature_uuid = {} discarded, because it's repeated", reading.feature_uuid);
+                failures.push(ReadingFailure {
+                    error: MessageError::DuplicateReadingError(format!(
+                        "feature_uuid {} appears more than once",
+                        reading.feature_uuid
+                    )),
+                    feature_uuid: reading.feature_uuid,
+                    feature_name: reading.feature_name,
+                });
+                continue;
+            }
+            let observed_at: DateTime = self
+                .clock
+                .observed_at(reading.get_timestamp().or(message_timestamp), received_at);
+            if !registered.contains(reading.feature_uuid.as_str()) {
+                let error: MessageError =
+                    match prepare_pending(generic_msg, &reading, registry, validator, observed_at, received_at) {
+                        Ok(pending_doc) => store_pending(database, &pending_doc).await,
+                        Err(error) => error,
+                    };
+                // sensors are written after the loop and pending upserts are idempotent, so the message can be retried
+                if error.is_transient() {
+                    return Err(error);
+                }
+                failures.push(ReadingFailure {
+                    feature_uuid: reading.feature_uuid,
+                    feature_name: reading.feature_name,
+                    error,
+                });
+                continue;
+            }
+            match prepare_reading(
+                generic_msg,
+                &reading,
+                &mut sensor_docs,
+                registry,
+                validator,
+                observed_at,
+            ) {
+                Ok(write) => writes.push(write),
+                Err(error) => {
+                    error!(target: "app", "process_readings - reading of feature_uuid = {} discarded, err = {:?}", reading.feature_uuid, error);
+                    failures.push(ReadingFailure {
+                        feature_uuid: reading.feature_uuid,
+                        feature_name: reading.feature_name,
+                        error,
+                    });
+                }
+            }
+        }
+        if writes.is_empty() {
+            return Err(first_failure(failures));
+        }
+
+        let outcomes: Vec<Result<UpdateOutcome, DbError>> =
+            self.update_sensors(database, &writes).await.map_err(|err| {
+                error!(target: "app", "process_readings - cannot update sensors db, err = {:?}", err);
+                MessageError::from(err)
+            })?;
+        let mut updates: Vec<SensorUpdate> = Vec::with_capacity(outcomes.len());
+        let mut history_docs: Vec<SensorHistoryDocument> = Vec::with_capacity(writes.len());
+        for (write, outcome) in writes.into_iter().zip(outcomes) {
+            // the sensor has been deleted after `find_sensors`, so the reading isn't stored at all
+            let outcome: UpdateOutcome = match outcome {
+                Ok(outcome) => outcome,
+                Err(err) => {
+                    failures.push(ReadingFailure {
+                        feature_uuid: write.sensor_doc.featureUuid,
+                        feature_name: write.sensor_doc.featureName,
+                        error: MessageError::from(err),
+                    });
+                    continue;
+                }
+            };
+            // readings older than the stored value are part of the history anyway
+            if registry
+                .get(write.sensor_doc.featureName.as_str())
+                .is_some_and(|feature| feature.storage.history)
+            {
+                history_docs.push(SensorHistoryDocument::new(
+                    SensorHistoryMeta::from_sensor(&write.sensor_doc),
+                    &write.measurement,
+                    write.observed_at,
+                    received_at,
+                ));
+            }
+            match outcome {
+                UpdateOutcome::Updated(update) => updates.push(*update),
+                UpdateOutcome::Stale => failures.push(ReadingFailure {
+                    feature_uuid: write.sensor_doc.featureUuid,
+                    feature_name: write.sensor_doc.featureName,
+                    error: stale_reading(write.observed_at),
+                }),
+            }
+        }
+        self.store_history(database, &history_docs).await?;
+        debug!(target: "app", "process_readings - {} sensors updated, {} readings discarded", updates.len(), failures.len());
+        Ok(MessageReport { updates, failures })
+    }
+
+    // batch of readings of the same sensor, buffered by the device while it was offline.
+    // Every valid reading is stored in the history, while only the latest one updates the value of the sensor
+    async fn process_batch(
+        &self,
+        generic_msg: &GenericMessage,
+        batch: Vec<BatchEntry>,
+        database: &Database,
+        received_at: DateTime,
+    ) -> Result<MessageReport, MessageError> {
+        let registry: &FeatureRegistry = &self.registry;
+        let validator: &Validator = &self.validator;
+        debug!(target: "app", "process_batch - {} readings received", batch.len());
+        let feature: &FeatureKind = get_feature(registry, generic_msg.topic.feature_name.as_str())?;
+        let sensor_doc: Option<SensorDocument> = find_sensor(database, generic_msg).await.map_err(|err| {
+            error!(target: "app", "process_batch - cannot read sensor db, err = {:?}", err);
+            MessageError::from(err)
+        })?;
+
+        // entries without a trusted timestamp are observed at the receive time, so they are flagged as untimed
+        let mut entries: Vec<(DateTime, bool, BatchEntry)> = batch
+            .into_iter()
+            .map(
+                |entry| match self.clock.device_timestamp(entry.get_timestamp(), received_at) {
+                    Some(observed_at) => (observed_at, false, entry),
+                    None => (received_at, true, entry),
+                },
+            )
+            .collect();
+        // oldest first, so the rate of change of every reading is checked against the previous one
+        entries.sort_by_key(|(observed_at, _, _)| *observed_at);
+
+        let mut accepted: Vec<(Measurement, DateTime, bool)> = Vec::with_capacity(entries.len());
+        let mut failures: Vec<ReadingFailure> = Vec::new();
+        for (observed_at, untimed, entry) in entries {
+            let unit: Option<&str> = entry.unit.as_deref().or(generic_msg.get_unit());
+            let result = to_measurement(feature, entry.get_value(feature), unit).and_then(|measurement| {
+                let previous: Option<PreviousReading> = match (feature.max_rate_of_change, accepted.last()) {
+                    (None, _) => None,
+                    (Some(_), Some((latest_measurement, latest_observed_at, _))) => Some(PreviousReading {
+                        value: latest_measurement.value.clone(),
+                        elapsed_secs: (observed_at.timestamp_millis() - latest_observed_at.timestamp_millis()) as f64
+                            / 1000.0,
+                    }),
+                    (Some(_), None) => sensor_doc
+                        .as_ref()
+                        .filter(|sensor_doc| !is_newer(sensor_doc, observed_at))
+                        .map(|sensor_doc| previous_reading(sensor_doc, observed_at)),
+                };
+                validator.validate(&generic_msg.device_uuid, feature, &measurement.value, previous.as_ref())?;
+                Ok(measurement)
+            });
+            match result {
+                Ok(measurement) => accepted.push((measurement, observed_at, untimed)),
+                Err(error) => {
+                    error!(target: "app", "process_batch - reading observed at = {} discarded, err = {:?}", observed_at, error);
+                    failures.push(ReadingFailure {
+                        feature_uuid: generic_msg.feature_uuid.clone(),
+                        feature_name: feature.name.clone(),
+                        error,
+                    });
+                }
+            }
+        }
+        // untimed entries would always look like the latest reading, even if they have been buffered for a long time,
+        // so they update the sensor only if no entry has a trusted timestamp
+        let Some((measurement, observed_at, _)) = accepted
+            .iter()
+            .rev()
+            .find(|(_, _, untimed)| !untimed)
+            .or(accepted.last())
+            .cloned()
+        else {
+            return Err(first_failure(failures));
+        };
+        let meta: SensorHistoryMeta = match sensor_doc.as_ref() {
+            Some(sensor_doc) => SensorHistoryMeta::from_sensor(sensor_doc),
+            None => SensorHistoryMeta::new(
+                generic_msg.api_token.as_str(),
+                generic_msg.device_uuid.as_str(),
+                generic_msg.feature_uuid.as_str(),
+                feature.name.as_str(),
+            ),
+        };
+        let history_docs: Vec<SensorHistoryDocument> = accepted
+            .iter()
+            .map(|(measurement, observed_at, _)| {
+                SensorHistoryDocument::new(meta.clone(), measurement, *observed_at, received_at)
+            })
+            .collect();
+
+        // only the latest reading is kept until the sensor is registered, but all readings are part of the history
+        if sensor_doc.is_none() {
+            let pending_doc = PendingSensorDocument::new(
+                generic_msg.api_token.as_str(),
+                generic_msg.device_uuid.as_str(),
+                generic_msg.feature_uuid.as_str(),
+                feature.name.as_str(),
+                &measurement,
+                observed_at,
+                received_at,
+            );
+            // readings of unknown profiles are discarded by `store_pending`, so they never reach the history
+            let known_profile: bool = is_known_profile(database, generic_msg.api_token.as_str())
+                .await
+                .map_err(|err| {
+                    error!(target: "app", "process_batch - cannot read sensors db, err = {:?}", err);
+                    MessageError::from(err)
+                })?;
+            if feature.storage.history && known_profile {
+                self.store_history(database, &history_docs).await?;
+                info!(target: "app", "process_batch - {} readings of the unregistered sensor stored in the history", history_docs.len());
+            }
+            return Err(store_pending(database, &pending_doc).await);
+        }
+
+        // history is written first, so the value of the sensor is never ahead of its history
+        if feature.storage.history {
+            self.store_history(database, &history_docs).await?;
+        }
+        let mut updates: Vec<SensorUpdate> = Vec::with_capacity(1);
+        match self
+            .update_sensor(database, generic_msg, &measurement, observed_at)
+            .await
+        {
+            Ok(UpdateOutcome::Updated(update)) => updates.push(*update),
+            // readings are part of the history anyway, even if the sensor already has a newer value
+            Ok(UpdateOutcome::Stale) => {
+                info!(target: "app", "process_batch - sensor not updated, because it has a value newer than {}", observed_at);
+            }
+            Err(err) => {
+                error!(target: "app", "process_batch - cannot update sensor db, err = {:?}", err);
+                return Err(MessageError::from(err));
+            }
+        }
+        debug!(target: "app", "process_batch - {} readings stored, {} readings discarded", history_docs.len(), failures.len());
+        Ok(MessageReport { updates, failures })
+    }
+
+    // the update is written in bulk with the updates of other deliveries, if bulk writes are enabled
+    async fn update_sensor(
+        &self,
+        database: &Database,
+        generic_msg: &GenericMessage,
+        measurement: &Measurement,
+        observed_at: DateTime,
+    ) -> Result<UpdateOutcome, DbError> {
+        match self.writer.as_ref() {
+            Some(writer) => writer.update_sensor(generic_msg, measurement, observed_at).await,
+            None => update_sensor(database, generic_msg, measurement, observed_at).await,
+        }
+    }
+
+    // the updates are written in bulk with the updates of other deliveries, if bulk writes are enabled
+    async fn update_sensors(
+        &self,
+        database: &Database,
+        writes: &[SensorWrite],
+    ) -> Result<Vec<Result<UpdateOutcome, DbError>>, DbError> {
+        match self.writer.as_ref() {
+            Some(writer) => Ok(writer.update_sensors(writes).await),
+            None => update_sensors(database, writes).await,
+        }
+    }
+
+    // readings are stored in the history first, then only the ones inserted for the first time
+    // are added to the rollups, so a message processed again is never counted twice
+    async fn store_history(
+        &self,
+        database: &Database,
+        history_docs: &[SensorHistoryDocument],
+    ) -> Result<(), MessageError> {
+        let inserted_docs: Vec<SensorHistoryDocument> =
+            insert_history(database, history_docs).await.map_err(|err| {
+                error!(target: "app", "store_history - cannot insert history db, err = {:?}", err);
+                MessageError::from(err)
+            })?;
+        let rollup_docs: Vec<SensorHistoryDocument> = inserted_docs
+            .into_iter()
+            .filter(|history_doc| {
+                self.registry
+                    .get(history_doc.meta.featureName.as_str())
+                    .is_some_and(|feature| feature.storage.rollups_enabled())
+            })
+            .collect();
+        update_rollups(database, &self.calendar, &rollup_docs)
+            .await
+            .map_err(|err| {
+                error!(target: "app", "store_history - cannot update rollups db, err = {:?}", err);
+                MessageError::from(err)
+            })
+    }
+}
+
+impl MessageHandler for SensorsHandler {
+    fn family(&self) -> &'static str {
+        SENSORS_FAMILY
+    }
+
+    fn handle<'a>(
+        &'a self,
+        database: &'a Database,
+        generic_msg: &'a GenericMessage,
+        received_at: DateTime,
+    ) -> HandlerFuture<'a> {
+        Box::pin(self.process(database, generic_msg, received_at))
+    }
+}
+
+// validate a reading of a sensor that isn't registered yet, returning the reading to store until it's registered.
+// The rate of change cannot be checked, because there isn't a stored value
+fn prepare_pending(
+    generic_msg: &GenericMessage,
+    reading: &Reading,
+    registry: &FeatureRegistry,
+    validator: &Validator,
+    observed_at: DateTime,
+    received_at: DateTime,
+) -> Result<PendingSensorDocument, MessageError> {
+    let feature: &FeatureKind = get_feature(registry, reading.feature_name.as_str())?;
+    let measurement: Measurement = to_measurement(feature, reading.get_value(feature), reading.unit.as_deref())?;
+    validator.validate(&generic_msg.device_uuid, feature, &measurement.value, None)?;
+    Ok(PendingSensorDocument::new(
+        generic_msg.api_token.as_str(),
+        generic_msg.device_uuid.as_str(),
+        reading.feature_uuid.as_str(),
+        feature.name.as_str(),
+        &measurement,
+        observed_at,
+        received_at,
+    ))
+}
+
+// store the reading of a sensor that isn't registered yet, so it can be back-filled later (see `backfill_pending_sensors`).
+// The message is acked, because the reading has been stored.
+// Readings of unknown profiles (e.g. wrong or revoked api tokens) are rejected, because they would never be registered
+async fn store_pending(database: &Database, pending_doc: &PendingSensorDocument) -> MessageError {
+    match is_known_profile(database, pending_doc.apiToken.as_str()).await {
+        Ok(true) => {}
+        Ok(false) => {
+            warn!(target: "app", "store_pending - sensor with feature_uuid = {} discarded, because its profile has no sensors", pending_doc.featureUuid);
+            return MessageError::SensorNotFoundError;
+        }
+        Err(err) => {
+            error!(target: "app", "store_pending - cannot read sensors db, err = {:?}", err);
+            return MessageError::from(err);
+        }
+    }
+    match upsert_pending_sensor(database, pending_doc).await {
+        Ok(()) => {
+            info!(target: "app", "store_pending - sensor with feature_uuid = {} not registered yet, reading stored as pending", pending_doc.featureUuid);
+            MessageError::SensorPendingError(format!("sensor {} is not registered yet", pending_doc.featureUuid))
+        }
+        Err(err) => {
+            error!(target: "app", "store_pending - cannot upsert pending sensors db, err = {:?}", err);
+            MessageError::from(err)
+        }
+    }
+}
+
+// validate a reading of a message with multiple readings, returning the sensor to update with its new value
+fn prepare_reading(
+    generic_msg: &GenericMessage,
+    reading: &Reading,
+    sensor_docs: &mut HashMap<String, SensorDocument>,
+    registry: &FeatureRegistry,
+    validator: &Validator,
+    observed_at: DateTime,
+) -> Result<SensorWrite, MessageError> {
+    let feature: &FeatureKind = get_feature(registry, reading.feature_name.as_str())?;
+    let measurement: Measurement = to_measurement(feature, reading.get_value(feature), reading.unit.as_deref())?;
+    // repeated features have already been discarded by `process_readings`
+    let sensor_doc: SensorDocument = sensor_docs
+        .remove(reading.feature_uuid.as_str())
+        .ok_or(MessageError::SensorNotFoundError)?;
+    // the sensor must belong to the feature of the reading, otherwise its value would be stored with the wrong type
+    if sensor_doc.featureName != feature.name {
+        return Err(MessageError::InvalidValueError(format!(
+            "sensor {} is {}, not {}",
+            reading.feature_uuid, sensor_doc.featureName, feature.name
+        )));
+    }
+    // a reading older than the stored value cannot be checked against it, and it will only be part of the history
+    let previous: Option<PreviousReading> = feature
+        .max_rate_of_change
+        .filter(|_| !is_newer(&sensor_doc, observed_at))
+        .map(|_| previous_reading(&sensor_doc, observed_at));
+    validator.validate(&generic_msg.device_uuid, feature, &measurement.value, previous.as_ref())?;
+    Ok(SensorWrite {
+        sensor_doc,
+        measurement,
+        observed_at,
+    })
+}
+
+// error of a message without valid readings
+fn first_failure(failures: Vec<ReadingFailure>) -> MessageError {
+    match failures.into_iter().next() {
+        Some(failure) => failure.error,
+        None => MessageError::NoneValuePayloadError,
+    }
+}
+
+fn stale_reading(observed_at: DateTime) -> MessageError {
+    info!(target: "app", "stale_reading - reading observed at = {} discarded, because the sensor has a newer value", observed_at);
+    MessageError::StaleReadingError(format!("a value newer than {} is already stored", observed_at))
+}
+
+fn get_feature<'a>(registry: &'a FeatureRegistry, feature_name: &str) -> Result<&'a FeatureKind, MessageError> {
+    registry.get(feature_name).ok_or_else(|| {
+        error!(target: "app", "get_feature - cannot recognize Message payload type = {}", feature_name);
+        MessageError::UnknownFeatureError(feature_name.to_string())
+    })
+}
+
+// convert the value to the canonical unit of the feature, before checking its range
+fn to_measurement(
+    feature: &FeatureKind,
+    value_opt: Option<SensorValue>,
+    unit: Option<&str>,
+) -> Result<Measurement, MessageError> {
+    let Some(value) = value_opt else {
+        error!(target: "app", "to_measurement - cannot update sensor, because value_opt is None");
+        return Err(MessageError::NoneValuePayloadError);
+    };
+    normalize(feature, value, unit).map_err(|reason| {
+        error!(target: "app", "to_measurement - cannot normalize value, reason = {}", reason);
+        MessageError::UnsupportedUnitError(reason)
+    })
+}
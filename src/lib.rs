@@ -1,5 +1,12 @@
 pub mod amqp;
+pub mod clock;
 pub mod config;
 pub mod db;
 pub mod errors;
+pub mod features;
+pub mod handlers;
 pub mod models;
+pub mod rollups;
+pub mod units;
+pub mod validation;
+pub mod workers;
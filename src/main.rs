@@ -1,16 +1,45 @@
+use std::sync::Arc;
+use std::time::Duration;
+
 use futures_lite::StreamExt;
 use lapin::message::Delivery;
 use mongodb::Database;
-use mongodb::bson::Bson;
+use mongodb::bson::DateTime;
+use mongodb::options::TimeseriesGranularity;
+use tokio::signal;
+use tokio::sync::watch;
+use tokio::time::timeout;
 use tracing::{debug, error, info};
 
-use consumer::amqp::{AmqpClient, read_message};
+use consumer::amqp::acks::AckBatchConfig;
+use consumer::amqp::dead_letter::DeadLetterConfig;
+use consumer::amqp::events::{EventPublisher, EventsConfig};
+use consumer::amqp::retry::RetryConfig;
+use consumer::amqp::settler::DeliverySettler;
+use consumer::amqp::supervisor::{ReconnectPolicy, reconnect};
+use consumer::amqp::tls::TlsConfig;
+use consumer::amqp::topology::Topology;
+use consumer::amqp::{
+    AckDecision, AmqpClient, ConnectionStatus, prefetch_count, read_message, received_at, stamp_received_at,
+};
+use consumer::clock::ClockPolicy;
 use consumer::config::{Env, init};
+use consumer::db::bulk::{BulkWriteConfig, BulkWriter, SubmissionNotifier};
 use consumer::db::connect;
-use consumer::db::sensor::update_sensor;
+use consumer::db::history::{history_granularity, init_history_collection};
+use consumer::db::indexes::ensure_indexes;
+use consumer::db::pending::backfill_pending_sensors;
+use consumer::db::retention::reconcile_retention;
+use consumer::errors::amqp_error::AmqpError;
 use consumer::errors::message_error::MessageError;
-use consumer::models::generic_message::GenericMessage;
-use consumer::models::sensor::Sensor;
+use consumer::features::FeatureRegistry;
+use consumer::handlers::Handlers;
+use consumer::models::generic_message::{GenericMessage, ordering_key};
+use consumer::models::sensor::{MessageReport, SensorUpdate};
+use consumer::models::sensor_event::SensorUpdatedEvent;
+use consumer::rollups::RollupCalendar;
+use consumer::validation::Validator;
+use consumer::workers::{KeyedTasks, WorkerPool};
 
 #[tokio::main]
 async fn main() {
@@ -23,63 +52,331 @@ async fn main() {
         error!(target: "app", "MongoDB - cannot connect {:?}", error);
         panic!("cannot connect to MongoDB:: {:?}", error)
     });
+    let granularity: TimeseriesGranularity = history_granularity(&env).unwrap_or_else(|error| {
+        error!(target: "app", "MongoDB - invalid history granularity {:?}", error);
+        panic!("invalid history granularity:: {:?}", error)
+    });
+    init_history_collection(&database, granularity)
+        .await
+        .unwrap_or_else(|error| {
+            error!(target: "app", "MongoDB - cannot init history collection {:?}", error);
+            panic!("cannot init history collection:: {:?}", error)
+        });
+    ensure_indexes(&database).await.unwrap_or_else(|error| {
+        error!(target: "app", "MongoDB - cannot ensure indexes {:?}", error);
+        panic!("cannot ensure MongoDB indexes:: {:?}", error)
+    });
+
+    // 3. Init the handlers of the supported topic families, with the registry of supported features
+    let registry: Arc<FeatureRegistry> = Arc::new(match env.features_file.as_deref() {
+        Some(features_file) => FeatureRegistry::load(features_file).unwrap_or_else(|error| {
+            error!(target: "app", "Features - cannot load registry {:?}", error);
+            panic!("cannot load features registry:: {:?}", error)
+        }),
+        None => FeatureRegistry::default(),
+    });
+    // retention is enforced by MongoDB with TTL indexes, that follow the registry at every startup
+    reconcile_retention(&database, &registry).await.unwrap_or_else(|error| {
+        error!(target: "app", "MongoDB - cannot reconcile retention {:?}", error);
+        panic!("cannot reconcile retention:: {:?}", error)
+    });
+
+    let validator: Arc<Validator> = Arc::new(Validator::new());
+    let clock: ClockPolicy = ClockPolicy::from_env(&env);
+    let calendar: RollupCalendar = RollupCalendar::from_env(&env).unwrap_or_else(|error| {
+        error!(target: "app", "Rollups - invalid timezone {:?}", error);
+        panic!("invalid rollup timezone:: {:?}", error)
+    });
+    // updates of single sensors sent by different workers are written together with a single bulk write
+    let writer: Option<BulkWriter> =
+        BulkWriteConfig::from_env(&env).map(|config| BulkWriter::spawn(database.clone(), config));
+    let handlers: Arc<Handlers> = Arc::new(Handlers::with_defaults(registry, validator, clock, calendar, writer));
+    // readings of unregistered sensors are kept as pending, until the registration service creates their sensors
+    spawn_pending_backfill(
+        database.clone(),
+        Duration::from_secs(env.pending_backfill_interval_secs),
+    );
 
-    // 3. Init RabbitMQ
+    // 4. Init RabbitMQ
     info!(target: "app", "Initializing RabbitMQ...");
-    let mut amqp_client: AmqpClient =
-        AmqpClient::new(env.amqp_uri.clone(), env.amqp_queue_name.clone()).consumer(env.amqp_consumer_tag.clone());
-    amqp_client.connect(true).await;
-    while let Some(delivery_res) = amqp_client.consumer.as_mut().unwrap().next().await {
-        if let Ok(delivery) = delivery_res {
-            let _ = process_amqp_message(&delivery, &database).await;
+    let topology: Topology = match env.amqp_topology_file.as_deref() {
+        Some(topology_file) => Topology::load(topology_file).unwrap_or_else(|error| {
+            error!(target: "app", "AMQP - cannot load topology {:?}", error);
+            panic!("cannot load AMQP topology:: {:?}", error)
+        }),
+        None => Topology::default(),
+    };
+    let prefetch_count: u16 = prefetch_count(&env).unwrap_or_else(|error| {
+        error!(target: "app", "AMQP - invalid prefetch count {:?}", error);
+        panic!("invalid AMQP prefetch count:: {:?}", error)
+    });
+    // invalid TLS material must stop the consumer immediately, instead of failing every connection attempt
+    let tls: Option<TlsConfig> = TlsConfig::from_env(&env).unwrap_or_else(|error| {
+        error!(target: "app", "AMQP - invalid TLS configuration {:?}", error);
+        panic!("invalid AMQP TLS configuration:: {:?}", error)
+    });
+    let mut amqp_client: AmqpClient = AmqpClient::new(env.amqp_uri.clone(), env.amqp_queue_name.clone())
+        .consumer(env.amqp_consumer_tag.clone())
+        .dead_letter(DeadLetterConfig::from_env(&env))
+        .retry(RetryConfig::from_env(&env))
+        .events(EventsConfig::from_env(&env))
+        .acks(AckBatchConfig::from_env(&env))
+        .prefetch(prefetch_count)
+        .topology(topology)
+        .tls(tls);
+    let reconnect_policy: ReconnectPolicy = ReconnectPolicy::from_env(&env);
+    log_connection_status(amqp_client.status());
+    // the broker could be unavailable at startup, so keep trying until it's reachable or SIGTERM/SIGINT is received
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
+    if amqp_client.connect(true).await.is_err() {
+        tokio::select! {
+            _ = &mut shutdown => {
+                info!(target: "app", "AMQP consumer - shutdown requested before connecting to the AMQP server");
+                database.client().clone().shutdown().await;
+                return;
+            }
+            _ = reconnect(&mut amqp_client, &reconnect_policy) => {}
+        }
+    }
+    let mut settler: DeliverySettler = amqp_client.settler().unwrap_or_else(|error| {
+        error!(target: "app", "AMQP consumer - cannot create delivery settler {:?}", error);
+        panic!("cannot create delivery settler:: {:?}", error)
+    });
+    let mut events: Option<EventPublisher> = amqp_client.event_publisher().unwrap_or_else(|error| {
+        error!(target: "app", "AMQP consumer - cannot create event publisher {:?}", error);
+        panic!("cannot create event publisher:: {:?}", error)
+    });
+
+    // 5. Init workers, to process multiple deliveries concurrently.
+    // Every delivery is completed in its own task, so a worker can move on as soon as the sensor update
+    // has been sent to the bulk writer, while the delivery waits for the bulk write
+    let workers_database: Database = database.clone();
+    let tasks: KeyedTasks = KeyedTasks::new();
+    let workers_tasks: KeyedTasks = tasks.clone();
+    let workers: WorkerPool<Job> = WorkerPool::new(
+        env.consumer_workers,
+        env.amqp_prefetch_count as usize,
+        move |job: Job| {
+            let database = workers_database.clone();
+            let handlers = handlers.clone();
+            let (notifier, submitted) = SubmissionNotifier::new();
+            workers_tasks.spawn(job.key.clone(), move |previous| async move {
+                let result = notifier
+                    .scope(process_amqp_message(&job.delivery, &database, &handlers))
+                    .await;
+                // bulk writes of the same device could complete in a different order,
+                // so events are published in the order of the deliveries
+                previous.wait().await;
+                if let (Ok(report), Some(events)) = (result.as_ref(), job.events.as_ref()) {
+                    for update in report.updates.iter() {
+                        publish_sensor_updated(events, update).await;
+                    }
+                }
+                if let Ok(report) = result.as_ref() {
+                    job.settler.dead_letter_failures(&job.delivery, &report.failures).await;
+                }
+                // ack only after the sensor update has been persisted (at-least-once processing)
+                job.settler.settle(&job.delivery, result.as_ref().err()).await;
+            });
+            async move {
+                // the next delivery of the same device is processed only after this update has been sent,
+                // so updates are written in the order of the deliveries
+                let _ = submitted.await;
+            }
+        },
+    );
+
+    // 6. Consume deliveries until SIGTERM/SIGINT is received
+    loop {
+        let delivery_res = tokio::select! {
+            _ = &mut shutdown => break,
+            delivery_opt = amqp_client.consumer.as_mut().unwrap().next() => match delivery_opt {
+                Some(delivery_res) => delivery_res,
+                None => {
+                    error!(target: "app", "AMQP consumer - consumer stream ended unexpectedly, reconnecting...");
+                    tokio::select! {
+                        _ = &mut shutdown => break,
+                        _ = reconnect(&mut amqp_client, &reconnect_policy) => {}
+                    }
+                    settler = amqp_client.settler().expect("cannot create delivery settler after reconnection");
+                    events = amqp_client
+                        .event_publisher()
+                        .expect("cannot create event publisher after reconnection");
+                    continue;
+                }
+            },
+        };
+        if let Ok(mut delivery) = delivery_res {
+            // readings of the same device are always processed by the same worker to keep their order
+            let key: String = ordering_key(&delivery.data);
+            stamp_received_at(&mut delivery);
+            settler.track(&delivery);
+            let job = Job {
+                key: key.clone(),
+                delivery,
+                settler: settler.clone(),
+                events: events.clone(),
+            };
+            if let Err(job) = workers.dispatch(&key, job).await {
+                error!(target: "app", "AMQP consumer - cannot dispatch delivery to workers, requeuing it");
+                job.settler.settle_with(&job.delivery, AckDecision::Requeue).await;
+            }
         } else {
             let err = delivery_res.err();
             error!(target: "app", "AMQP consumer - delivery_res error = {:?}", err);
             info!(target: "app", "AMQP consumer - waiting for recovery...");
-            amqp_client.wait_for_recovery(err.unwrap()).await;
+            let recovery_result = amqp_client.wait_for_recovery(err.unwrap()).await;
+            info!(target: "app", "AMQP consumer - recovery result = {:?}", recovery_result);
+            // auto-recovery failed, so rebuild the whole client
+            if let Err(AmqpError::ErrorCannotRecover(_) | AmqpError::Uninitialized(_)) = recovery_result {
+                tokio::select! {
+                    _ = &mut shutdown => break,
+                    _ = reconnect(&mut amqp_client, &reconnect_policy) => {}
+                }
+                settler = amqp_client
+                    .settler()
+                    .expect("cannot create delivery settler after reconnection");
+                events = amqp_client
+                    .event_publisher()
+                    .expect("cannot create event publisher after reconnection");
+            }
         }
     }
+
+    // 7. Graceful shutdown
+    shutdown_gracefully(
+        amqp_client,
+        workers,
+        tasks,
+        settler,
+        database,
+        env.shutdown_timeout_secs,
+    )
+    .await;
 }
 
-async fn process_amqp_message(delivery: &Delivery, database: &Database) -> Result<Option<Sensor>, MessageError> {
-    let payload_str: &str = read_message(delivery).await;
-    debug!(target: "app", "process_amqp_message - payload_str = {}", payload_str);
-    // deserialize to a GenericMessage (with turbofish operator "::<GenericMessage>")
-    match serde_json::from_str::<GenericMessage>(payload_str) {
-        Ok(generic_msg) => {
-            debug!(target: "app", "process_amqp_message - message received of type = {}", generic_msg.topic.feature_name);
-            debug!(target: "app", "process_amqp_message - message payload deserialized from JSON = {:?}", generic_msg);
-
-            let bson_value_opt: Option<Bson> = match generic_msg.topic.feature_name.as_str() {
-                // f64 sensors
-                "temperature" | "humidity" | "light" | "airpressure" => generic_msg.get_value_as_bson_f64(),
-                // i64 sensors
-                "motion" | "airquality" | "online" => generic_msg.get_value_as_bson_i64(),
-                _ => {
-                    error!(target: "app", "process_amqp_message - cannot recognize Message payload type = {}", generic_msg.topic.feature_name);
-                    None
+// log every change of the AMQP connection status
+fn log_connection_status(mut status: watch::Receiver<ConnectionStatus>) {
+    tokio::spawn(async move {
+        while status.changed().await.is_ok() {
+            let current: ConnectionStatus = *status.borrow_and_update();
+            info!(target: "app", "AMQP connection status = {:?}", current);
+        }
+    });
+}
+
+// periodically back-fill the sensors registered after their first readings.
+// Errors are only logged, because pending readings are retried at the next run
+fn spawn_pending_backfill(database: Database, period: Duration) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(period);
+        loop {
+            interval.tick().await;
+            match backfill_pending_sensors(&database).await {
+                Ok(0) => {}
+                Ok(updated) => {
+                    info!(target: "app", "spawn_pending_backfill - {} sensors have been back-filled", updated);
                 }
-            };
-            debug!(target: "app", "process_amqp_message - bson_value_opt = {:?}", &bson_value_opt);
-            if let Some(bson_value) = bson_value_opt {
-                match update_sensor(database, &generic_msg, &bson_value).await {
-                    Ok(sensor) => {
-                        debug!(target: "app", "process_amqp_message - sensor db updated with result = {:?}", sensor);
-                        Ok(sensor)
-                    }
-                    Err(err) => {
-                        error!(target: "app", "process_amqp_message - cannot update sensor db, err = {:?}", err);
-                        Err(MessageError::UpdateDbError(err))
-                    }
+                Err(err) => {
+                    error!(target: "app", "spawn_pending_backfill - cannot back-fill pending sensors, err = {:?}", err);
                 }
-            } else {
-                error!(target: "app", "process_amqp_message - cannot update sensor, because bson_value_opt is None");
-                Err(MessageError::NoneValuePayloadError)
             }
         }
-        Err(err) => {
-            error!(target: "app", "process_amqp_message - cannot convert payload as json Message. Error = {:?}", err);
-            Err(MessageError::MessageParsingError)
+    });
+}
+
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        signal::ctrl_c().await.expect("cannot listen for SIGINT");
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        signal::unix::signal(signal::unix::SignalKind::terminate())
+            .expect("cannot listen for SIGTERM")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+    tokio::select! {
+        _ = ctrl_c => info!(target: "app", "shutdown_signal - SIGINT received"),
+        _ = terminate => info!(target: "app", "shutdown_signal - SIGTERM received"),
+    }
+}
+
+// stop consuming, wait for in-flight deliveries, then close AMQP and MongoDB connections.
+// Deliveries not settled before the timeout are requeued by the AMQP server when the channel is closed.
+async fn shutdown_gracefully(
+    mut amqp_client: AmqpClient,
+    workers: WorkerPool<Job>,
+    tasks: KeyedTasks,
+    settler: DeliverySettler,
+    database: Database,
+    timeout_secs: u64,
+) {
+    info!(target: "app", "shutdown_gracefully - cancelling AMQP consumer...");
+    if let Err(err) = amqp_client.cancel_consumer().await {
+        error!(target: "app", "shutdown_gracefully - cannot cancel AMQP consumer, err = {:?}", err);
+    }
+    info!(target: "app", "shutdown_gracefully - waiting up to {}s for in-flight deliveries...", timeout_secs);
+    let drain = async {
+        workers.join().await;
+        tasks.join().await;
+    };
+    let drained: bool = timeout(Duration::from_secs(timeout_secs), drain).await.is_ok();
+    if !drained {
+        error!(target: "app", "shutdown_gracefully - timeout expired, unsettled deliveries will be requeued");
+    }
+    // successful deliveries could still be waiting for their multiple-ack
+    settler.flush().await;
+    info!(target: "app", "shutdown_gracefully - closing AMQP channel and connection...");
+    if let Err(err) = amqp_client.close().await {
+        error!(target: "app", "shutdown_gracefully - cannot close AMQP client, err = {:?}", err);
+    }
+    info!(target: "app", "shutdown_gracefully - closing MongoDB client...");
+    // if workers are still running, don't wait for their MongoDB operations
+    database.client().clone().shutdown().immediate(!drained).await;
+    info!(target: "app", "shutdown_gracefully - shutdown completed");
+}
+
+// a delivery waiting to be processed by a worker
+struct Job {
+    // deliveries with the same key are processed in order (see `ordering_key`)
+    key: String,
+    delivery: Delivery,
+    settler: DeliverySettler,
+    events: Option<EventPublisher>,
+}
+
+// events are best-effort: the sensor has already been updated, so a failure is only logged
+// and the delivery is acked anyway, otherwise the update would be applied twice
+async fn publish_sensor_updated(events: &EventPublisher, update: &SensorUpdate) {
+    let event = SensorUpdatedEvent::new(update, DateTime::now().to_string());
+    if let Err(err) = events.publish_sensor_updated(&event).await {
+        error!(target: "app", "publish_sensor_updated - cannot publish event = {:?}, err = {:?}", event, err);
+    }
+}
+
+async fn process_amqp_message(
+    delivery: &Delivery,
+    database: &Database,
+    handlers: &Handlers,
+) -> Result<MessageReport, MessageError> {
+    let received_at: DateTime = received_at(delivery);
+    let payload_str: &str = read_message(delivery);
+    debug!(target: "app", "process_amqp_message - payload_str = {}", payload_str);
+    let generic_msg: GenericMessage = GenericMessage::from_json(payload_str)?;
+    debug!(target: "app", "process_amqp_message - message received with topic = {}", generic_msg.topic);
+    debug!(target: "app", "process_amqp_message - message payload deserialized from JSON = {:?}", generic_msg);
+    generic_msg.check_topic().inspect_err(|err| {
+        error!(target: "app", "process_amqp_message - topic not valid, err = {:?}", err);
+    })?;
+    match handlers.get(generic_msg.topic.family.as_str()) {
+        Some(handler) => handler.handle(database, &generic_msg, received_at).await,
+        None => {
+            error!(target: "app", "process_amqp_message - cannot recognize topic family = {}", generic_msg.topic.family);
+            Err(MessageError::UnknownFamilyError(generic_msg.topic.family.clone()))
         }
     }
 }
@@ -0,0 +1,21 @@
+use mongodb::bson::DateTime;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+// discrete event of a device (e.g. a button pressed or a door opened),
+// received as `events/<device>/<name>` with an optional `data` object in the payload
+#[allow(non_snake_case)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct DeviceEventDocument {
+    // profile info
+    pub apiToken: String,
+    // device info
+    pub deviceUuid: String,
+    pub channel: Option<String>,
+    // event info
+    pub name: String,
+    pub data: Option<Value>,
+    // dates
+    pub observedAt: DateTime,
+    pub receivedAt: DateTime,
+}
@@ -0,0 +1,80 @@
+use mongodb::bson::DateTime;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::clock::parse_timestamp;
+
+// log line of a device, received as `logs/<device>/<name>` with payload
+// { "level": "warn", "message": "..." } or { "lines": [{ "level": "warn", "message": "...", "timestamp": ... }, ...] }
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LogLine {
+    #[serde(default = "default_level")]
+    pub level: String,
+    pub message: String,
+    #[serde(default)]
+    pub timestamp: Option<Value>,
+}
+
+fn default_level() -> String {
+    "info".to_string()
+}
+
+impl LogLine {
+    pub fn get_timestamp(&self) -> Option<DateTime> {
+        self.timestamp.as_ref().and_then(parse_timestamp)
+    }
+}
+
+// log lines of a payload, either a single line or the lines buffered by the device
+pub fn log_lines(payload: &Value) -> Result<Vec<LogLine>, serde_json::Error> {
+    match payload.get("lines") {
+        Some(lines) => Vec::<LogLine>::deserialize(lines),
+        None => LogLine::deserialize(payload).map(|line| vec![line]),
+    }
+}
+
+#[allow(non_snake_case)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct DeviceLogDocument {
+    // profile info
+    pub apiToken: String,
+    // device info
+    pub deviceUuid: String,
+    pub source: String,
+    // log info
+    pub level: String,
+    pub message: String,
+    // dates
+    pub observedAt: DateTime,
+    pub receivedAt: DateTime,
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::models::device_log::log_lines;
+    use mongodb::bson::DateTime;
+    use pretty_assertions::assert_eq;
+    use serde_json::json;
+
+    #[test]
+    #[test_log::test]
+    fn ok_log_lines() {
+        let lines = log_lines(&json!({ "level": "warn", "message": "wifi reconnected" })).unwrap();
+        assert_eq!(lines.len(), 1);
+        assert_eq!(lines[0].level, "warn");
+        assert_eq!(lines[0].get_timestamp(), None);
+
+        let lines = log_lines(&json!({ "lines": [
+            { "message": "boot", "timestamp": 1700000000000_i64 },
+            { "level": "error", "message": "sensor not found" }
+        ] }))
+        .unwrap();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0].level, "info");
+        assert_eq!(lines[0].get_timestamp(), Some(DateTime::from_millis(1_700_000_000_000)));
+        assert_eq!(lines[1].message, "sensor not found");
+
+        assert!(log_lines(&json!({ "level": "warn" })).is_err());
+    }
+}
@@ -0,0 +1,87 @@
+use mongodb::bson::DateTime;
+use serde::{Deserialize, Serialize};
+
+// payload of a `status/<device>/<name>` message, e.g.
+// "payload": { "online": true, "rssi": -67, "uptimeSecs": 3600, "firmwareVersion": "1.2.0", "freeHeap": 120000 }
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeviceStatusPayload {
+    #[serde(default = "default_online")]
+    pub online: bool,
+    // Wi-Fi signal strength (dBm)
+    #[serde(default)]
+    pub rssi: Option<i64>,
+    #[serde(default)]
+    pub uptime_secs: Option<i64>,
+    #[serde(default)]
+    pub firmware_version: Option<String>,
+    // free memory (bytes)
+    #[serde(default)]
+    pub free_heap: Option<i64>,
+}
+
+// a device that sends its status is online, unless it says otherwise (e.g. with a last will message)
+fn default_online() -> bool {
+    true
+}
+
+// health of a device, with the latest status received
+#[allow(non_snake_case)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct DeviceStatusDocument {
+    // profile info
+    pub apiToken: String,
+    // device info
+    pub deviceUuid: String,
+    pub online: bool,
+    pub rssi: Option<i64>,
+    pub uptimeSecs: Option<i64>,
+    pub firmwareVersion: Option<String>,
+    pub freeHeap: Option<i64>,
+    // dates
+    pub observedAt: DateTime,
+    pub lastSeenAt: DateTime,
+}
+
+impl DeviceStatusDocument {
+    pub fn new(
+        api_token: &str,
+        device_uuid: &str,
+        payload: DeviceStatusPayload,
+        observed_at: DateTime,
+        received_at: DateTime,
+    ) -> Self {
+        Self {
+            apiToken: api_token.to_string(),
+            deviceUuid: device_uuid.to_string(),
+            online: payload.online,
+            rssi: payload.rssi,
+            uptimeSecs: payload.uptime_secs,
+            firmwareVersion: payload.firmware_version,
+            freeHeap: payload.free_heap,
+            observedAt: observed_at,
+            lastSeenAt: received_at,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::models::device_status::DeviceStatusPayload;
+    use pretty_assertions::assert_eq;
+    use serde_json::json;
+
+    #[test]
+    #[test_log::test]
+    fn ok_device_status_payload() {
+        let payload: DeviceStatusPayload =
+            serde_json::from_value(json!({ "rssi": -67, "uptimeSecs": 3600, "firmwareVersion": "1.2.0" })).unwrap();
+        assert!(payload.online);
+        assert_eq!(payload.rssi, Some(-67));
+        assert_eq!(payload.uptime_secs, Some(3600));
+        assert_eq!(payload.firmware_version.as_deref(), Some("1.2.0"));
+        assert_eq!(payload.free_heap, None);
+        let payload: DeviceStatusPayload = serde_json::from_value(json!({ "online": false })).unwrap();
+        assert!(!payload.online);
+    }
+}
@@ -1,7 +1,12 @@
-use mongodb::bson::{Bson, to_bson};
+use mongodb::bson::DateTime;
 use serde::Deserialize;
 use serde_json::Value;
+use tracing::error;
 
+use crate::clock::parse_timestamp;
+use crate::errors::message_error::MessageError;
+use crate::features::{FeatureKind, ValueType};
+use crate::models::sensor_value::SensorValue;
 use crate::models::topic::Topic;
 
 // input message from RabbitMQ
@@ -10,6 +15,8 @@ use crate::models::topic::Topic;
 pub struct GenericMessage {
     pub api_token: String,
     pub device_uuid: String,
+    // empty for messages with multiple readings (see `Reading`)
+    #[serde(default)]
     pub feature_uuid: String,
     pub topic: Topic,
     // payload is variable, because it can be PayloadTrait (Temperature, Humidity...)
@@ -17,66 +24,320 @@ pub struct GenericMessage {
     pub payload: Value,
 }
 
+// single reading of a message with multiple readings, e.g.
+// "payload": { "readings": [{ "featureUuid": "...", "featureName": "temperature", "value": 21.5, "unit": "°C" }, ...] }
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Reading {
+    pub feature_uuid: String,
+    pub feature_name: String,
+    pub value: Value,
+    #[serde(default)]
+    pub unit: Option<String>,
+    // observation time of the reading, if different from the one of the message
+    #[serde(default)]
+    pub timestamp: Option<Value>,
+}
+
+// reading buffered by a device while it was offline, sent with the other readings of the same sensor, e.g.
+// "payload": { "unit": "°C", "batch": [{ "value": 21.5, "timestamp": 1700000000000 }, ...] }
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BatchEntry {
+    pub value: Value,
+    pub timestamp: Value,
+    // unit of the reading, if different from the one of the batch
+    #[serde(default)]
+    pub unit: Option<String>,
+}
+
+// minimal view of a GenericMessage, used to route it before its full deserialization
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct MessageKey {
+    device_uuid: String,
+}
+
+// minimal view of a GenericMessage, used to find out if it cannot be deserialized because of its topic
+#[derive(Debug, Deserialize)]
+struct MessageTopic {
+    topic: Value,
+}
+
+// key used to keep the relative order of readings of the same device (`device_uuid`).
+// It doesn't include the feature, because a message can contain readings of multiple features.
+// Returns an empty string if the payload cannot be parsed.
+pub fn ordering_key(payload: &[u8]) -> String {
+    serde_json::from_slice::<MessageKey>(payload)
+        .map(|key| key.device_uuid)
+        .unwrap_or_default()
+}
+
+// read the value with the type defined by the feature in the registry
+fn typed_value(value: &Value, feature: &FeatureKind) -> Option<SensorValue> {
+    match feature.value_type {
+        ValueType::Float => value.as_f64().map(SensorValue::Float),
+        ValueType::Integer => value.as_i64().map(SensorValue::Integer),
+        ValueType::Boolean => value.as_bool().map(SensorValue::Boolean),
+        ValueType::String => value.as_str().map(|value| SensorValue::String(value.to_string())),
+        ValueType::Object => value.as_object().map(|value| SensorValue::Object(value.clone())),
+    }
+}
+
 impl GenericMessage {
-    pub fn get_value_as_bson_f64(&self) -> Option<Bson> {
-        let value: f64 = self.payload.get("value").and_then(|value| value.as_f64())?;
-        to_bson::<f64>(&value).ok()
+    // deserialize a message from JSON.
+    // Messages with an invalid topic are reported as `InvalidTopicError`, any other failure as `MessageParsingError`
+    pub fn from_json(payload: &str) -> Result<Self, MessageError> {
+        serde_json::from_str::<GenericMessage>(payload).map_err(|err| {
+            let topic_err: Option<String> = serde_json::from_str::<MessageTopic>(payload)
+                .ok()
+                .and_then(|message_topic| Topic::deserialize(&message_topic.topic).err())
+                .map(|topic_err| topic_err.to_string());
+            error!(target: "app", "from_json - cannot convert payload as json Message. Error = {:?}", err);
+            match topic_err {
+                Some(reason) => MessageError::InvalidTopicError(reason),
+                None => MessageError::MessageParsingError,
+            }
+        })
+    }
+    // the topic is set by the device, so it must refer to the same device of the message
+    pub fn check_topic(&self) -> Result<(), MessageError> {
+        if self.topic.device_id != self.device_uuid {
+            return Err(MessageError::InvalidTopicError(format!(
+                "topic device {} doesn't match device_uuid {}",
+                self.topic.device_id, self.device_uuid
+            )));
+        }
+        Ok(())
+    }
+    // readings of a message with multiple readings, or None if the message contains a single reading
+    pub fn readings(&self) -> Option<Result<Vec<Reading>, serde_json::Error>> {
+        let readings: &Value = self.payload.get("readings")?;
+        Some(Vec::<Reading>::deserialize(readings))
+    }
+    // readings of a batch of the same sensor, or None if the message isn't a batch
+    pub fn batch(&self) -> Option<Result<Vec<BatchEntry>, serde_json::Error>> {
+        let batch: &Value = self.payload.get("batch")?;
+        Some(Vec::<BatchEntry>::deserialize(batch))
+    }
+    // read the value with the type defined by the feature in the registry.
+    // The value is not normalized yet (see `units::normalize`)
+    pub fn get_value(&self, feature: &FeatureKind) -> Option<SensorValue> {
+        typed_value(self.payload.get("value")?, feature)
+    }
+    // optional unit of the value (e.g. "°F"), if different from the canonical unit of the feature
+    pub fn get_unit(&self) -> Option<&str> {
+        self.payload.get("unit").and_then(|unit| unit.as_str())
+    }
+    // optional observation time set by the device (epoch millis or RFC 3339),
+    // None if missing or not valid
+    pub fn get_timestamp(&self) -> Option<DateTime> {
+        self.payload.get("timestamp").and_then(parse_timestamp)
+    }
+}
+
+impl BatchEntry {
+    pub fn get_value(&self, feature: &FeatureKind) -> Option<SensorValue> {
+        typed_value(&self.value, feature)
     }
-    pub fn get_value_as_bson_i64(&self) -> Option<Bson> {
-        let value: i64 = self.payload.get("value").and_then(|value| value.as_i64())?;
-        to_bson::<i64>(&value).ok()
+    pub fn get_timestamp(&self) -> Option<DateTime> {
+        parse_timestamp(&self.timestamp)
+    }
+}
+
+impl Reading {
+    pub fn get_value(&self, feature: &FeatureKind) -> Option<SensorValue> {
+        typed_value(&self.value, feature)
+    }
+    pub fn get_timestamp(&self) -> Option<DateTime> {
+        self.timestamp.as_ref().and_then(parse_timestamp)
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::models::generic_message::GenericMessage;
+    use crate::errors::message_error::MessageError;
+    use crate::features::{FeatureKind, ValueType};
+    use crate::models::generic_message::{GenericMessage, ordering_key};
+    use crate::models::sensor_value::SensorValue;
     use crate::models::topic::Topic;
-    use mongodb::bson::to_bson;
+    use mongodb::bson::DateTime;
     use pretty_assertions::assert_eq;
     use serde_json::json;
 
     #[test]
     #[test_log::test]
-    fn ok_get_value_as_bson_f64() {
-        let api_token = "473a4861-632b-4915-b01e-cf1d418966c6";
+    fn ok_get_value() {
         let device_uuid = "246e3256-f0dd-4fcb-82c5-ee20c2267eeb";
-        let feature_uuid = "41cb3f47-894c-45e9-90d9-a4d4de903896";
-        let sensor_type = "temperature";
-        let value: f64 = 21.0;
-
-        let topic: Topic = Topic::new(format!("sensors/{}/{}", device_uuid, sensor_type).as_str());
+        let topic: Topic = Topic::parse(format!("sensors/{}/{}", device_uuid, "color").as_str()).unwrap();
         let generic_msg: GenericMessage = GenericMessage {
-            api_token: api_token.to_string(),
+            api_token: "473a4861-632b-4915-b01e-cf1d418966c6".to_string(),
             device_uuid: device_uuid.to_string(),
-            feature_uuid: feature_uuid.to_string(),
+            feature_uuid: "41cb3f47-894c-45e9-90d9-a4d4de903896".to_string(),
             topic,
-            payload: json!({ "value": value }),
+            payload: json!({ "value": { "r": 255, "g": 0, "b": 0 } }),
         };
-        let result = generic_msg.get_value_as_bson_f64().unwrap();
-        let expected = to_bson::<f64>(&value).unwrap();
-        assert_eq!(result, expected);
+        let feature = FeatureKind::new("color", ValueType::Object, None);
+        assert_eq!(
+            generic_msg.get_value(&feature),
+            Some(SensorValue::Object(
+                json!({ "r": 255, "g": 0, "b": 0 }).as_object().unwrap().clone()
+            ))
+        );
     }
 
     #[test]
     #[test_log::test]
-    fn ok_get_value_as_bson_i64() {
-        let api_token = "473a4861-632b-4915-b01e-cf1d418966c6";
-        let device_uuid = "246e3256-f0dd-4fcb-82c5-ee20c2267eeb";
-        let feature_uuid = "41cb3f47-894c-45e9-90d9-a4d4de903896";
-        let sensor_type = "motion";
-        let value: i64 = 1;
+    fn from_json_errors() {
+        let message = |topic: serde_json::Value| {
+            json!({
+                "deviceUuid": "246e3256-f0dd-4fcb-82c5-ee20c2267eeb",
+                "apiToken": "473a4861-632b-4915-b01e-cf1d418966c6",
+                "featureUuid": "41cb3f47-894c-45e9-90d9-a4d4de903896",
+                "topic": topic,
+                "payload": { "value": 21.5 }
+            })
+            .to_string()
+        };
+        let generic_msg = GenericMessage::from_json(&message(json!(
+            "sensors/246e3256-f0dd-4fcb-82c5-ee20c2267eeb/temperature"
+        )))
+        .unwrap();
+        assert_eq!(generic_msg.topic.feature_name, "temperature");
+        assert!(matches!(
+            GenericMessage::from_json(&message(json!("sensors/temperature"))),
+            Err(MessageError::InvalidTopicError(_))
+        ));
+        assert!(matches!(
+            GenericMessage::from_json(&message(
+                json!({ "family": "sensors", "deviceId": "", "featureName": "temperature" })
+            )),
+            Err(MessageError::InvalidTopicError(_))
+        ));
+        // the topic is valid, but the message is not
+        assert!(matches!(
+            GenericMessage::from_json(r#"{ "topic": "sensors/246e3256-f0dd-4fcb-82c5-ee20c2267eeb/temperature" }"#),
+            Err(MessageError::MessageParsingError)
+        ));
+        assert!(matches!(
+            GenericMessage::from_json("not json"),
+            Err(MessageError::MessageParsingError)
+        ));
+    }
 
-        let topic: Topic = Topic::new(format!("sensors/{}/{}", device_uuid, sensor_type).as_str());
-        let generic_msg: GenericMessage = GenericMessage {
-            api_token: api_token.to_string(),
+    #[test]
+    #[test_log::test]
+    fn ok_ordering_key() {
+        let payload = json!({
+            "apiToken": "473a4861-632b-4915-b01e-cf1d418966c6",
+            "deviceUuid": "246e3256-f0dd-4fcb-82c5-ee20c2267eeb",
+            "featureUuid": "41cb3f47-894c-45e9-90d9-a4d4de903896",
+            "topic": { "family": "sensors", "deviceId": "246e3256-f0dd-4fcb-82c5-ee20c2267eeb", "featureName": "temperature" },
+            "payload": { "value": 21.0 }
+        });
+        let key = ordering_key(serde_json::to_string(&payload).unwrap().as_bytes());
+        assert_eq!(key, "246e3256-f0dd-4fcb-82c5-ee20c2267eeb");
+        assert_eq!(ordering_key(b"bad payload"), "");
+    }
+
+    #[test]
+    #[test_log::test]
+    fn ok_readings() {
+        let payload = json!({
+            "apiToken": "473a4861-632b-4915-b01e-cf1d418966c6",
+            "deviceUuid": "246e3256-f0dd-4fcb-82c5-ee20c2267eeb",
+            "topic": { "family": "sensors", "deviceId": "246e3256-f0dd-4fcb-82c5-ee20c2267eeb", "featureName": "readings" },
+            "payload": { "timestamp": 1700000000000_i64, "readings": [
+                { "featureUuid": "41cb3f47-894c-45e9-90d9-a4d4de903896", "featureName": "temperature", "value": 70.5, "unit": "°F", "timestamp": "2023-11-14T22:13:19Z" },
+                { "featureUuid": "2a9e3b5c-2a40-4c5c-9a6f-3c1b8f0e7d11", "featureName": "motion", "value": 1 }
+            ] }
+        });
+        let generic_msg: GenericMessage = serde_json::from_value(payload).unwrap();
+        assert_eq!(generic_msg.feature_uuid, "");
+        let readings = generic_msg.readings().unwrap().unwrap();
+        assert_eq!(readings.len(), 2);
+        assert_eq!(readings[0].feature_name, "temperature");
+        assert_eq!(readings[0].unit.as_deref(), Some("°F"));
+        let temperature = FeatureKind::new("temperature", ValueType::Float, Some("°C"));
+        assert_eq!(readings[0].get_value(&temperature), Some(SensorValue::Float(70.5)));
+        let motion = FeatureKind::new("motion", ValueType::Integer, None);
+        assert_eq!(readings[1].get_value(&motion), Some(SensorValue::Integer(1)));
+        assert_eq!(readings[1].unit, None);
+        assert_eq!(
+            generic_msg.get_timestamp(),
+            Some(DateTime::from_millis(1_700_000_000_000))
+        );
+        assert_eq!(
+            readings[0].get_timestamp(),
+            Some(DateTime::from_millis(1_699_999_999_000))
+        );
+        assert_eq!(readings[1].get_timestamp(), None);
+
+        // a message with a single reading doesn't have readings
+        let generic_msg: GenericMessage = serde_json::from_value(json!({
+            "apiToken": "473a4861-632b-4915-b01e-cf1d418966c6",
+            "deviceUuid": "246e3256-f0dd-4fcb-82c5-ee20c2267eeb",
+            "featureUuid": "41cb3f47-894c-45e9-90d9-a4d4de903896",
+            "topic": { "family": "sensors", "deviceId": "246e3256-f0dd-4fcb-82c5-ee20c2267eeb", "featureName": "temperature" },
+            "payload": { "value": 21.0 }
+        }))
+        .unwrap();
+        assert!(generic_msg.readings().is_none());
+        assert!(generic_msg.batch().is_none());
+    }
+
+    #[test]
+    #[test_log::test]
+    fn ok_batch() {
+        let payload = json!({
+            "apiToken": "473a4861-632b-4915-b01e-cf1d418966c6",
+            "deviceUuid": "246e3256-f0dd-4fcb-82c5-ee20c2267eeb",
+            "featureUuid": "41cb3f47-894c-45e9-90d9-a4d4de903896",
+            "topic": { "family": "sensors", "deviceId": "246e3256-f0dd-4fcb-82c5-ee20c2267eeb", "featureName": "temperature" },
+            "payload": { "unit": "°F", "batch": [
+                { "value": 70.5, "timestamp": 1700000000000_i64 },
+                { "value": 21.0, "timestamp": "2023-11-14T22:14:20Z", "unit": "°C" }
+            ] }
+        });
+        let generic_msg: GenericMessage = serde_json::from_value(payload).unwrap();
+        let batch = generic_msg.batch().unwrap().unwrap();
+        assert_eq!(batch.len(), 2);
+        assert_eq!(generic_msg.get_unit(), Some("°F"));
+        let temperature = FeatureKind::new("temperature", ValueType::Float, Some("°C"));
+        assert_eq!(batch[0].get_value(&temperature), Some(SensorValue::Float(70.5)));
+        assert_eq!(batch[0].get_timestamp(), Some(DateTime::from_millis(1_700_000_000_000)));
+        assert_eq!(batch[0].unit, None);
+        assert_eq!(batch[1].get_timestamp(), Some(DateTime::from_millis(1_700_000_060_000)));
+        assert_eq!(batch[1].unit.as_deref(), Some("°C"));
+
+        // every reading of a batch must have a timestamp
+        let payload = json!({
+            "apiToken": "473a4861-632b-4915-b01e-cf1d418966c6",
+            "deviceUuid": "246e3256-f0dd-4fcb-82c5-ee20c2267eeb",
+            "featureUuid": "41cb3f47-894c-45e9-90d9-a4d4de903896",
+            "topic": { "family": "sensors", "deviceId": "246e3256-f0dd-4fcb-82c5-ee20c2267eeb", "featureName": "temperature" },
+            "payload": { "batch": [{ "value": 70.5 }] }
+        });
+        let generic_msg: GenericMessage = serde_json::from_value(payload).unwrap();
+        assert!(generic_msg.batch().unwrap().is_err());
+    }
+
+    #[test]
+    #[test_log::test]
+    fn check_topic_device() {
+        let device_uuid = "246e3256-f0dd-4fcb-82c5-ee20c2267eeb";
+        let mut generic_msg: GenericMessage = GenericMessage {
+            api_token: "473a4861-632b-4915-b01e-cf1d418966c6".to_string(),
             device_uuid: device_uuid.to_string(),
-            feature_uuid: feature_uuid.to_string(),
-            topic,
-            payload: json!({ "value": value }),
+            feature_uuid: "41cb3f47-894c-45e9-90d9-a4d4de903896".to_string(),
+            topic: Topic::parse(format!("sensors/{}/relay-1/online", device_uuid).as_str()).unwrap(),
+            payload: json!({ "value": 1 }),
         };
-        let result = generic_msg.get_value_as_bson_i64().unwrap();
-        let expected = to_bson::<i64>(&value).unwrap();
-        assert_eq!(result, expected);
+        assert!(generic_msg.check_topic().is_ok());
+        generic_msg.topic = Topic::parse("sensors/another-device/relay-1/online").unwrap();
+        assert!(matches!(
+            generic_msg.check_topic(),
+            Err(MessageError::InvalidTopicError(_))
+        ));
     }
 }
@@ -1,3 +1,11 @@
+pub mod device_event;
+pub mod device_log;
+pub mod device_status;
 pub mod generic_message;
+pub mod pending_sensor;
 pub mod sensor;
+pub mod sensor_event;
+pub mod sensor_history;
+pub mod sensor_rollup;
+pub mod sensor_value;
 pub mod topic;
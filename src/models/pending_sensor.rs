@@ -0,0 +1,92 @@
+use mongodb::bson::DateTime;
+use serde::{Deserialize, Serialize};
+
+use crate::models::sensor_value::{Measurement, SensorValue};
+
+// latest valid reading of a sensor that isn't registered yet, because the device sent it
+// before the registration service created the sensor. It's used to back-fill the sensor later
+#[allow(non_snake_case)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct PendingSensorDocument {
+    // profile info
+    pub apiToken: String,
+    // device info
+    pub deviceUuid: String,
+    // feature info
+    pub featureUuid: String,
+    pub featureName: String,
+    pub value: SensorValue,
+    pub unit: Option<String>,
+    pub originalUnit: Option<String>,
+    // dates
+    pub observedAt: DateTime,
+    pub firstSeenAt: DateTime,
+    pub lastSeenAt: DateTime,
+}
+
+impl PendingSensorDocument {
+    pub fn new(
+        api_token: &str,
+        device_uuid: &str,
+        feature_uuid: &str,
+        feature_name: &str,
+        measurement: &Measurement,
+        observed_at: DateTime,
+        received_at: DateTime,
+    ) -> Self {
+        Self {
+            apiToken: api_token.to_string(),
+            deviceUuid: device_uuid.to_string(),
+            featureUuid: feature_uuid.to_string(),
+            featureName: feature_name.to_string(),
+            value: measurement.value.clone(),
+            unit: measurement.unit.clone(),
+            originalUnit: measurement.original_unit.clone(),
+            observedAt: observed_at,
+            firstSeenAt: received_at,
+            lastSeenAt: received_at,
+        }
+    }
+
+    pub fn measurement(&self) -> Measurement {
+        Measurement {
+            value: self.value.clone(),
+            unit: self.unit.clone(),
+            original_unit: self.originalUnit.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::models::pending_sensor::PendingSensorDocument;
+    use crate::models::sensor_value::{Measurement, SensorValue};
+    use mongodb::bson::DateTime;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    #[test_log::test]
+    fn ok_pending_sensor_document() {
+        let measurement = Measurement {
+            value: SensorValue::Float(21.5),
+            unit: Some("°C".to_string()),
+            original_unit: Some("°F".to_string()),
+        };
+        let observed_at = DateTime::from_millis(1_700_000_000_000);
+        let received_at = DateTime::from_millis(1_700_000_001_000);
+        let pending_doc = PendingSensorDocument::new(
+            "473a4861-632b-4915-b01e-cf1d418966c6",
+            "246e3256-f0dd-4fcb-82c5-ee20c2267eeb",
+            "41cb3f47-894c-45e9-90d9-a4d4de903896",
+            "temperature",
+            &measurement,
+            observed_at,
+            received_at,
+        );
+        assert_eq!(pending_doc.featureName, "temperature");
+        assert_eq!(pending_doc.observedAt, observed_at);
+        assert_eq!(pending_doc.firstSeenAt, received_at);
+        assert_eq!(pending_doc.lastSeenAt, received_at);
+        assert_eq!(pending_doc.measurement(), measurement);
+    }
+}
@@ -2,6 +2,9 @@ use mongodb::bson::DateTime;
 use mongodb::bson::oid::ObjectId;
 use serde::{Deserialize, Serialize};
 
+use crate::errors::message_error::MessageError;
+use crate::models::sensor_value::SensorValue;
+
 #[allow(non_snake_case)]
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct SensorDocument {
@@ -17,10 +20,20 @@ pub struct SensorDocument {
     // feature info
     pub featureUuid: String,
     pub featureName: String,
-    pub value: f64,
+    pub value: SensorValue,
+    // canonical unit of the value and unit of the reading before the conversion
+    // (missing in documents written by older versions)
+    #[serde(default)]
+    pub unit: Option<String>,
+    #[serde(default)]
+    pub originalUnit: Option<String>,
     // dates
     pub createdAt: DateTime,
+    // when the value has been received by the server
     pub modifiedAt: DateTime,
+    // when the value has been measured by the device (missing if the sensor has never been updated)
+    #[serde(default)]
+    pub observedAt: Option<DateTime>,
 }
 
 #[allow(non_snake_case)]
@@ -38,8 +51,39 @@ pub struct Sensor {
     // feature info
     pub featureUuid: String,
     pub featureName: String,
-    pub value: f64,
+    pub value: SensorValue,
+    // canonical unit of the value and unit of the reading before the conversion
+    // (missing in documents written by older versions)
+    #[serde(default)]
+    pub unit: Option<String>,
+    #[serde(default)]
+    pub originalUnit: Option<String>,
     // dates
     pub createdAt: String,
     pub modifiedAt: String,
+    #[serde(default)]
+    pub observedAt: Option<String>,
+}
+
+// sensor before and after an update
+#[derive(Debug, Clone)]
+pub struct SensorUpdate {
+    pub previous: Sensor,
+    pub current: Sensor,
+}
+
+// result of a message, with the outcome of each reading.
+// A message with a single reading that cannot be stored is an error, not a failure in the report
+#[derive(Debug, Default)]
+pub struct MessageReport {
+    pub updates: Vec<SensorUpdate>,
+    pub failures: Vec<ReadingFailure>,
+}
+
+// reading of a message with multiple readings that hasn't been stored
+#[derive(Debug)]
+pub struct ReadingFailure {
+    pub feature_uuid: String,
+    pub feature_name: String,
+    pub error: MessageError,
 }
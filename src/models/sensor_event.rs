@@ -0,0 +1,106 @@
+use serde::{Deserialize, Serialize};
+
+use crate::models::sensor::SensorUpdate;
+use crate::models::sensor_value::SensorValue;
+
+pub const SENSOR_UPDATED_EVENT: &str = "sensor.updated";
+
+// domain event published after every successful sensor update.
+// It doesn't contain the api token, because events can be consumed by other services.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct SensorUpdatedEvent {
+    pub event_type: String,
+    pub sensor_id: String,
+    pub profile_owner_id: String,
+    pub device_uuid: String,
+    pub feature_uuid: String,
+    pub feature_name: String,
+    pub value: SensorValue,
+    pub previous_value: SensorValue,
+    pub unit: Option<String>,
+    pub modified_at: String,
+    pub previous_modified_at: String,
+    // when the value has been measured by the device
+    pub observed_at: Option<String>,
+    pub emitted_at: String,
+}
+
+impl SensorUpdatedEvent {
+    pub fn new(update: &SensorUpdate, emitted_at: String) -> Self {
+        Self {
+            event_type: SENSOR_UPDATED_EVENT.to_string(),
+            sensor_id: update.current._id.clone(),
+            profile_owner_id: update.current.profileOwnerId.clone(),
+            device_uuid: update.current.deviceUuid.clone(),
+            feature_uuid: update.current.featureUuid.clone(),
+            feature_name: update.current.featureName.clone(),
+            value: update.current.value.clone(),
+            previous_value: update.previous.value.clone(),
+            unit: update.current.unit.clone(),
+            modified_at: update.current.modifiedAt.clone(),
+            previous_modified_at: update.previous.modifiedAt.clone(),
+            observed_at: update.current.observedAt.clone(),
+            emitted_at,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::models::sensor::{Sensor, SensorUpdate};
+    use crate::models::sensor_event::{SENSOR_UPDATED_EVENT, SensorUpdatedEvent};
+    use crate::models::sensor_value::SensorValue;
+    use pretty_assertions::assert_eq;
+    use serde_json::json;
+
+    #[test]
+    #[test_log::test]
+    fn ok_sensor_updated_event() {
+        let previous = Sensor {
+            _id: "63963ce7c7fd6d463c6c77a3".to_string(),
+            profileOwnerId: "620d710e4e8fe8f3394084bc".to_string(),
+            apiToken: "473a4861-632b-4915-b01e-cf1d418966c6".to_string(),
+            deviceUuid: "246e3256-f0dd-4fcb-82c5-ee20c2267eeb".to_string(),
+            mac: "60:55:F9:DF:F8:92".to_string(),
+            model: "dht-light".to_string(),
+            manufacturer: "ks89".to_string(),
+            featureUuid: "41cb3f47-894c-45e9-90d9-a4d4de903896".to_string(),
+            featureName: "temperature".to_string(),
+            value: SensorValue::Float(20.5),
+            unit: Some("°C".to_string()),
+            originalUnit: Some("°C".to_string()),
+            createdAt: "2024-01-01 10:00:00.0 +00:00:00".to_string(),
+            modifiedAt: "2024-01-01 10:00:00.0 +00:00:00".to_string(),
+            observedAt: None,
+        };
+        let mut current = previous.clone();
+        current.value = SensorValue::Float(21.0);
+        current.modifiedAt = "2024-01-01 10:05:00.0 +00:00:00".to_string();
+        current.observedAt = Some("2024-01-01 10:04:58.0 +00:00:00".to_string());
+        let update = SensorUpdate { previous, current };
+
+        let event = SensorUpdatedEvent::new(&update, "2024-01-01 10:05:01.0 +00:00:00".to_string());
+        assert_eq!(event.event_type, SENSOR_UPDATED_EVENT);
+        assert_eq!(event.value, SensorValue::Float(21.0));
+        assert_eq!(event.previous_value, SensorValue::Float(20.5));
+        assert_eq!(
+            serde_json::to_value(&event).unwrap(),
+            json!({
+                "eventType": "sensor.updated",
+                "sensorId": "63963ce7c7fd6d463c6c77a3",
+                "profileOwnerId": "620d710e4e8fe8f3394084bc",
+                "deviceUuid": "246e3256-f0dd-4fcb-82c5-ee20c2267eeb",
+                "featureUuid": "41cb3f47-894c-45e9-90d9-a4d4de903896",
+                "featureName": "temperature",
+                "value": 21.0,
+                "previousValue": 20.5,
+                "unit": "°C",
+                "modifiedAt": "2024-01-01 10:05:00.0 +00:00:00",
+                "previousModifiedAt": "2024-01-01 10:00:00.0 +00:00:00",
+                "observedAt": "2024-01-01 10:04:58.0 +00:00:00",
+                "emittedAt": "2024-01-01 10:05:01.0 +00:00:00"
+            })
+        );
+    }
+}
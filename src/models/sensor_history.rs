@@ -0,0 +1,113 @@
+use mongodb::bson::DateTime;
+use serde::{Deserialize, Serialize};
+
+use crate::models::sensor::SensorDocument;
+use crate::models::sensor_value::Measurement;
+use crate::models::sensor_value::SensorValue;
+
+// single reading of a sensor, stored in the time-series history collection
+#[allow(non_snake_case)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct SensorHistoryDocument {
+    // metaField of the time-series collection, that identifies the series of the sensor
+    pub meta: SensorHistoryMeta,
+    pub value: SensorValue,
+    pub unit: Option<String>,
+    pub originalUnit: Option<String>,
+    // dates (`observedAt` is the timeField of the time-series collection)
+    pub observedAt: DateTime,
+    pub receivedAt: DateTime,
+}
+
+#[allow(non_snake_case)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct SensorHistoryMeta {
+    // profile info
+    pub apiToken: String,
+    // device info
+    pub deviceUuid: String,
+    // feature info
+    pub featureUuid: String,
+    pub featureName: String,
+}
+
+impl SensorHistoryMeta {
+    pub fn new(api_token: &str, device_uuid: &str, feature_uuid: &str, feature_name: &str) -> Self {
+        Self {
+            apiToken: api_token.to_string(),
+            deviceUuid: device_uuid.to_string(),
+            featureUuid: feature_uuid.to_string(),
+            featureName: feature_name.to_string(),
+        }
+    }
+
+    pub fn from_sensor(sensor_doc: &SensorDocument) -> Self {
+        Self::new(
+            sensor_doc.apiToken.as_str(),
+            sensor_doc.deviceUuid.as_str(),
+            sensor_doc.featureUuid.as_str(),
+            sensor_doc.featureName.as_str(),
+        )
+    }
+}
+
+impl SensorHistoryDocument {
+    pub fn new(
+        meta: SensorHistoryMeta,
+        measurement: &Measurement,
+        observed_at: DateTime,
+        received_at: DateTime,
+    ) -> Self {
+        Self {
+            meta,
+            value: measurement.value.clone(),
+            unit: measurement.unit.clone(),
+            originalUnit: measurement.original_unit.clone(),
+            observedAt: observed_at,
+            receivedAt: received_at,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::models::sensor_history::{SensorHistoryDocument, SensorHistoryMeta};
+    use crate::models::sensor_value::{Measurement, SensorValue};
+    use mongodb::bson::{DateTime, doc, to_document};
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    #[test_log::test]
+    fn ok_history_document() {
+        let meta = SensorHistoryMeta::new(
+            "473a4861-632b-4915-b01e-cf1d418966c6",
+            "246e3256-f0dd-4fcb-82c5-ee20c2267eeb",
+            "41cb3f47-894c-45e9-90d9-a4d4de903896",
+            "temperature",
+        );
+        let measurement = Measurement {
+            value: SensorValue::Float(21.5),
+            unit: Some("°C".to_string()),
+            original_unit: Some("°F".to_string()),
+        };
+        let observed_at = DateTime::from_millis(1_700_000_000_000);
+        let received_at = DateTime::from_millis(1_700_000_001_000);
+        let history_doc = SensorHistoryDocument::new(meta, &measurement, observed_at, received_at);
+        assert_eq!(
+            to_document(&history_doc).unwrap(),
+            doc! {
+                "meta": {
+                    "apiToken": "473a4861-632b-4915-b01e-cf1d418966c6",
+                    "deviceUuid": "246e3256-f0dd-4fcb-82c5-ee20c2267eeb",
+                    "featureUuid": "41cb3f47-894c-45e9-90d9-a4d4de903896",
+                    "featureName": "temperature"
+                },
+                "value": 21.5,
+                "unit": "°C",
+                "originalUnit": "°F",
+                "observedAt": observed_at,
+                "receivedAt": received_at
+            }
+        );
+    }
+}
@@ -0,0 +1,68 @@
+use mongodb::bson::DateTime;
+use serde::{Deserialize, Serialize};
+
+use crate::models::sensor_history::SensorHistoryMeta;
+
+// numeric readings of a sensor in an hour or in a day, updated incrementally as readings arrive
+#[allow(non_snake_case)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct SensorRollupDocument {
+    // same sensor info of the history
+    pub meta: SensorHistoryMeta,
+    // `hour` or `day`
+    pub tier: String,
+    // IANA timezone of the boundaries of the rollup
+    pub timezone: String,
+    pub bucketStart: DateTime,
+    pub unit: Option<String>,
+    pub min: f64,
+    pub max: f64,
+    pub sum: f64,
+    pub count: i64,
+    // dates
+    pub firstObservedAt: DateTime,
+    pub lastObservedAt: DateTime,
+    pub modifiedAt: DateTime,
+}
+
+impl SensorRollupDocument {
+    pub fn avg(&self) -> f64 {
+        self.sum / self.count as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::models::sensor_rollup::SensorRollupDocument;
+    use mongodb::bson::{DateTime, doc, from_document};
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    #[test_log::test]
+    fn ok_rollup_document() {
+        let bucket_start = DateTime::from_millis(1_699_999_200_000);
+        let rollup_doc: SensorRollupDocument = from_document(doc! {
+            "meta": {
+                "apiToken": "473a4861-632b-4915-b01e-cf1d418966c6",
+                "deviceUuid": "246e3256-f0dd-4fcb-82c5-ee20c2267eeb",
+                "featureUuid": "41cb3f47-894c-45e9-90d9-a4d4de903896",
+                "featureName": "temperature"
+            },
+            "tier": "hour",
+            "timezone": "UTC",
+            "bucketStart": bucket_start,
+            "unit": "°C",
+            "min": 20.5,
+            "max": 22.0,
+            "sum": 63.0,
+            "count": 3_i64,
+            "firstObservedAt": DateTime::from_millis(1_700_000_000_000),
+            "lastObservedAt": DateTime::from_millis(1_700_000_600_000),
+            "modifiedAt": DateTime::from_millis(1_700_000_601_000)
+        })
+        .unwrap();
+        assert_eq!(rollup_doc.bucketStart, bucket_start);
+        assert_eq!(rollup_doc.count, 3);
+        assert_eq!(rollup_doc.avg(), 21.0);
+    }
+}
@@ -0,0 +1,102 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+// value of a sensor, stored as it is in MongoDB (e.g. an integer as Int64 and a float as Double).
+// Variants are untagged, so the order matters: integers must be tried before floats
+// and booleans before everything else, otherwise they would be read with the wrong type
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum SensorValue {
+    Boolean(bool),
+    Integer(i64),
+    Float(f64),
+    String(String),
+    Object(Map<String, Value>),
+}
+
+// value normalized to the canonical unit of its feature
+#[derive(Debug, Clone, PartialEq)]
+pub struct Measurement {
+    pub value: SensorValue,
+    // canonical unit of the feature
+    pub unit: Option<String>,
+    // unit of the reading, before the conversion
+    pub original_unit: Option<String>,
+}
+
+impl SensorValue {
+    // numeric value, if any
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            SensorValue::Integer(value) => Some(*value as f64),
+            SensorValue::Float(value) => Some(*value),
+            SensorValue::Boolean(_) | SensorValue::String(_) | SensorValue::Object(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for SensorValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SensorValue::Boolean(value) => write!(f, "{}", value),
+            SensorValue::Integer(value) => write!(f, "{}", value),
+            SensorValue::Float(value) => write!(f, "{}", value),
+            SensorValue::String(value) => write!(f, "{}", value),
+            SensorValue::Object(value) => write!(f, "{}", Value::Object(value.clone())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::models::sensor_value::SensorValue;
+    use mongodb::bson::{Bson, doc, from_bson, to_bson};
+    use pretty_assertions::assert_eq;
+    use serde_json::json;
+
+    #[test]
+    #[test_log::test]
+    fn ok_bson_round_trip() {
+        let values = vec![
+            (SensorValue::Float(21.5), Bson::Double(21.5)),
+            (SensorValue::Integer(1), Bson::Int64(1)),
+            (SensorValue::Boolean(true), Bson::Boolean(true)),
+            (
+                SensorValue::String("open".to_string()),
+                Bson::String("open".to_string()),
+            ),
+            (
+                SensorValue::Object(json!({ "r": 255, "g": 0, "b": 10.5 }).as_object().unwrap().clone()),
+                Bson::Document(doc! { "r": 255_i64, "g": 0_i64, "b": 10.5 }),
+            ),
+        ];
+        for (value, bson) in values {
+            assert_eq!(to_bson(&value).unwrap(), bson);
+            assert_eq!(from_bson::<SensorValue>(bson).unwrap(), value);
+        }
+        // documents written by older versions can contain Int32 values
+        assert_eq!(
+            from_bson::<SensorValue>(Bson::Int32(3)).unwrap(),
+            SensorValue::Integer(3)
+        );
+        // a float without decimals is still a float
+        assert_eq!(
+            from_bson::<SensorValue>(Bson::Double(21.0)).unwrap(),
+            SensorValue::Float(21.0)
+        );
+    }
+
+    #[test]
+    #[test_log::test]
+    fn ok_json_round_trip() {
+        let value: SensorValue = serde_json::from_value(json!(1)).unwrap();
+        assert_eq!(value, SensorValue::Integer(1));
+        let value: SensorValue = serde_json::from_value(json!(1.5)).unwrap();
+        assert_eq!(value, SensorValue::Float(1.5));
+        assert_eq!(serde_json::to_value(SensorValue::Float(1.5)).unwrap(), json!(1.5));
+        assert_eq!(SensorValue::Integer(2).as_f64(), Some(2.0));
+        assert_eq!(SensorValue::Boolean(false).as_f64(), None);
+    }
+}
@@ -2,22 +2,102 @@ use std::fmt;
 
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
-#[serde(rename_all = "camelCase")]
+use crate::errors::message_error::MessageError;
+
+// topic of a message, with format `<family>/<device>/<feature>`
+// or `<family>/<device>/<channel>/<feature>` for sub-devices (e.g. a relay board with multiple channels).
+// It can be received both as string and as object
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(rename_all = "camelCase", try_from = "TopicRepr")]
 pub struct Topic {
     pub family: String,
     pub device_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub channel: Option<String>,
     pub feature_name: String,
 }
 
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum TopicRepr {
+    Path(String),
+    #[serde(rename_all = "camelCase")]
+    Fields {
+        family: String,
+        device_id: String,
+        #[serde(default)]
+        channel: Option<String>,
+        feature_name: String,
+    },
+}
+
+// the reason is kept in the deserialization error of the message
+impl TryFrom<TopicRepr> for Topic {
+    type Error = String;
+
+    fn try_from(repr: TopicRepr) -> Result<Self, Self::Error> {
+        match repr {
+            TopicRepr::Path(topic) => Topic::parse_path(topic.as_str()),
+            TopicRepr::Fields {
+                family,
+                device_id,
+                channel,
+                feature_name,
+            } => {
+                let topic = Topic {
+                    family,
+                    device_id,
+                    channel,
+                    feature_name,
+                };
+                topic.validate()?;
+                Ok(topic)
+            }
+        }
+    }
+}
+
 impl Topic {
-    pub fn new(topic: &str) -> Self {
+    pub fn parse(topic: &str) -> Result<Self, MessageError> {
+        Self::parse_path(topic).map_err(MessageError::InvalidTopicError)
+    }
+
+    fn parse_path(topic: &str) -> Result<Self, String> {
         let items: Vec<&str> = topic.split('/').collect();
-        Self {
-            family: items.first().unwrap().to_string(),
-            device_id: items.get(1).unwrap().to_string(),
-            feature_name: items.last().unwrap().to_string(),
+        let topic: Topic = match items.as_slice() {
+            [family, device_id, feature_name] => Topic {
+                family: family.to_string(),
+                device_id: device_id.to_string(),
+                channel: None,
+                feature_name: feature_name.to_string(),
+            },
+            [family, device_id, channel, feature_name] => Topic {
+                family: family.to_string(),
+                device_id: device_id.to_string(),
+                channel: Some(channel.to_string()),
+                feature_name: feature_name.to_string(),
+            },
+            _ => return Err(format!("topic {} must have 3 or 4 segments", topic)),
+        };
+        topic.validate()?;
+        Ok(topic)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        let segments = [
+            Some(self.family.as_str()),
+            Some(self.device_id.as_str()),
+            self.channel.as_deref(),
+            Some(self.feature_name.as_str()),
+        ];
+        if segments
+            .into_iter()
+            .flatten()
+            .any(|segment| segment.trim().is_empty() || segment.contains('/'))
+        {
+            return Err(format!("topic {} has empty or invalid segments", self));
         }
+        Ok(())
     }
 }
 
@@ -27,6 +107,10 @@ impl fmt::Display for Topic {
         fmt.write_str("/")?;
         fmt.write_str(self.device_id.as_str())?;
         fmt.write_str("/")?;
+        if let Some(channel) = self.channel.as_deref() {
+            fmt.write_str(channel)?;
+            fmt.write_str("/")?;
+        }
         fmt.write_str(self.feature_name.as_str())?;
         Ok(())
     }
@@ -36,6 +120,7 @@ impl fmt::Display for Topic {
 mod tests {
     use crate::models::topic::Topic;
     use pretty_assertions::assert_eq;
+    use serde_json::json;
 
     #[test]
     #[test_log::test]
@@ -43,8 +128,42 @@ mod tests {
         let uuid = "246e3256-f0dd-4fcb-82c5-ee20c2267eeb";
         let sensor_type = "temperature";
 
-        let topic: Topic = Topic::new(format!("sensors/{}/{}", uuid, sensor_type).as_str());
+        let topic: Topic = Topic::parse(format!("sensors/{}/{}", uuid, sensor_type).as_str()).unwrap();
         let expected = topic.to_string();
         assert_eq!(format!("sensors/{}/{}", uuid, sensor_type), expected);
     }
+
+    #[test]
+    #[test_log::test]
+    fn ok_parse_topic() {
+        let uuid = "246e3256-f0dd-4fcb-82c5-ee20c2267eeb";
+        let topic: Topic = Topic::parse(format!("sensors/{}/relay-2/online", uuid).as_str()).unwrap();
+        assert_eq!(topic.family, "sensors");
+        assert_eq!(topic.device_id, uuid);
+        assert_eq!(topic.channel.as_deref(), Some("relay-2"));
+        assert_eq!(topic.feature_name, "online");
+        assert_eq!(topic.to_string(), format!("sensors/{}/relay-2/online", uuid));
+
+        // both string and object topics are accepted
+        let from_string: Topic = serde_json::from_value(json!(format!("sensors/{}/temperature", uuid))).unwrap();
+        let from_object: Topic =
+            serde_json::from_value(json!({ "family": "sensors", "deviceId": uuid, "featureName": "temperature" }))
+                .unwrap();
+        assert_eq!(from_string, from_object);
+        assert_eq!(from_string.channel, None);
+    }
+
+    #[test]
+    #[test_log::test]
+    fn bad_topic() {
+        assert!(Topic::parse("sensors").is_err());
+        assert!(Topic::parse("sensors/246e3256-f0dd-4fcb-82c5-ee20c2267eeb").is_err());
+        assert!(Topic::parse("sensors//temperature").is_err());
+        assert!(Topic::parse("sensors/246e3256-f0dd-4fcb-82c5-ee20c2267eeb/").is_err());
+        assert!(Topic::parse("sensors/246e3256-f0dd-4fcb-82c5-ee20c2267eeb/a/b/temperature").is_err());
+        let result = serde_json::from_value::<Topic>(
+            json!({ "family": "sensors", "deviceId": "", "featureName": "temperature" }),
+        );
+        assert!(result.is_err());
+    }
 }
@@ -0,0 +1,156 @@
+use chrono::{NaiveTime, TimeDelta, TimeZone, Timelike, Utc};
+use chrono_tz::Tz;
+use mongodb::bson::DateTime;
+
+use crate::config::Env;
+
+// Rollups summarize the numeric readings of a sensor (min, max, sum and count)
+// in an hour or in a day, so dashboards don't have to scan the history for long ranges.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RollupTier {
+    Hour,
+    Day,
+}
+
+impl RollupTier {
+    pub const ALL: [RollupTier; 2] = [RollupTier::Hour, RollupTier::Day];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RollupTier::Hour => "hour",
+            RollupTier::Day => "day",
+        }
+    }
+}
+
+// Boundaries of the rollups in the local time of the users,
+// otherwise a day would start at midnight UTC and not at their midnight.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RollupCalendar {
+    pub timezone: Tz,
+}
+
+impl RollupCalendar {
+    pub fn from_env(env: &Env) -> Result<Self, String> {
+        env.rollup_timezone
+            .parse::<Tz>()
+            .map(|timezone| Self { timezone })
+            .map_err(|_| format!("rollup timezone {} is not a valid IANA timezone", env.rollup_timezone))
+    }
+
+    // start of the rollup of `tier` that contains a reading observed at `observed_at`
+    pub fn bucket_start(&self, tier: RollupTier, observed_at: DateTime) -> DateTime {
+        let millis: i64 = observed_at.timestamp_millis();
+        let Some(utc) = Utc.timestamp_millis_opt(millis).single() else {
+            return observed_at;
+        };
+        let local = utc.with_timezone(&self.timezone);
+        match tier {
+            // computed from the instant and not from the local time,
+            // so the hour repeated when DST ends has its own rollup
+            RollupTier::Hour => {
+                let offset_ms: i64 =
+                    ((local.minute() * 60 + local.second()) * 1000 + local.timestamp_subsec_millis()) as i64;
+                DateTime::from_millis(millis - offset_ms)
+            }
+            RollupTier::Day => {
+                let midnight = local.date_naive().and_time(NaiveTime::MIN);
+                self.timezone
+                    .from_local_datetime(&midnight)
+                    .earliest()
+                    // days starting with a DST change (e.g. America/Santiago) don't have a midnight
+                    .or_else(|| {
+                        self.timezone
+                            .from_local_datetime(&(midnight + TimeDelta::hours(1)))
+                            .earliest()
+                    })
+                    .map_or(observed_at, |start| DateTime::from_millis(start.timestamp_millis()))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::rollups::{RollupCalendar, RollupTier};
+    use mongodb::bson::DateTime;
+    use pretty_assertions::assert_eq;
+
+    fn date(rfc3339: &str) -> DateTime {
+        DateTime::parse_rfc3339_str(rfc3339).unwrap()
+    }
+
+    #[test]
+    #[test_log::test]
+    fn ok_bucket_start() {
+        let utc = RollupCalendar {
+            timezone: chrono_tz::UTC,
+        };
+        let observed_at = date("2024-07-01T22:30:15.250Z");
+        assert_eq!(
+            utc.bucket_start(RollupTier::Hour, observed_at),
+            date("2024-07-01T22:00:00Z")
+        );
+        assert_eq!(
+            utc.bucket_start(RollupTier::Day, observed_at),
+            date("2024-07-01T00:00:00Z")
+        );
+
+        // in Rome it's already the next day (UTC+2)
+        let rome = RollupCalendar {
+            timezone: chrono_tz::Europe::Rome,
+        };
+        assert_eq!(
+            rome.bucket_start(RollupTier::Hour, observed_at),
+            date("2024-07-01T22:00:00Z")
+        );
+        assert_eq!(
+            rome.bucket_start(RollupTier::Day, observed_at),
+            date("2024-07-01T22:00:00Z")
+        );
+
+        // hours start at half past the UTC hour (UTC+5:30)
+        let kolkata = RollupCalendar {
+            timezone: chrono_tz::Asia::Kolkata,
+        };
+        assert_eq!(
+            kolkata.bucket_start(RollupTier::Hour, observed_at),
+            date("2024-07-01T22:30:00Z")
+        );
+        assert_eq!(
+            kolkata.bucket_start(RollupTier::Day, observed_at),
+            date("2024-07-01T18:30:00Z")
+        );
+    }
+
+    #[test]
+    #[test_log::test]
+    fn ok_bucket_start_dst() {
+        let rome = RollupCalendar {
+            timezone: chrono_tz::Europe::Rome,
+        };
+        // 02:30 is repeated when DST ends, once in CEST and once in CET
+        let first = date("2024-10-27T00:30:00Z");
+        let second = date("2024-10-27T01:30:00Z");
+        assert_eq!(rome.bucket_start(RollupTier::Hour, first), date("2024-10-27T00:00:00Z"));
+        assert_eq!(
+            rome.bucket_start(RollupTier::Hour, second),
+            date("2024-10-27T01:00:00Z")
+        );
+        // the day lasts 25 hours
+        assert_eq!(rome.bucket_start(RollupTier::Day, first), date("2024-10-26T22:00:00Z"));
+        assert_eq!(
+            rome.bucket_start(RollupTier::Day, date("2024-10-27T22:59:00Z")),
+            date("2024-10-26T22:00:00Z")
+        );
+
+        // midnight is skipped when DST starts in Santiago
+        let santiago = RollupCalendar {
+            timezone: chrono_tz::America::Santiago,
+        };
+        assert_eq!(
+            santiago.bucket_start(RollupTier::Day, date("2024-09-08T12:00:00Z")),
+            date("2024-09-08T04:00:00Z")
+        );
+    }
+}
@@ -24,6 +24,19 @@ pub async fn drop_all_collections(db: &Database) {
         .drop()
         .await
         .expect("drop 'sensors' collection");
+    for collection in [
+        "sensor_history",
+        "sensor_rollups",
+        "pending_sensors",
+        "device_status",
+        "device_events",
+        "device_logs",
+    ] {
+        db.collection::<Document>(collection)
+            .drop()
+            .await
+            .unwrap_or_else(|_| panic!("drop '{}' collection", collection));
+    }
 }
 
 pub async fn insert_sensor(db: &Database, input: RegisterInput, sensor_type: &str) -> Result<String, anyhow::Error> {
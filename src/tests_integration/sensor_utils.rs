@@ -50,6 +50,7 @@ pub struct FloatSensor {
 }
 
 pub trait Sensor {
+    #[allow(clippy::too_many_arguments)]
     fn new(
         // profile info
         profile_owner_id: String,
@@ -120,6 +121,7 @@ impl Sensor for FloatSensor {
 }
 
 impl IntSensor {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         // profile info
         profile_owner_id: String,
@@ -152,6 +154,7 @@ impl IntSensor {
 }
 
 impl FloatSensor {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         // profile info
         profile_owner_id: String,
@@ -1,7 +1,27 @@
 use rand::prelude::*;
+use std::sync::Arc;
+
+use consumer::clock::ClockPolicy;
+use consumer::config::Env;
+use consumer::features::FeatureRegistry;
+use consumer::handlers::Handlers;
+use consumer::rollups::RollupCalendar;
+use consumer::validation::Validator;
 
 use crate::tests_integration::db_utils::RegisterInput;
 
+// handlers with the default features, sharing the validator with the test
+pub fn create_handlers(env: &Env, validator: Arc<Validator>) -> Handlers {
+    Handlers::with_defaults(
+        Arc::new(FeatureRegistry::default()),
+        validator,
+        ClockPolicy::from_env(env),
+        RollupCalendar::from_env(env).expect("invalid rollup timezone"),
+        // updates are written one by one, so every test can check the result of its own delivery
+        None,
+    )
+}
+
 pub fn create_register_input(
     profile_owner_id: &str,
     api_token: &str,
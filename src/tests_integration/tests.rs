@@ -1,8 +1,10 @@
 use futures_lite::StreamExt;
 use mongodb::Database;
+use mongodb::bson::{DateTime, Document, doc};
 use pretty_assertions::assert_eq;
 use serde_json::json;
 use std::process::Command;
+use std::sync::Arc;
 use std::time::Duration;
 use tokio::time::sleep;
 use tracing::{debug, error, info};
@@ -10,12 +12,25 @@ use uuid::Uuid;
 
 use consumer::amqp::AmqpClient;
 use consumer::config::{Env, init};
+use consumer::db::bulk::{BulkWriteConfig, BulkWriter};
 use consumer::db::connect;
+use consumer::db::device::upsert_device_status;
+use consumer::db::history::{history_granularity, init_history_collection, insert_history};
+use consumer::db::indexes::ensure_indexes;
+use consumer::db::pending::backfill_pending_sensors;
+use consumer::db::sensor::UpdateOutcome;
+use consumer::errors::db_error::DbError;
 use consumer::errors::message_error::MessageError;
+use consumer::models::device_status::{DeviceStatusDocument, DeviceStatusPayload};
+use consumer::models::generic_message::GenericMessage;
+use consumer::models::sensor_history::SensorHistoryDocument;
+use consumer::models::sensor_rollup::SensorRollupDocument;
+use consumer::models::sensor_value::{Measurement, SensorValue};
+use consumer::validation::Validator;
 
 use crate::process_amqp_message;
 use crate::tests_integration::db_utils::{RegisterInput, drop_all_collections, insert_sensor};
-use crate::tests_integration::test_utils::{create_register_input, get_random_mac};
+use crate::tests_integration::test_utils::{create_handlers, create_register_input, get_random_mac};
 
 fn run_rabbitmqadmin_cli(payload: &str) {
     Command::new("rabbitmqadmin")
@@ -76,7 +91,7 @@ async fn ok_receive_float_amqp_message() {
     // init AMQP client
     let mut amqp_client: AmqpClient =
         AmqpClient::new(env.amqp_uri.clone(), env.amqp_queue_name.clone()).consumer(env.amqp_consumer_tag.clone());
-    amqp_client.connect(true).await;
+    amqp_client.connect(true).await.expect("cannot connect to AMQP server");
 
     // create AMQP message payload
     let device_uuid: String = Uuid::new_v4().to_string();
@@ -125,10 +140,10 @@ async fn ok_receive_float_amqp_message() {
     });
     // read and process AMQP message
     let delivery = amqp_client.consumer.as_mut().unwrap().next().await.unwrap().unwrap();
-    let result = process_amqp_message(&delivery, &db).await;
+    let result = process_amqp_message(&delivery, &db, &create_handlers(&env, Arc::new(Validator::new()))).await;
 
     // check results: resulting sensor should have the updated 'value'
-    let sensor = result.unwrap().unwrap();
+    let sensor = result.unwrap().updates.remove(0).current;
     // profile info
     assert_eq!(sensor.profileOwnerId, profile_owner_id);
     assert_eq!(sensor.apiToken, api_token);
@@ -140,7 +155,9 @@ async fn ok_receive_float_amqp_message() {
     // feature info
     assert_eq!(sensor.featureUuid, feature_uuid);
     assert_eq!(sensor.featureName, sensor_type);
-    assert_eq!(sensor.value, value);
+    assert_eq!(sensor.value, SensorValue::Float(value));
+    assert_eq!(sensor.unit, Some("°C".to_string()));
+    assert_eq!(sensor.originalUnit, Some("°C".to_string()));
 
     // cleanup
     drop_all_collections(&db).await;
@@ -168,7 +185,7 @@ async fn ok_receive_int_amqp_message() {
     // init AMQP client
     let mut amqp_client: AmqpClient =
         AmqpClient::new(env.amqp_uri.clone(), env.amqp_queue_name.clone()).consumer(env.amqp_consumer_tag.clone());
-    amqp_client.connect(true).await;
+    amqp_client.connect(true).await.expect("cannot connect to AMQP server");
 
     // create AMQP message payload
     let device_uuid: String = Uuid::new_v4().to_string();
@@ -218,10 +235,10 @@ async fn ok_receive_int_amqp_message() {
 
     // read and process AMQP message
     let delivery = amqp_client.consumer.as_mut().unwrap().next().await.unwrap().unwrap();
-    let result = process_amqp_message(&delivery, &db).await;
+    let result = process_amqp_message(&delivery, &db, &create_handlers(&env, Arc::new(Validator::new()))).await;
 
     // check results: resulting sensor should have the updated 'value'
-    let sensor = result.unwrap().unwrap();
+    let sensor = result.unwrap().updates.remove(0).current;
     // profile info
     assert_eq!(sensor.profileOwnerId, profile_owner_id);
     assert_eq!(sensor.apiToken, api_token);
@@ -233,7 +250,7 @@ async fn ok_receive_int_amqp_message() {
     // feature info
     assert_eq!(sensor.featureUuid, feature_uuid);
     assert_eq!(sensor.featureName, sensor_type);
-    assert_eq!(sensor.value as i64, value);
+    assert_eq!(sensor.value, SensorValue::Integer(value));
 
     // cleanup
     drop_all_collections(&db).await;
@@ -261,7 +278,7 @@ async fn missing_sensor_receive_amqp_message() {
     // init AMQP client
     let mut amqp_client: AmqpClient =
         AmqpClient::new(env.amqp_uri.clone(), env.amqp_queue_name.clone()).consumer(env.amqp_consumer_tag.clone());
-    amqp_client.connect(true).await;
+    amqp_client.connect(true).await.expect("cannot connect to AMQP server");
 
     // create AMQP message payload
     let device_uuid: String = Uuid::new_v4().to_string();
@@ -294,12 +311,12 @@ async fn missing_sensor_receive_amqp_message() {
 
     // read and process AMQP message
     let delivery = amqp_client.consumer.as_mut().unwrap().next().await.unwrap().unwrap();
-    let result = process_amqp_message(&delivery, &db).await;
+    let result = process_amqp_message(&delivery, &db, &create_handlers(&env, Arc::new(Validator::new()))).await;
 
     // check results: it must be an error, because `sensor_type="unknowntype"` is not valid
     assert_eq!(
         result.err().unwrap().to_string(),
-        anyhow::Error::from(MessageError::NoneValuePayloadError).to_string()
+        anyhow::Error::from(MessageError::UnknownFeatureError(sensor_type.to_string())).to_string()
     );
 
     // cleanup
@@ -328,7 +345,7 @@ async fn bad_payload_receive_amqp_message() {
     // init AMQP client
     let mut amqp_client: AmqpClient =
         AmqpClient::new(env.amqp_uri.clone(), env.amqp_queue_name.clone()).consumer(env.amqp_consumer_tag.clone());
-    amqp_client.connect(true).await;
+    amqp_client.connect(true).await.expect("cannot connect to AMQP server");
 
     // create AMQP message payload
     let json_val = json!({
@@ -346,7 +363,7 @@ async fn bad_payload_receive_amqp_message() {
 
     // read and process AMQP message
     let delivery = amqp_client.consumer.as_mut().unwrap().next().await.unwrap().unwrap();
-    let result = process_amqp_message(&delivery, &db).await;
+    let result = process_amqp_message(&delivery, &db, &create_handlers(&env, Arc::new(Validator::new()))).await;
 
     // check results: it must be an error, because json message is not valid (not deserializable as GenericMessage)
     assert_eq!(
@@ -360,3 +377,786 @@ async fn bad_payload_receive_amqp_message() {
     sleep(Duration::from_millis(1000)).await;
     amqp_client.close_connection().await.expect("cannot close connection");
 }
+
+#[tokio::test]
+#[test_log::test]
+async fn out_of_range_receive_amqp_message() {
+    purge_queue_rabbitmqadmin_cli();
+    sleep(Duration::from_millis(1000)).await;
+
+    // init logger and env variables
+    let env: Env = init();
+
+    // init DB client
+    let db: Database = connect(&env).await.unwrap_or_else(|error| {
+        error!(target: "app", "MongoDB - cannot connect {:?}", error);
+        panic!("cannot connect to MongoDB:: {:?}", error)
+    });
+    drop_all_collections(&db).await;
+
+    // init AMQP client
+    let mut amqp_client: AmqpClient =
+        AmqpClient::new(env.amqp_uri.clone(), env.amqp_queue_name.clone()).consumer(env.amqp_consumer_tag.clone());
+    amqp_client.connect(true).await.expect("cannot connect to AMQP server");
+
+    // create AMQP message payload
+    let device_uuid: String = Uuid::new_v4().to_string();
+    let feature_uuid: String = Uuid::new_v4().to_string();
+    let api_token: String = Uuid::new_v4().to_string();
+    let sensor_type = "humidity";
+    let value: f64 = 250.0;
+    let json_val = json!({
+        "deviceUuid": device_uuid,
+        "apiToken": api_token,
+        "featureUuid": feature_uuid,
+        "topic": {
+            "family": "sensors",
+            "deviceId": device_uuid,
+            "featureName": sensor_type
+        },
+        "payload": {
+            "value": value
+        }
+    });
+    let json_str = serde_json::to_string(&json_val).unwrap();
+    debug!(target: "app", "json_str = {}", json_str);
+
+    tokio::spawn(async move {
+        info!(target: "app", "waiting 2s before running cli command...");
+        sleep(Duration::from_millis(2000)).await;
+        // send an AMQP message to the server via `rabbitmqadmin` cli
+        run_rabbitmqadmin_cli(json_str.as_str());
+    });
+
+    // read and process AMQP message
+    let validator = Arc::new(Validator::new());
+    let delivery = amqp_client.consumer.as_mut().unwrap().next().await.unwrap().unwrap();
+    let result = process_amqp_message(&delivery, &db, &create_handlers(&env, validator.clone())).await;
+
+    // check results: it must be an error, because humidity cannot be greater than 100%
+    assert!(matches!(result, Err(MessageError::InvalidValueError(_))));
+    assert_eq!(validator.rejected(device_uuid.as_str()), 1);
+
+    // cleanup
+    drop_all_collections(&db).await;
+    purge_queue_rabbitmqadmin_cli();
+    sleep(Duration::from_millis(1000)).await;
+    amqp_client.close_connection().await.expect("cannot close connection");
+}
+
+#[tokio::test]
+#[test_log::test]
+async fn ok_receive_multiple_readings_amqp_message() {
+    purge_queue_rabbitmqadmin_cli();
+    sleep(Duration::from_millis(1000)).await;
+
+    // init logger and env variables
+    let env: Env = init();
+
+    // init DB client
+    let db: Database = connect(&env).await.unwrap_or_else(|error| {
+        error!(target: "app", "MongoDB - cannot connect {:?}", error);
+        panic!("cannot connect to MongoDB:: {:?}", error)
+    });
+    drop_all_collections(&db).await;
+
+    // init AMQP client
+    let mut amqp_client: AmqpClient =
+        AmqpClient::new(env.amqp_uri.clone(), env.amqp_queue_name.clone()).consumer(env.amqp_consumer_tag.clone());
+    amqp_client.connect(true).await.expect("cannot connect to AMQP server");
+
+    // create AMQP message payload, with a valid temperature, a valid humidity, an unknown sensor and a repeated temperature
+    let device_uuid: String = Uuid::new_v4().to_string();
+    let temperature_uuid: String = Uuid::new_v4().to_string();
+    let humidity_uuid: String = Uuid::new_v4().to_string();
+    let missing_uuid: String = Uuid::new_v4().to_string();
+    let api_token: String = Uuid::new_v4().to_string();
+    let json_val = json!({
+        "deviceUuid": device_uuid,
+        "apiToken": api_token,
+        "topic": {
+            "family": "sensors",
+            "deviceId": device_uuid,
+            "featureName": "readings"
+        },
+        "payload": {
+            "readings": [
+                { "featureUuid": temperature_uuid, "featureName": "temperature", "value": 21.5 },
+                { "featureUuid": humidity_uuid, "featureName": "humidity", "value": 40.0 },
+                { "featureUuid": missing_uuid, "featureName": "light", "value": 100.0 },
+                { "featureUuid": temperature_uuid, "featureName": "temperature", "value": 30.0 }
+            ]
+        }
+    });
+    let json_str = serde_json::to_string(&json_val).unwrap();
+    debug!(target: "app", "json_str = {}", json_str);
+
+    // register the temperature and humidity sensors of the same device
+    let mac: String = get_random_mac();
+    let profile_owner_id = "63963ce7c7fd6d463c6c77a3";
+    for (feature_uuid, sensor_type) in [(&temperature_uuid, "temperature"), (&humidity_uuid, "humidity")] {
+        let register_body: RegisterInput = create_register_input(
+            profile_owner_id,
+            &api_token,
+            &device_uuid,
+            &mac,
+            "test-model",
+            "ks89",
+            feature_uuid,
+        );
+        let _ = insert_sensor(&db, register_body, sensor_type).await;
+    }
+
+    tokio::spawn(async move {
+        info!(target: "app", "waiting 2s before running cli command...");
+        sleep(Duration::from_millis(2000)).await;
+        // send an AMQP message to the server via `rabbitmqadmin` cli
+        run_rabbitmqadmin_cli(json_str.as_str());
+    });
+
+    // read and process AMQP message
+    let delivery = amqp_client.consumer.as_mut().unwrap().next().await.unwrap().unwrap();
+    let result = process_amqp_message(&delivery, &db, &create_handlers(&env, Arc::new(Validator::new()))).await;
+
+    // check results: both registered sensors are updated, the unknown and the repeated ones are reported as failures
+    let report = result.unwrap();
+    assert_eq!(report.updates.len(), 2);
+    assert_eq!(report.updates[0].current.featureUuid, temperature_uuid);
+    assert_eq!(report.updates[0].current.value, SensorValue::Float(21.5));
+    assert_eq!(report.updates[1].current.featureUuid, humidity_uuid);
+    assert_eq!(report.updates[1].current.value, SensorValue::Float(40.0));
+    assert_eq!(report.failures.len(), 2);
+    assert_eq!(report.failures[0].feature_uuid, missing_uuid);
+    assert!(matches!(report.failures[0].error, MessageError::SensorPendingError(_)));
+    assert_eq!(report.failures[1].feature_uuid, temperature_uuid);
+    assert!(matches!(
+        report.failures[1].error,
+        MessageError::DuplicateReadingError(_)
+    ));
+    // the reading of the unknown sensor is kept until the sensor is registered
+    let pending_count = db
+        .collection::<Document>("pending_sensors")
+        .count_documents(doc! { "featureUuid": &missing_uuid })
+        .await
+        .expect("count pending sensors");
+    assert_eq!(pending_count, 1);
+
+    // cleanup
+    drop_all_collections(&db).await;
+    purge_queue_rabbitmqadmin_cli();
+    sleep(Duration::from_millis(1000)).await;
+    amqp_client.close_connection().await.expect("cannot close connection");
+}
+
+#[tokio::test]
+#[test_log::test]
+async fn stale_receive_amqp_message() {
+    purge_queue_rabbitmqadmin_cli();
+    sleep(Duration::from_millis(1000)).await;
+
+    // init logger and env variables
+    let env: Env = init();
+
+    // init DB client
+    let db: Database = connect(&env).await.unwrap_or_else(|error| {
+        error!(target: "app", "MongoDB - cannot connect {:?}", error);
+        panic!("cannot connect to MongoDB:: {:?}", error)
+    });
+    drop_all_collections(&db).await;
+
+    // init AMQP client
+    let mut amqp_client: AmqpClient =
+        AmqpClient::new(env.amqp_uri.clone(), env.amqp_queue_name.clone()).consumer(env.amqp_consumer_tag.clone());
+    amqp_client.connect(true).await.expect("cannot connect to AMQP server");
+
+    // create 2 AMQP message payloads, the second one observed before the first one (e.g. buffered by the device)
+    let device_uuid: String = Uuid::new_v4().to_string();
+    let feature_uuid: String = Uuid::new_v4().to_string();
+    let api_token: String = Uuid::new_v4().to_string();
+    let sensor_type = "temperature";
+    let now_ms: i64 = DateTime::now().timestamp_millis();
+    let json_strs: Vec<String> = [(21.5, now_ms - 60_000), (20.0, now_ms - 120_000)]
+        .iter()
+        .map(|(value, timestamp)| {
+            let json_val = json!({
+                "deviceUuid": device_uuid,
+                "apiToken": api_token,
+                "featureUuid": feature_uuid,
+                "topic": {
+                    "family": "sensors",
+                    "deviceId": device_uuid,
+                    "featureName": sensor_type
+                },
+                "payload": {
+                    "value": value,
+                    "timestamp": timestamp
+                }
+            });
+            serde_json::to_string(&json_val).unwrap()
+        })
+        .collect();
+
+    // register a sensor, otherwise it won't be possible to update it's value
+    let mac: String = get_random_mac();
+    let register_body: RegisterInput = create_register_input(
+        "63963ce7c7fd6d463c6c77a3",
+        &api_token,
+        &device_uuid,
+        &mac,
+        "test-model",
+        "ks89",
+        &feature_uuid,
+    );
+    let _ = insert_sensor(&db, register_body, sensor_type).await;
+
+    tokio::spawn(async move {
+        info!(target: "app", "waiting 2s before running cli command...");
+        sleep(Duration::from_millis(2000)).await;
+        // send AMQP messages to the server via `rabbitmqadmin` cli
+        for json_str in json_strs {
+            run_rabbitmqadmin_cli(json_str.as_str());
+        }
+    });
+
+    // read and process AMQP messages
+    let handlers = create_handlers(&env, Arc::new(Validator::new()));
+    let delivery = amqp_client.consumer.as_mut().unwrap().next().await.unwrap().unwrap();
+    let result = process_amqp_message(&delivery, &db, &handlers).await;
+    let sensor = result.unwrap().updates.remove(0).current;
+    assert_eq!(sensor.value, SensorValue::Float(21.5));
+    assert_eq!(
+        sensor.observedAt,
+        Some(DateTime::from_millis(now_ms - 60_000).to_string())
+    );
+    let delivery = amqp_client.consumer.as_mut().unwrap().next().await.unwrap().unwrap();
+    let result = process_amqp_message(&delivery, &db, &handlers).await;
+
+    // check results: the older observation must not replace the newer one
+    assert!(matches!(result, Err(MessageError::StaleReadingError(_))));
+
+    // cleanup
+    drop_all_collections(&db).await;
+    purge_queue_rabbitmqadmin_cli();
+    sleep(Duration::from_millis(1000)).await;
+    amqp_client.close_connection().await.expect("cannot close connection");
+}
+
+#[tokio::test]
+#[test_log::test]
+async fn ok_receive_batch_amqp_message() {
+    purge_queue_rabbitmqadmin_cli();
+    sleep(Duration::from_millis(1000)).await;
+
+    // init logger and env variables
+    let env: Env = init();
+
+    // init DB client
+    let db: Database = connect(&env).await.unwrap_or_else(|error| {
+        error!(target: "app", "MongoDB - cannot connect {:?}", error);
+        panic!("cannot connect to MongoDB:: {:?}", error)
+    });
+    drop_all_collections(&db).await;
+    init_history_collection(&db, history_granularity(&env).unwrap())
+        .await
+        .expect("cannot init history collection");
+    ensure_indexes(&db).await.expect("cannot ensure indexes");
+
+    // init AMQP client
+    let mut amqp_client: AmqpClient =
+        AmqpClient::new(env.amqp_uri.clone(), env.amqp_queue_name.clone()).consumer(env.amqp_consumer_tag.clone());
+    amqp_client.connect(true).await.expect("cannot connect to AMQP server");
+
+    // create AMQP message payload, with readings buffered by the device (not sorted), an invalid one
+    // and one with a wrong timestamp (observed at the receive time)
+    let device_uuid: String = Uuid::new_v4().to_string();
+    let feature_uuid: String = Uuid::new_v4().to_string();
+    let api_token: String = Uuid::new_v4().to_string();
+    let sensor_type = "humidity";
+    let now_ms: i64 = DateTime::now().timestamp_millis();
+    let json_val = json!({
+        "deviceUuid": device_uuid,
+        "apiToken": api_token,
+        "featureUuid": feature_uuid,
+        "topic": {
+            "family": "sensors",
+            "deviceId": device_uuid,
+            "featureName": sensor_type
+        },
+        "payload": {
+            "batch": [
+                { "value": 41.0, "timestamp": now_ms - 120_000 },
+                { "value": 43.0, "timestamp": now_ms - 60_000 },
+                { "value": 42.0, "timestamp": now_ms - 180_000 },
+                { "value": 250.0, "timestamp": now_ms - 30_000 },
+                { "value": 42.5, "timestamp": 0 }
+            ]
+        }
+    });
+    let json_str = serde_json::to_string(&json_val).unwrap();
+    debug!(target: "app", "json_str = {}", json_str);
+
+    // register a sensor, otherwise it won't be possible to update it's value
+    let mac: String = get_random_mac();
+    let register_body: RegisterInput = create_register_input(
+        "63963ce7c7fd6d463c6c77a3",
+        &api_token,
+        &device_uuid,
+        &mac,
+        "test-model",
+        "ks89",
+        &feature_uuid,
+    );
+    let _ = insert_sensor(&db, register_body, sensor_type).await;
+
+    tokio::spawn(async move {
+        info!(target: "app", "waiting 2s before running cli command...");
+        sleep(Duration::from_millis(2000)).await;
+        // send an AMQP message to the server via `rabbitmqadmin` cli
+        run_rabbitmqadmin_cli(json_str.as_str());
+    });
+
+    // read and process AMQP message
+    let delivery = amqp_client.consumer.as_mut().unwrap().next().await.unwrap().unwrap();
+    let result = process_amqp_message(&delivery, &db, &create_handlers(&env, Arc::new(Validator::new()))).await;
+
+    // check results: the sensor has the latest valid reading with a trusted timestamp and the history all valid readings
+    let mut report = result.unwrap();
+    assert_eq!(report.failures.len(), 1);
+    assert!(matches!(report.failures[0].error, MessageError::InvalidValueError(_)));
+    let sensor = report.updates.remove(0).current;
+    assert_eq!(sensor.value, SensorValue::Float(43.0));
+    assert_eq!(
+        sensor.observedAt,
+        Some(DateTime::from_millis(now_ms - 60_000).to_string())
+    );
+    // readings stored again (e.g. by a requeued delivery) don't change the history and the rollups
+    let history_docs: Vec<SensorHistoryDocument> = db
+        .collection::<SensorHistoryDocument>("sensor_history")
+        .find(doc! { "meta.featureUuid": &feature_uuid })
+        .await
+        .unwrap()
+        .try_collect()
+        .await
+        .unwrap();
+    let inserted_docs: Vec<SensorHistoryDocument> = insert_history(&db, &history_docs).await.unwrap();
+    assert!(inserted_docs.is_empty());
+    let history_count = db
+        .collection::<Document>("sensor_history")
+        .count_documents(doc! { "meta.featureUuid": &feature_uuid })
+        .await
+        .unwrap();
+    assert_eq!(history_count, 4);
+    // readings may be split in two days, if the test runs at midnight
+    let day_rollups: Vec<SensorRollupDocument> = db
+        .collection::<SensorRollupDocument>("sensor_rollups")
+        .find(doc! { "meta.featureUuid": &feature_uuid, "tier": "day" })
+        .await
+        .unwrap()
+        .try_collect()
+        .await
+        .unwrap();
+    assert_eq!(day_rollups.iter().map(|rollup| rollup.count).sum::<i64>(), 4);
+    assert_eq!(day_rollups.iter().map(|rollup| rollup.sum).sum::<f64>(), 168.5);
+    assert_eq!(
+        day_rollups.iter().map(|rollup| rollup.min).fold(f64::MAX, f64::min),
+        41.0
+    );
+    assert_eq!(
+        day_rollups.iter().map(|rollup| rollup.max).fold(f64::MIN, f64::max),
+        43.0
+    );
+
+    // cleanup
+    drop_all_collections(&db).await;
+    purge_queue_rabbitmqadmin_cli();
+    sleep(Duration::from_millis(1000)).await;
+    amqp_client.close_connection().await.expect("cannot close connection");
+}
+
+#[tokio::test]
+#[test_log::test]
+async fn ok_receive_status_amqp_message() {
+    purge_queue_rabbitmqadmin_cli();
+    sleep(Duration::from_millis(1000)).await;
+
+    // init logger and env variables
+    let env: Env = init();
+
+    // init DB client
+    let db: Database = connect(&env).await.unwrap_or_else(|error| {
+        error!(target: "app", "MongoDB - cannot connect {:?}", error);
+        panic!("cannot connect to MongoDB:: {:?}", error)
+    });
+    drop_all_collections(&db).await;
+
+    // init AMQP client
+    let mut amqp_client: AmqpClient =
+        AmqpClient::new(env.amqp_uri.clone(), env.amqp_queue_name.clone()).consumer(env.amqp_consumer_tag.clone());
+    amqp_client.connect(true).await.expect("cannot connect to AMQP server");
+
+    // create AMQP message payload, with the health of the device
+    let device_uuid: String = Uuid::new_v4().to_string();
+    let api_token: String = Uuid::new_v4().to_string();
+    let json_val = json!({
+        "deviceUuid": device_uuid,
+        "apiToken": api_token,
+        "topic": format!("status/{}/health", device_uuid),
+        "payload": {
+            "online": true,
+            "rssi": -67,
+            "uptimeSecs": 3600,
+            "firmwareVersion": "1.2.0"
+        }
+    });
+    let json_str = serde_json::to_string(&json_val).unwrap();
+    debug!(target: "app", "json_str = {}", json_str);
+
+    tokio::spawn(async move {
+        info!(target: "app", "waiting 2s before running cli command...");
+        sleep(Duration::from_millis(2000)).await;
+        // send an AMQP message to the server via `rabbitmqadmin` cli
+        run_rabbitmqadmin_cli(json_str.as_str());
+    });
+
+    // read and process AMQP message
+    let delivery = amqp_client.consumer.as_mut().unwrap().next().await.unwrap().unwrap();
+    let result = process_amqp_message(&delivery, &db, &create_handlers(&env, Arc::new(Validator::new()))).await;
+
+    // check results: no sensor is updated, but the status of the device is stored
+    assert!(result.unwrap().updates.is_empty());
+    let status_doc = db
+        .collection::<Document>("device_status")
+        .find_one(doc! { "deviceUuid": &device_uuid })
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(status_doc.get_bool("online").unwrap());
+    assert_eq!(status_doc.get_i64("rssi").unwrap(), -67);
+    assert_eq!(status_doc.get_str("firmwareVersion").unwrap(), "1.2.0");
+
+    // an older status (e.g. delivered late) doesn't replace the stored one
+    let older_payload: DeviceStatusPayload = serde_json::from_value(json!({ "online": false })).unwrap();
+    let older_observed_at =
+        DateTime::from_millis(status_doc.get_datetime("observedAt").unwrap().timestamp_millis() - 60_000);
+    let older_doc = DeviceStatusDocument::new(
+        &api_token,
+        &device_uuid,
+        older_payload,
+        older_observed_at,
+        DateTime::now(),
+    );
+    upsert_device_status(&db, &older_doc).await.unwrap();
+    let status_docs: Vec<Document> = db
+        .collection::<Document>("device_status")
+        .find(doc! { "deviceUuid": &device_uuid })
+        .await
+        .unwrap()
+        .try_collect()
+        .await
+        .unwrap();
+    assert_eq!(status_docs.len(), 1);
+    assert!(status_docs[0].get_bool("online").unwrap());
+
+    // cleanup
+    drop_all_collections(&db).await;
+    purge_queue_rabbitmqadmin_cli();
+    sleep(Duration::from_millis(1000)).await;
+    amqp_client.close_connection().await.expect("cannot close connection");
+}
+
+#[tokio::test]
+#[test_log::test]
+async fn ok_bulk_writer() {
+    // init logger and env variables
+    let env: Env = init();
+
+    // init DB client
+    let db: Database = connect(&env).await.unwrap_or_else(|error| {
+        error!(target: "app", "MongoDB - cannot connect {:?}", error);
+        panic!("cannot connect to MongoDB:: {:?}", error)
+    });
+    drop_all_collections(&db).await;
+
+    // register two sensors of the same device, the third one doesn't exist
+    let device_uuid: String = Uuid::new_v4().to_string();
+    let api_token: String = Uuid::new_v4().to_string();
+    let temperature_uuid: String = Uuid::new_v4().to_string();
+    let humidity_uuid: String = Uuid::new_v4().to_string();
+    let missing_uuid: String = Uuid::new_v4().to_string();
+    let mac: String = get_random_mac();
+    for (feature_uuid, sensor_type) in [(&temperature_uuid, "temperature"), (&humidity_uuid, "humidity")] {
+        let register_body: RegisterInput = create_register_input(
+            "63963ce7c7fd6d463c6c77a3",
+            &api_token,
+            &device_uuid,
+            &mac,
+            "test-model",
+            "ks89",
+            feature_uuid,
+        );
+        let _ = insert_sensor(&db, register_body, sensor_type).await;
+    }
+    let message = |feature_uuid: &str, feature_name: &str| -> GenericMessage {
+        serde_json::from_value(json!({
+            "deviceUuid": device_uuid,
+            "apiToken": api_token,
+            "featureUuid": feature_uuid,
+            "topic": {
+                "family": "sensors",
+                "deviceId": device_uuid,
+                "featureName": feature_name
+            },
+            "payload": {
+                "value": 0.0
+            }
+        }))
+        .unwrap()
+    };
+    let measurement = |value: f64| Measurement {
+        value: SensorValue::Float(value),
+        unit: None,
+        original_unit: None,
+    };
+    let now_ms: i64 = DateTime::now().timestamp_millis();
+    let temperature_msg: GenericMessage = message(&temperature_uuid, "temperature");
+    let humidity_msg: GenericMessage = message(&humidity_uuid, "humidity");
+    let missing_msg: GenericMessage = message(&missing_uuid, "light");
+    let (older, newer) = (measurement(20.0), measurement(21.0));
+    let (humidity, light) = (measurement(40.0), measurement(100.0));
+
+    // all updates are sent in the same window, so they are written with a single bulk write
+    let writer = BulkWriter::spawn(
+        db.clone(),
+        BulkWriteConfig {
+            max_ops: 4,
+            window: Duration::from_secs(1),
+        },
+    );
+    let (older_res, newer_res, humidity_res, missing_res) = tokio::join!(
+        writer.update_sensor(&temperature_msg, &older, DateTime::from_millis(now_ms - 60_000)),
+        writer.update_sensor(&temperature_msg, &newer, DateTime::from_millis(now_ms)),
+        writer.update_sensor(&humidity_msg, &humidity, DateTime::from_millis(now_ms)),
+        writer.update_sensor(&missing_msg, &light, DateTime::from_millis(now_ms)),
+    );
+
+    // check results: the older update of the temperature is replaced by the newer one
+    assert!(matches!(older_res, Ok(UpdateOutcome::Stale)));
+    let Ok(UpdateOutcome::Updated(update)) = newer_res else {
+        panic!("temperature sensor not updated");
+    };
+    assert_eq!(update.current.value, SensorValue::Float(21.0));
+    let Ok(UpdateOutcome::Updated(update)) = humidity_res else {
+        panic!("humidity sensor not updated");
+    };
+    assert_eq!(update.current.value, SensorValue::Float(40.0));
+    assert!(matches!(missing_res, Err(DbError::NotFound(_))));
+
+    // cleanup
+    drop_all_collections(&db).await;
+}
+
+#[tokio::test]
+#[test_log::test]
+async fn ok_pending_sensor_backfill() {
+    // init logger and env variables
+    let env: Env = init();
+
+    // init DB client
+    let db: Database = connect(&env).await.unwrap_or_else(|error| {
+        error!(target: "app", "MongoDB - cannot connect {:?}", error);
+        panic!("cannot connect to MongoDB:: {:?}", error)
+    });
+    drop_all_collections(&db).await;
+
+    // register the temperature sensor of the device, the humidity one will be registered later
+    let device_uuid: String = Uuid::new_v4().to_string();
+    let api_token: String = Uuid::new_v4().to_string();
+    let temperature_uuid: String = Uuid::new_v4().to_string();
+    let humidity_uuid: String = Uuid::new_v4().to_string();
+    let mac: String = get_random_mac();
+    let register = |feature_uuid: &str| -> RegisterInput {
+        create_register_input(
+            "63963ce7c7fd6d463c6c77a3",
+            &api_token,
+            &device_uuid,
+            &mac,
+            "test-model",
+            "ks89",
+            feature_uuid,
+        )
+    };
+    let _ = insert_sensor(&db, register(&temperature_uuid), "temperature").await;
+    let message = |api_token: &str| -> GenericMessage {
+        serde_json::from_value(json!({
+            "deviceUuid": device_uuid,
+            "apiToken": api_token,
+            "featureUuid": humidity_uuid,
+            "topic": {
+                "family": "sensors",
+                "deviceId": device_uuid,
+                "featureName": "humidity"
+            },
+            "payload": {
+                "value": 40.0
+            }
+        }))
+        .unwrap()
+    };
+    let humidity_msg: GenericMessage = message(&api_token);
+
+    // process the reading of the humidity sensor, that isn't registered yet
+    let handlers = create_handlers(&env, Arc::new(Validator::new()));
+    let sensors_handler = handlers.get("sensors").unwrap();
+    let result = sensors_handler.handle(&db, &humidity_msg, DateTime::now()).await;
+    assert!(matches!(result, Err(MessageError::SensorPendingError(_))));
+    let pending_filter = doc! { "featureUuid": &humidity_uuid };
+    let pending_count = db
+        .collection::<Document>("pending_sensors")
+        .count_documents(pending_filter.clone())
+        .await
+        .unwrap();
+    assert_eq!(pending_count, 1);
+
+    // register the humidity sensor and back-fill it
+    let _ = insert_sensor(&db, register(&humidity_uuid), "humidity").await;
+    assert_eq!(backfill_pending_sensors(&db).await.unwrap(), 1);
+
+    // check results: the sensor has the pending value and the pending sensor has been removed
+    let sensor_doc = db
+        .collection::<Document>("sensors")
+        .find_one(doc! { "featureUuid": &humidity_uuid })
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(sensor_doc.get_f64("value").unwrap(), 40.0);
+    let pending_count = db
+        .collection::<Document>("pending_sensors")
+        .count_documents(pending_filter)
+        .await
+        .unwrap();
+    assert_eq!(pending_count, 0);
+
+    // all readings of a batch of an unregistered sensor are stored in the history, only the latest one as pending
+    let batch_uuid: String = Uuid::new_v4().to_string();
+    let now_ms: i64 = DateTime::now().timestamp_millis();
+    let batch_msg: GenericMessage = serde_json::from_value(json!({
+        "deviceUuid": device_uuid,
+        "apiToken": api_token,
+        "featureUuid": batch_uuid,
+        "topic": {
+            "family": "sensors",
+            "deviceId": device_uuid,
+            "featureName": "humidity"
+        },
+        "payload": {
+            "batch": [
+                { "value": 41.0, "timestamp": now_ms - 120_000 },
+                { "value": 42.0, "timestamp": now_ms - 60_000 }
+            ]
+        }
+    }))
+    .unwrap();
+    let result = sensors_handler.handle(&db, &batch_msg, DateTime::now()).await;
+    assert!(matches!(result, Err(MessageError::SensorPendingError(_))));
+    let history_count = db
+        .collection::<Document>("sensor_history")
+        .count_documents(doc! { "meta.featureUuid": &batch_uuid })
+        .await
+        .unwrap();
+    assert_eq!(history_count, 2);
+    let pending_doc = db
+        .collection::<Document>("pending_sensors")
+        .find_one(doc! { "featureUuid": &batch_uuid })
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(pending_doc.get_f64("value").unwrap(), 42.0);
+
+    // readings of unknown profiles are not kept as pending
+    let unknown_msg: GenericMessage = message(&Uuid::new_v4().to_string());
+    let result = sensors_handler.handle(&db, &unknown_msg, DateTime::now()).await;
+    assert!(matches!(result, Err(MessageError::SensorNotFoundError)));
+    let pending_count = db
+        .collection::<Document>("pending_sensors")
+        .count_documents(doc! { "apiToken": &unknown_msg.api_token })
+        .await
+        .unwrap();
+    assert_eq!(pending_count, 0);
+
+    // cleanup
+    drop_all_collections(&db).await;
+}
+
+#[tokio::test]
+#[test_log::test]
+async fn stale_pending_sensor_reading() {
+    // init logger and env variables
+    let env: Env = init();
+
+    // init DB client
+    let db: Database = connect(&env).await.unwrap_or_else(|error| {
+        error!(target: "app", "MongoDB - cannot connect {:?}", error);
+        panic!("cannot connect to MongoDB:: {:?}", error)
+    });
+    drop_all_collections(&db).await;
+
+    // register the temperature sensor of the device, so its profile is known
+    let device_uuid: String = Uuid::new_v4().to_string();
+    let api_token: String = Uuid::new_v4().to_string();
+    let temperature_uuid: String = Uuid::new_v4().to_string();
+    let humidity_uuid: String = Uuid::new_v4().to_string();
+    let register_body: RegisterInput = create_register_input(
+        "63963ce7c7fd6d463c6c77a3",
+        &api_token,
+        &device_uuid,
+        &get_random_mac(),
+        "test-model",
+        "ks89",
+        &temperature_uuid,
+    );
+    let _ = insert_sensor(&db, register_body, "temperature").await;
+    let now_ms: i64 = DateTime::now().timestamp_millis();
+    let message = |value: f64, timestamp: i64| -> GenericMessage {
+        serde_json::from_value(json!({
+            "deviceUuid": device_uuid,
+            "apiToken": api_token,
+            "featureUuid": humidity_uuid,
+            "topic": {
+                "family": "sensors",
+                "deviceId": device_uuid,
+                "featureName": "humidity"
+            },
+            "payload": {
+                "value": value,
+                "timestamp": timestamp
+            }
+        }))
+        .unwrap()
+    };
+
+    // the newer reading arrives first, then an older one buffered by the device
+    let handlers = create_handlers(&env, Arc::new(Validator::new()));
+    let sensors_handler = handlers.get("sensors").unwrap();
+    for (value, timestamp) in [(41.0, now_ms - 1_000), (40.0, now_ms - 60_000)] {
+        let result = sensors_handler
+            .handle(&db, &message(value, timestamp), DateTime::now())
+            .await;
+        assert!(matches!(result, Err(MessageError::SensorPendingError(_))));
+    }
+
+    // check results: the pending sensor keeps the newer reading
+    let pending_doc = db
+        .collection::<Document>("pending_sensors")
+        .find_one(doc! { "featureUuid": &humidity_uuid })
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(pending_doc.get_f64("value").unwrap(), 41.0);
+    assert_eq!(
+        pending_doc.get_datetime("observedAt").unwrap(),
+        &DateTime::from_millis(now_ms - 1_000)
+    );
+
+    // cleanup
+    drop_all_collections(&db).await;
+}
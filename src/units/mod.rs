@@ -0,0 +1,162 @@
+use crate::features::{FeatureKind, ValueType};
+use crate::models::sensor_value::{Measurement, SensorValue};
+
+// units of measurement that can be converted to each other, when they measure the same quantity
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Unit {
+    Celsius,
+    Fahrenheit,
+    Kelvin,
+    // pressure units, with their value in Pascal
+    Pressure(f64),
+    // illuminance units, with their value in lux
+    Illuminance(f64),
+    Percent,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Quantity {
+    Temperature,
+    Pressure,
+    Illuminance,
+    Ratio,
+}
+
+impl Unit {
+    // parse the unit symbol, accepting the most common aliases
+    pub fn parse(symbol: &str) -> Option<Self> {
+        match symbol.trim() {
+            "°C" | "C" | "celsius" => Some(Unit::Celsius),
+            "°F" | "F" | "fahrenheit" => Some(Unit::Fahrenheit),
+            "K" | "kelvin" => Some(Unit::Kelvin),
+            "Pa" => Some(Unit::Pressure(1.0)),
+            "hPa" | "mbar" => Some(Unit::Pressure(100.0)),
+            "kPa" => Some(Unit::Pressure(1000.0)),
+            "bar" => Some(Unit::Pressure(100_000.0)),
+            "psi" => Some(Unit::Pressure(6_894.757)),
+            "mmHg" => Some(Unit::Pressure(133.322)),
+            "inHg" => Some(Unit::Pressure(3_386.389)),
+            "lx" | "lux" => Some(Unit::Illuminance(1.0)),
+            "fc" => Some(Unit::Illuminance(10.764)),
+            "%" => Some(Unit::Percent),
+            _ => None,
+        }
+    }
+
+    fn quantity(&self) -> Quantity {
+        match self {
+            Unit::Celsius | Unit::Fahrenheit | Unit::Kelvin => Quantity::Temperature,
+            Unit::Pressure(_) => Quantity::Pressure,
+            Unit::Illuminance(_) => Quantity::Illuminance,
+            Unit::Percent => Quantity::Ratio,
+        }
+    }
+
+    // convert to the base unit of the quantity (°C, Pa, lx, %)
+    fn to_base(self, value: f64) -> f64 {
+        match self {
+            Unit::Celsius | Unit::Percent => value,
+            Unit::Fahrenheit => (value - 32.0) * 5.0 / 9.0,
+            Unit::Kelvin => value - 273.15,
+            Unit::Pressure(factor) | Unit::Illuminance(factor) => value * factor,
+        }
+    }
+
+    // convert from the base unit of the quantity
+    fn base_to(self, value: f64) -> f64 {
+        match self {
+            Unit::Celsius | Unit::Percent => value,
+            Unit::Fahrenheit => value * 9.0 / 5.0 + 32.0,
+            Unit::Kelvin => value + 273.15,
+            Unit::Pressure(factor) | Unit::Illuminance(factor) => value / factor,
+        }
+    }
+}
+
+pub fn convert(value: f64, from: &str, to: &str) -> Result<f64, String> {
+    let from_unit: Unit = Unit::parse(from).ok_or_else(|| format!("unit {} is not supported", from))?;
+    let to_unit: Unit = Unit::parse(to).ok_or_else(|| format!("unit {} is not supported", to))?;
+    if from_unit.quantity() != to_unit.quantity() {
+        return Err(format!("unit {} cannot be converted to {}", from, to));
+    }
+    Ok(to_unit.base_to(from_unit.to_base(value)))
+}
+
+// convert the value from the unit of the reading (if any) to the canonical unit of the feature,
+// then round it to the precision of the feature.
+// Readings without unit are expected to be already in the canonical unit
+pub fn normalize(feature: &FeatureKind, value: SensorValue, unit: Option<&str>) -> Result<Measurement, String> {
+    let canonical_unit: Option<&str> = feature.unit.as_deref();
+    let original_unit: Option<&str> = unit.or(canonical_unit);
+    let value: SensorValue = match (unit, canonical_unit) {
+        (Some(unit), Some(canonical_unit)) if unit != canonical_unit => {
+            let number: f64 = value
+                .as_f64()
+                .ok_or_else(|| format!("{} value with unit {} is not a number", feature.name, unit))?;
+            let converted: f64 = convert(number, unit, canonical_unit)?;
+            match feature.value_type {
+                ValueType::Integer => SensorValue::Integer(converted.round() as i64),
+                _ => SensorValue::Float(converted),
+            }
+        }
+        (Some(unit), None) => {
+            return Err(format!(
+                "{} doesn't have a unit, but the reading is in {}",
+                feature.name, unit
+            ));
+        }
+        _ => value,
+    };
+    let value: SensorValue = match value {
+        SensorValue::Float(number) => SensorValue::Float(feature.round(number)),
+        value => value,
+    };
+    Ok(Measurement {
+        value,
+        unit: canonical_unit.map(String::from),
+        original_unit: original_unit.map(String::from),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::features::{FeatureKind, ValueType};
+    use crate::models::sensor_value::SensorValue;
+    use crate::units::{convert, normalize};
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    #[test_log::test]
+    fn ok_convert() {
+        assert_eq!(convert(212.0, "°F", "°C").unwrap(), 100.0);
+        assert_eq!(convert(0.0, "C", "K").unwrap(), 273.15);
+        assert_eq!(convert(101325.0, "Pa", "hPa").unwrap(), 1013.25);
+        assert_eq!(convert(1.0, "fc", "lx").unwrap(), 10.764);
+        assert!(convert(1.0, "°C", "hPa").is_err());
+        assert!(convert(1.0, "furlong", "hPa").is_err());
+    }
+
+    #[test]
+    #[test_log::test]
+    fn ok_normalize() {
+        let mut temperature = FeatureKind::new("temperature", ValueType::Float, Some("°C"));
+        temperature.storage.precision = Some(2);
+        let measurement = normalize(&temperature, SensorValue::Float(70.5), Some("°F")).unwrap();
+        assert_eq!(measurement.value, SensorValue::Float(21.39));
+        assert_eq!(measurement.unit.as_deref(), Some("°C"));
+        assert_eq!(measurement.original_unit.as_deref(), Some("°F"));
+
+        // readings without unit are already in the canonical unit
+        let measurement = normalize(&temperature, SensorValue::Float(21.456), None).unwrap();
+        assert_eq!(measurement.value, SensorValue::Float(21.46));
+        assert_eq!(measurement.original_unit.as_deref(), Some("°C"));
+
+        let airpressure = FeatureKind::new("airpressure", ValueType::Integer, Some("hPa"));
+        let measurement = normalize(&airpressure, SensorValue::Integer(101325), Some("Pa")).unwrap();
+        assert_eq!(measurement.value, SensorValue::Integer(1013));
+
+        assert!(normalize(&temperature, SensorValue::Float(1.0), Some("hPa")).is_err());
+        let motion = FeatureKind::new("motion", ValueType::Integer, None);
+        assert!(normalize(&motion, SensorValue::Integer(1), Some("%")).is_err());
+    }
+}
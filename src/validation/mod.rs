@@ -0,0 +1,207 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use tracing::warn;
+
+use crate::errors::message_error::MessageError;
+use crate::features::FeatureKind;
+use crate::models::sensor_value::SensorValue;
+
+// Validates readings against the rules of their feature (range, allowed values and rate of change)
+// before they are stored, and counts rejected readings per device.
+// It's shared by all workers.
+#[derive(Debug, Default)]
+pub struct Validator {
+    rejected_total: AtomicU64,
+    rejected: Mutex<RejectedCounters>,
+}
+
+// device uuids are sent by the devices, so only the devices with the most recent rejections are tracked
+const MAX_TRACKED_DEVICES: usize = 10_000;
+
+// rejected readings per device, evicting the device rejected least recently when full
+#[derive(Debug, Default)]
+struct RejectedCounters {
+    // count and sequence number of the last rejection, by device uuid
+    devices: HashMap<String, (u64, u64)>,
+    sequence: u64,
+}
+
+impl RejectedCounters {
+    fn increment(&mut self, device_uuid: &str, max_devices: usize) -> u64 {
+        self.sequence += 1;
+        if !self.devices.contains_key(device_uuid)
+            && self.devices.len() >= max_devices
+            && let Some(oldest) = self
+                .devices
+                .iter()
+                .min_by_key(|(_, (_, last_sequence))| *last_sequence)
+                .map(|(device_uuid, _)| device_uuid.clone())
+        {
+            self.devices.remove(&oldest);
+        }
+        let (count, last_sequence) = self.devices.entry(device_uuid.to_string()).or_default();
+        *count += 1;
+        *last_sequence = self.sequence;
+        *count
+    }
+}
+
+// value currently stored for a sensor, used to check the rate of change
+#[derive(Debug, Clone, PartialEq)]
+pub struct PreviousReading {
+    pub value: SensorValue,
+    // seconds elapsed since the previous value has been stored
+    pub elapsed_secs: f64,
+}
+
+// shortest interval used to compute the rate of change, to avoid divisions by zero
+const MIN_ELAPSED_SECS: f64 = 0.001;
+
+impl Validator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn validate(
+        &self,
+        device_uuid: &str,
+        feature: &FeatureKind,
+        value: &SensorValue,
+        previous: Option<&PreviousReading>,
+    ) -> Result<(), MessageError> {
+        validate_value(feature, value, previous).map_err(|reason| {
+            let rejected: u64 = self.increment_rejected(device_uuid);
+            warn!(target: "app", "validate - reading of device_uuid = {} rejected ({} so far): {}", device_uuid, rejected, reason);
+            MessageError::InvalidValueError(reason)
+        })
+    }
+
+    // number of readings rejected for a device since the consumer started
+    // (0 if the device has been evicted, see `MAX_TRACKED_DEVICES`)
+    pub fn rejected(&self, device_uuid: &str) -> u64 {
+        let rejected = self.rejected.lock().unwrap();
+        rejected
+            .devices
+            .get(device_uuid)
+            .map(|(count, _)| *count)
+            .unwrap_or_default()
+    }
+
+    // number of readings rejected for all devices since the consumer started
+    pub fn rejected_total(&self) -> u64 {
+        self.rejected_total.load(Ordering::Relaxed)
+    }
+
+    fn increment_rejected(&self, device_uuid: &str) -> u64 {
+        self.rejected_total.fetch_add(1, Ordering::Relaxed);
+        self.rejected
+            .lock()
+            .unwrap()
+            .increment(device_uuid, MAX_TRACKED_DEVICES)
+    }
+}
+
+// returns the reason why the value is not valid
+pub fn validate_value(
+    feature: &FeatureKind,
+    value: &SensorValue,
+    previous: Option<&PreviousReading>,
+) -> Result<(), String> {
+    if let (Some(allowed_values), SensorValue::Integer(integer)) = (feature.allowed_values.as_ref(), value)
+        && !allowed_values.contains(integer)
+    {
+        return Err(format!(
+            "{} value {} is not one of {:?}",
+            feature.name, integer, allowed_values
+        ));
+    }
+    let Some(number) = value.as_f64() else {
+        return Ok(());
+    };
+    if let Some(min) = feature.min
+        && number < min
+    {
+        return Err(format!("{} value {} is lower than {}", feature.name, number, min));
+    }
+    if let Some(max) = feature.max
+        && number > max
+    {
+        return Err(format!("{} value {} is greater than {}", feature.name, number, max));
+    }
+    if let (Some(max_rate), Some(previous)) = (feature.max_rate_of_change, previous)
+        && let Some(previous_number) = previous.value.as_f64()
+    {
+        let rate: f64 = (number - previous_number).abs() / previous.elapsed_secs.max(MIN_ELAPSED_SECS);
+        if rate > max_rate {
+            return Err(format!(
+                "{} value changed from {} to {} in {}s, more than {}/s",
+                feature.name, previous_number, number, previous.elapsed_secs, max_rate
+            ));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::errors::message_error::MessageError;
+    use crate::features::{FeatureKind, ValueType};
+    use crate::models::sensor_value::SensorValue;
+    use crate::validation::{PreviousReading, RejectedCounters, Validator, validate_value};
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    #[test_log::test]
+    fn ok_validate_value() {
+        let humidity = FeatureKind::new("humidity", ValueType::Float, Some("%")).range(Some(0.0), Some(100.0));
+        assert!(validate_value(&humidity, &SensorValue::Float(55.5), None).is_ok());
+        assert!(validate_value(&humidity, &SensorValue::Float(250.0), None).is_err());
+        assert!(validate_value(&humidity, &SensorValue::Float(-0.1), None).is_err());
+
+        let motion = FeatureKind::new("motion", ValueType::Integer, None).allowed_values(vec![0, 1]);
+        assert!(validate_value(&motion, &SensorValue::Integer(1), None).is_ok());
+        assert!(validate_value(&motion, &SensorValue::Integer(2), None).is_err());
+
+        let mut temperature = FeatureKind::new("temperature", ValueType::Float, Some("°C"));
+        temperature.max_rate_of_change = Some(1.0);
+        let previous = PreviousReading {
+            value: SensorValue::Float(20.0),
+            elapsed_secs: 10.0,
+        };
+        assert!(validate_value(&temperature, &SensorValue::Float(25.0), Some(&previous)).is_ok());
+        assert!(validate_value(&temperature, &SensorValue::Float(35.0), Some(&previous)).is_err());
+        // the rate of change is not checked for the first reading
+        assert!(validate_value(&temperature, &SensorValue::Float(35.0), None).is_ok());
+    }
+
+    #[test]
+    #[test_log::test]
+    fn ok_count_rejected_per_device() {
+        let validator = Validator::new();
+        let humidity = FeatureKind::new("humidity", ValueType::Float, Some("%")).range(Some(0.0), Some(100.0));
+        let device_uuid = "246e3256-f0dd-4fcb-82c5-ee20c2267eeb";
+        let result = validator.validate(device_uuid, &humidity, &SensorValue::Float(250.0), None);
+        assert!(matches!(result, Err(MessageError::InvalidValueError(_))));
+        let _ = validator.validate(device_uuid, &humidity, &SensorValue::Float(-5.0), None);
+        let _ = validator.validate(device_uuid, &humidity, &SensorValue::Float(50.0), None);
+        assert_eq!(validator.rejected(device_uuid), 2);
+        assert_eq!(validator.rejected("other-device"), 0);
+        assert_eq!(validator.rejected_total(), 2);
+    }
+
+    #[test]
+    #[test_log::test]
+    fn ok_evict_rejected_devices() {
+        let mut counters = RejectedCounters::default();
+        assert_eq!(counters.increment("device-1", 2), 1);
+        assert_eq!(counters.increment("device-2", 2), 1);
+        assert_eq!(counters.increment("device-1", 2), 2);
+        // device-2 has been rejected least recently
+        assert_eq!(counters.increment("device-3", 2), 1);
+        assert_eq!(counters.devices.len(), 2);
+        assert!(!counters.devices.contains_key("device-2"));
+        assert_eq!(counters.increment("device-1", 2), 3);
+    }
+}
@@ -0,0 +1,192 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tracing::{debug, error, info};
+
+// Pool of workers that process jobs concurrently.
+// Jobs dispatched with the same key are always sent to the same worker,
+// so they are processed one after the other in the order they have been dispatched.
+// Every worker has a bounded queue, so `dispatch` waits when the worker is busy (backpressure).
+pub struct WorkerPool<J> {
+    senders: Vec<mpsc::Sender<J>>,
+    handles: Vec<JoinHandle<()>>,
+}
+
+impl<J: Send + 'static> WorkerPool<J> {
+    pub fn new<F, Fut>(workers: usize, capacity: usize, handler: F) -> Self
+    where
+        F: Fn(J) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let workers: usize = workers.max(1);
+        info!(target: "app", "WorkerPool - starting {} workers with capacity = {}", workers, capacity);
+        let handler = Arc::new(handler);
+        let mut senders: Vec<mpsc::Sender<J>> = Vec::with_capacity(workers);
+        let mut handles: Vec<JoinHandle<()>> = Vec::with_capacity(workers);
+        for index in 0..workers {
+            let (sender, mut receiver) = mpsc::channel::<J>(capacity.max(1));
+            let handler = handler.clone();
+            handles.push(tokio::spawn(async move {
+                while let Some(job) = receiver.recv().await {
+                    handler(job).await;
+                }
+                debug!(target: "app", "WorkerPool - worker {} stopped", index);
+            }));
+            senders.push(sender);
+        }
+        Self { senders, handles }
+    }
+
+    pub fn workers(&self) -> usize {
+        self.senders.len()
+    }
+
+    // send the job to the worker responsible for `key`.
+    // If the worker is not running anymore, the job is returned back to the caller
+    pub async fn dispatch(&self, key: &str, job: J) -> Result<(), J> {
+        let index: usize = worker_index(key, self.senders.len());
+        self.senders[index].send(job).await.map_err(|err| {
+            error!(target: "app", "WorkerPool - cannot dispatch job to worker {}", index);
+            err.0
+        })
+    }
+
+    // stop accepting new jobs and wait until all dispatched jobs have been processed
+    pub async fn join(self) {
+        drop(self.senders);
+        for handle in self.handles {
+            if let Err(err) = handle.await {
+                error!(target: "app", "WorkerPool - worker terminated with error = {:?}", err);
+            }
+        }
+    }
+}
+
+// Tasks spawned by the workers, to complete their jobs in background.
+// Every task receives the previous task spawned with the same key, so it can wait for it
+// and keep the order of the jobs with the same key (e.g. to publish events and ack deliveries).
+// Tasks are not limited here, so the caller must bound the jobs in progress (e.g. with the AMQP prefetch count)
+#[derive(Clone, Default)]
+pub struct KeyedTasks {
+    // latest task of every key, that waits for the previous ones
+    tasks: Arc<Mutex<HashMap<String, JoinHandle<()>>>>,
+}
+
+impl KeyedTasks {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn spawn<F, Fut>(&self, key: String, task: F)
+    where
+        F: FnOnce(PreviousTask) -> Fut,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let mut tasks = self.tasks.lock().unwrap();
+        // completed tasks don't need to be waited anymore
+        tasks.retain(|_, handle| !handle.is_finished());
+        let previous = PreviousTask(tasks.remove(&key));
+        tasks.insert(key, tokio::spawn(task(previous)));
+    }
+
+    // wait until all spawned tasks have been completed
+    pub async fn join(&self) {
+        let handles: Vec<JoinHandle<()>> = self.tasks.lock().unwrap().drain().map(|(_, handle)| handle).collect();
+        for handle in handles {
+            if let Err(err) = handle.await {
+                error!(target: "app", "KeyedTasks - task terminated with error = {:?}", err);
+            }
+        }
+    }
+}
+
+// previous task spawned with the same key, if still running
+pub struct PreviousTask(Option<JoinHandle<()>>);
+
+impl PreviousTask {
+    pub async fn wait(self) {
+        if let Some(handle) = self.0
+            && let Err(err) = handle.await
+        {
+            error!(target: "app", "PreviousTask - task terminated with error = {:?}", err);
+        }
+    }
+}
+
+pub fn worker_index(key: &str, workers: usize) -> usize {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    (hasher.finish() % workers.max(1) as u64) as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::workers::{KeyedTasks, WorkerPool, worker_index};
+    use pretty_assertions::assert_eq;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use tokio::time::sleep;
+
+    #[test]
+    #[test_log::test]
+    fn ok_worker_index() {
+        let key = "246e3256-f0dd-4fcb-82c5-ee20c2267eeb/41cb3f47-894c-45e9-90d9-a4d4de903896";
+        assert_eq!(worker_index(key, 4), worker_index(key, 4));
+        assert!(worker_index(key, 4) < 4);
+        assert_eq!(worker_index(key, 1), 0);
+        assert_eq!(worker_index(key, 0), 0);
+    }
+
+    #[tokio::test]
+    #[test_log::test]
+    async fn ok_same_key_keeps_order() {
+        let processed: Arc<Mutex<Vec<u64>>> = Arc::new(Mutex::new(Vec::new()));
+        let processed_clone = processed.clone();
+        let pool: WorkerPool<u64> = WorkerPool::new(4, 2, move |job: u64| {
+            let processed = processed_clone.clone();
+            async move {
+                // older jobs are slower, so they would complete later without ordering guarantees
+                sleep(Duration::from_millis(10 - job)).await;
+                processed.lock().unwrap().push(job);
+            }
+        });
+        for job in 0..10 {
+            pool.dispatch("same-device/same-feature", job).await.unwrap();
+        }
+        pool.join().await;
+        assert_eq!(*processed.lock().unwrap(), (0..10).collect::<Vec<u64>>());
+    }
+
+    #[tokio::test]
+    #[test_log::test]
+    async fn ok_keyed_tasks_keep_order() {
+        let processed: Arc<Mutex<Vec<(&str, u64)>>> = Arc::new(Mutex::new(Vec::new()));
+        let tasks = KeyedTasks::new();
+        for job in 0..10 {
+            for key in ["first-device", "second-device"] {
+                let processed = processed.clone();
+                tasks.spawn(key.to_string(), move |previous| async move {
+                    // older jobs are slower, so they would complete later without waiting for the previous task
+                    sleep(Duration::from_millis(10 - job)).await;
+                    previous.wait().await;
+                    processed.lock().unwrap().push((key, job));
+                });
+            }
+        }
+        tasks.join().await;
+        let processed = processed.lock().unwrap();
+        assert_eq!(processed.len(), 20);
+        for key in ["first-device", "second-device"] {
+            let jobs: Vec<u64> = processed
+                .iter()
+                .filter(|(processed_key, _)| *processed_key == key)
+                .map(|(_, job)| *job)
+                .collect();
+            assert_eq!(jobs, (0..10).collect::<Vec<u64>>());
+        }
+    }
+}